@@ -0,0 +1,620 @@
+use crate::encoding::OutputEncoding;
+use crate::linkcheck::{figure_label, float_reference, plain_text, table_label};
+use crate::md_ast::{Attributes, MarkdownElement, MarkdownFormat, MarkdownFragment, MarkdownTable};
+use crate::render::{Matter, RenderError, Renderer};
+use crate::texutil::{
+    admonition, asset_path, columns_start, decode_entities, educate_quotes_in_context, escape_tex,
+    frag_to_collected_text, heading_section_depth, image_size_options, index_term, is_columns_end,
+    is_url_like, sub_prefixed, table_caption_parts, to_typing, write_external_listing, tex_comment, TableStyle, TexConfig,
+};
+use crate::toc::{TableOfContents, TOCLevel};
+
+
+/// Renders ConTeXt code.
+pub struct ContextRenderer<'a> {
+    config: &'a TexConfig,
+}
+impl<'a> ContextRenderer<'a> {
+    pub fn new(config: &'a TexConfig) -> ContextRenderer<'a> {
+        ContextRenderer {
+            config,
+        }
+    }
+
+    /// Renders the contents of a table cell as inline material, without the blank lines that would
+    /// end the cell prematurely.
+    fn cell_to_tex(&self, cell: &MarkdownFragment) -> Result<String, RenderError> {
+        self.config.forget_preceding_text();
+        let mut ret = String::new();
+        for elem in cell.elements() {
+            let elem_tex = match elem {
+                MarkdownElement::Paragraph(subfrag) => {
+                    if !ret.is_empty() {
+                        ret.push(' ');
+                    }
+                    self.render(subfrag)?
+                },
+                MarkdownElement::Table(_) => {
+                    return Err(RenderError::MalformedTable("table nested in a table cell".to_owned()));
+                },
+                other => self.elements_to_tex(std::slice::from_ref(other))?,
+            };
+            ret.push_str(&elem_tex);
+        }
+        Ok(ret.trim().to_owned())
+    }
+
+    fn table_to_tex(&self, table: &MarkdownTable) -> Result<String, RenderError> {
+        let config = self.config;
+        let names = config.command_names();
+        let mut ret = String::new();
+        match config.table_style() {
+            TableStyle::None => {},
+            TableStyle::Minimal => {
+                ret.push_str(&format!("\\setup{}[frame=off]\n", names.table()));
+                if !table.header_rows().is_empty() {
+                    ret.push_str(&format!("\\setup{}[r][1][topframe=on,bottomframe=on]\n", names.table()));
+                }
+                ret.push_str(&format!("\\setup{}[r][last][bottomframe=on]\n", names.table()));
+            },
+            TableStyle::Grid => {
+                ret.push_str(&format!("\\setup{}[frame=on]\n", names.table()));
+            },
+        }
+        for (i, alignment) in table.alignments().iter().enumerate() {
+            let align_keyword = match alignment {
+                'l' => "flushleft",
+                'r' => "flushright",
+                'c' => "middle",
+                _ => "",
+            };
+            if align_keyword.is_empty() {
+                continue;
+            }
+            ret.push_str(&format!("\\setup{}[c][{}][align={}]\n", names.table(), i+1, align_keyword));
+        }
+        ret.push_str(&format!("\\b{}\n", names.table()));
+        let types_rows = vec![
+            ("TH", table.header_rows()),
+            ("TD", table.body_rows()),
+        ];
+        // pad ragged rows with empty cells so that every row has the same number of cells
+        let column_count = types_rows.iter()
+            .flat_map(|(_t, rows)| rows.iter().map(|row| row.len()))
+            .chain(std::iter::once(table.alignments().len()))
+            .max()
+            .unwrap_or(0);
+        for (t, rows) in types_rows {
+            for row in rows {
+                ret.push_str("\\bTR\n");
+                for col in row {
+                    let coltex = self.cell_to_tex(col)?;
+                    if coltex.is_empty() {
+                        ret.push_str(&format!("\\b{} \\e{}\n", t, t));
+                        continue;
+                    }
+                    ret.push_str(&format!("\\b{} ", t));
+                    ret.push_str(&coltex);
+                    ret.push_str(&format!(" \\e{}\n", t));
+                }
+                for _ in row.len()..column_count {
+                    ret.push_str(&format!("\\b{} \\e{}\n", t, t));
+                }
+                ret.push_str("\\eTR\n");
+            }
+        }
+        ret.push_str(&format!("\\e{}\n", names.table()));
+        Ok(ret)
+    }
+
+    /// Returns the caption for a table if the element following it is a paragraph starting with
+    /// `Table:`.
+    fn table_caption(&self, next_elem: Option<&MarkdownElement>) -> Result<Option<String>, RenderError> {
+        let (caption_start, caption_rest) = match table_caption_parts(next_elem) {
+            Some(parts) => parts,
+            None => return Ok(None),
+        };
+
+        let mut caption = self.render_text(caption_start);
+        caption.push_str(&self.elements_to_tex(caption_rest)?);
+        Ok(Some(caption.trim_end().to_owned()))
+    }
+
+    fn elements_to_tex(&self, elements: &[MarkdownElement]) -> Result<String, RenderError> {
+        let config = self.config;
+        let names = config.command_names();
+        let mut ret = String::new();
+        let mut i = 0;
+        while i < elements.len() {
+            let elem = &elements[i];
+            i += 1;
+            if elem.is_block() {
+                config.forget_preceding_text();
+            }
+
+            match elem {
+                MarkdownElement::BlockQuote(subfrag) => {
+                    if let Some((title, first_para_rest)) = admonition(subfrag) {
+                        // > [!NOTE] becomes a titled frame
+                        ret.push_str(&format!("\\start{}[title={{{}}}]\n", names.admonition(), title));
+                        if !first_para_rest.is_empty() {
+                            ret.push_str(&self.elements_to_tex(first_para_rest)?);
+                            ret.push_str("\n\n");
+                        }
+                        ret.push_str(&self.elements_to_tex(&subfrag.elements()[1..])?);
+                        ret.push_str(&format!("\\stop{}\n\n", names.admonition()));
+                        continue;
+                    }
+
+                    // nested narrower environments add up, indenting deeper quotes further
+                    let environment = if *config.narrower_quotes() { "narrower" } else { names.blockquote() };
+                    let subtex = self.render(subfrag)?;
+                    ret.push_str(&format!("\\start{}\n", environment));
+                    ret.push_str(&subtex);
+                    ret.push_str(&format!("\\stop{}\n\n", environment));
+                },
+                MarkdownElement::Div(name, subfrag) => {
+                    let environment = config.environment(name);
+                    let subtex = self.render(subfrag)?;
+                    ret.push_str(&format!("\\start{}\n", environment));
+                    ret.push_str(&subtex);
+                    ret.push_str(&format!("\\stop{}\n\n", environment));
+                },
+                MarkdownElement::Code(subfrag) => {
+                    // special handling for curly braces
+                    let subfrag_escaped = self.inline_code(subfrag);
+                    config.note_preceding_text(subfrag);
+                    ret.push_str(&subfrag_escaped);
+                },
+                MarkdownElement::CodeBlock(info, subfrag) => {
+                    let language = info.language();
+                    let subtex = frag_to_collected_text(subfrag)?;
+
+                    if language == Some("verse") {
+                        // poetry: typeset as text, but keep the line breaks
+                        ret.push_str("\\startlines\n");
+                        ret.push_str(&self.render_text(&subtex));
+                        if !subtex.is_empty() && !subtex.ends_with('\n') {
+                            ret.push('\n');
+                        }
+                        ret.push_str("\\stoplines\n\n");
+                        continue;
+                    }
+
+                    let options = if *config.number_code_lines() || info.numbers_lines() {
+                        "[numbering=line]"
+                    } else {
+                        ""
+                    };
+
+                    if let Some(min_lines) = config.external_listing_min_lines() {
+                        if subtex.lines().count() >= *min_lines {
+                            let listing_path = write_external_listing(&subtex, language, config)?;
+                            ret.push_str("\\typefile");
+                            ret.push_str(options);
+                            ret.push('{');
+                            ret.push_str(&listing_path);
+                            ret.push_str("}\n\n");
+                            continue;
+                        }
+                    }
+
+                    ret.push_str(&format!("\\start{}{}\n", names.typing(), options));
+                    ret.push_str(&subtex);
+                    if !subtex.is_empty() && !subtex.ends_with('\n') {
+                        // \stoptyping must be on its own line or the last line is swallowed
+                        ret.push('\n');
+                    }
+                    ret.push_str(&format!("\\stop{}\n\n", names.typing()));
+                },
+                MarkdownElement::Formatting(fmt, subfrag) => {
+                    let subtex = self.render(subfrag)?;
+                    match fmt {
+                        MarkdownFormat::Strikethrough => {
+                            ret.push_str(&format!("\\{}{{", names.strikethrough()));
+                            ret.push_str(&subtex);
+                            ret.push('}');
+                        },
+                        _ => {
+                            ret.push('{');
+                            match fmt {
+                                MarkdownFormat::Emphasis => ret.push_str("\\it "),
+                                MarkdownFormat::Strong => ret.push_str("\\bf "),
+                                _ => {
+                                    return Err(RenderError::InvalidFormatting(format!("{:?}", fmt)));
+                                },
+                            }
+                            ret.push_str(&subtex);
+                            ret.push('}');
+                        },
+                    }
+                },
+                MarkdownElement::Heading(level, subfrag) => {
+                    let command = if config.heading_commands().is_empty() {
+                        if *level == 1 {
+                            // the heading of this level is already output as part of descending the ToC
+                            continue;
+                        }
+
+                        sub_prefixed(heading_section_depth(*level, config), names.section())
+                    } else {
+                        match config.heading_commands().get((*level - 1) as usize) {
+                            Some(c) => c.clone(),
+                            None => return Err(RenderError::MissingHeadingCommand(*level)),
+                        }
+                    };
+
+                    let subtex = self.render(subfrag)?;
+
+                    ret.push('\\');
+                    ret.push_str(&command);
+                    ret.push('{');
+                    ret.push_str(&subtex);
+                    ret.push_str("}\n");
+                },
+                MarkdownElement::Link(url, subfrag) => {
+                    if url.trim().is_empty() {
+                        // nothing to link to, e.g. with a draft link `[text]()`
+                        config.warn(format!(
+                            "link {:?} has no destination; outputting its text only",
+                            plain_text(subfrag),
+                        ));
+                        ret.push_str(&self.render(subfrag)?);
+                        continue;
+                    }
+                    let subtex = match subfrag.elements().as_slice() {
+                        [MarkdownElement::Image(image_url, _, attrs)] if !image_url.trim().is_empty() => {
+                            // a linked image; box the figure (never floated) so it can be clicked
+                            format!(
+                                "\\hbox{{\\{}[{}]{}}}",
+                                names.figure(), asset_path(image_url, config)?, self.figure_options(attrs),
+                            )
+                        },
+                        [MarkdownElement::Text(text)] if is_url_like(text) => {
+                            // allow ConTeXt to break long URLs across lines
+                            format!("\\{}{{{}}}", names.hyphenated_url(), escape_tex(text, config))
+                        },
+                        _ => self.render(subfrag)?,
+                    };
+
+                    if *config.number_floats() {
+                        if let Some(label) = float_reference(url) {
+                            // a reference to a numbered figure or table
+                            ret.push_str(&format!("\\in{{{}}}[{}]", subtex, label));
+                            continue;
+                        }
+                    }
+
+                    ret.push_str(&format!("\\{}{{", names.goto()));
+                    ret.push_str(&subtex);
+                    ret.push_str("}[url(");
+                    ret.push_str(url);
+                    ret.push_str(")]");
+                },
+                MarkdownElement::Image(url, subfrag, attrs) => {
+                    if url.trim().is_empty() {
+                        // there is no file to include, e.g. with `![alt]()` or a reference definition `[ref]: <>`
+                        config.warn(format!(
+                            "image {:?} has no destination; outputting its description instead",
+                            plain_text(subfrag),
+                        ));
+                        ret.push_str(&self.render(subfrag)?);
+                        continue;
+                    }
+                    let label = if *config.number_floats() { figure_label(subfrag) } else { None };
+                    let placed = *config.float_figures() || label.is_some();
+                    let alignment = match attrs.get("align") {
+                        None => None,
+                        Some("left") => Some(("left", "flushleft")),
+                        Some("center") => Some(("middle", "middle")),
+                        Some("right") => Some(("right", "flushright")),
+                        Some(other) => {
+                            config.warn(format!("ignoring unknown image alignment {:?}", other));
+                            None
+                        },
+                    };
+                    if placed {
+                        // the alt text becomes the caption
+                        let subtex = self.render(subfrag)?;
+
+                        config.count_figure();
+                        ret.push_str("\\placefigure[");
+                        ret.push_str(config.float_placement());
+                        if let Some((location, _align)) = alignment {
+                            // floats are centered anyway; left and right let the text flow around them
+                            if location != "middle" {
+                                ret.push(',');
+                                ret.push_str(location);
+                            }
+                        }
+                        ret.push(']');
+                        if let Some(l) = &label {
+                            ret.push_str(&format!("[{}]", l));
+                        }
+                        ret.push('{');
+                        ret.push_str(&subtex);
+                        ret.push_str("}{");
+                    }
+
+                    if let (false, Some((_location, align))) = (placed, alignment) {
+                        ret.push_str(&format!("\\startalignment[{}]\n", align));
+                    }
+
+                    ret.push_str(&format!("\\{}[", names.figure()));
+                    ret.push_str(&asset_path(url, config)?);
+                    ret.push(']');
+                    ret.push_str(&self.figure_options(attrs));
+
+                    if placed {
+                        ret.push('}');
+                    } else if alignment.is_some() {
+                        ret.push_str("\n\\stopalignment");
+                    }
+                },
+                MarkdownElement::List(start, items) => {
+                    match start {
+                        Some(1) => ret.push_str(&format!("\n\\start{}[n]\n", names.itemize())),
+                        Some(s) => ret.push_str(&format!("\n\\start{}[n][start={}]\n", names.itemize(), s)),
+                        None => ret.push_str(&format!("\n\\start{}\n", names.itemize())),
+                    }
+                    for item in items {
+                        config.forget_preceding_text();
+                        let subtex = self.render(item)?;
+
+                        let has_blocks = item.elements().iter().any(|e| match e {
+                            MarkdownElement::Paragraph(_) | MarkdownElement::CodeBlock(_, _)
+                                | MarkdownElement::BlockQuote(_) | MarkdownElement::Table(_)
+                                | MarkdownElement::Div(_, _) => true,
+                            _ => false,
+                        });
+                        if has_blocks {
+                            // block content must not end up on the \item line
+                            ret.push_str("\\startitem\n");
+                            ret.push_str(&subtex);
+                            ret.push_str("\\stopitem\n");
+                        } else {
+                            ret.push_str("\\item ");
+                            ret.push_str(&subtex);
+                            ret.push('\n');
+                        }
+                    }
+                    ret.push_str(&format!("\\stop{}\n", names.itemize()));
+                },
+                MarkdownElement::Paragraph(subfrag) => {
+                    let subtex = self.render(subfrag)?;
+
+                    if *config.explicit_paragraphs() {
+                        ret.push_str("\\startparagraph\n");
+                        ret.push_str(&subtex);
+                        ret.push_str("\n\\stopparagraph\n\n");
+                    } else {
+                        ret.push_str(&subtex);
+                        ret.push_str("\n\n");
+                    }
+                },
+                MarkdownElement::Table(table) => {
+                    let caption = if *config.table_captions() {
+                        self.table_caption(elements.get(i))?
+                    } else {
+                        None
+                    };
+                    let label = match elements.get(i) {
+                        Some(MarkdownElement::Paragraph(p)) if caption.is_some() => {
+                            if *config.number_floats() { table_label(p) } else { None }
+                        },
+                        _ => None,
+                    };
+                    let table_tex = self.table_to_tex(table)?;
+
+                    if caption.is_some() {
+                        // the caption paragraph has been consumed
+                        i += 1;
+                    }
+
+                    if *config.float_tables() {
+                        ret.push_str("\\placetable[");
+                        ret.push_str(config.float_placement());
+                        ret.push(']');
+                        if let Some(l) = &label {
+                            ret.push_str(&format!("[{}]", l));
+                        }
+                        ret.push('{');
+                        ret.push_str(caption.as_deref().unwrap_or(""));
+                        ret.push_str("}{\n");
+                        ret.push_str(&table_tex);
+                        ret.push_str("}\n\n");
+                    } else if let Some(cap) = caption {
+                        ret.push_str("\\startplacetable[");
+                        if let Some(l) = &label {
+                            ret.push_str(&format!("reference={},", l));
+                        }
+                        ret.push_str("title={");
+                        ret.push_str(&cap);
+                        ret.push_str("}]\n");
+                        ret.push_str(&table_tex);
+                        ret.push_str("\\stopplacetable\n\n");
+                    } else {
+                        ret.push_str(&table_tex);
+                        ret.push('\n');
+                    }
+                },
+                MarkdownElement::Text(text) => {
+                    let text = self.render_text(&text);
+                    ret.push_str(&text);
+                },
+                MarkdownElement::HtmlFragment(html) => {
+                    if let Some(term) = index_term(html) {
+                        // <!-- index: term --> becomes an index entry
+                        config.count_index_entry();
+                        ret.push_str(&format!("\\{}{{", names.index()));
+                        ret.push_str(&self.render_text(&decode_entities(term)));
+                        ret.push('}');
+                        if html.ends_with('\n') {
+                            // block-level marker
+                            ret.push('\n');
+                        }
+                        continue;
+                    }
+                    if let Some(columns) = columns_start(html) {
+                        ret.push_str(&format!("\\startcolumns[n={}]\n", columns));
+                        continue;
+                    }
+                    if is_columns_end(html) {
+                        ret.push_str("\\stopcolumns\n\n");
+                        continue;
+                    }
+
+                    ret.push_str(&tex_comment(html));
+                },
+                MarkdownElement::FootnoteRef(foot_name) => {
+                    ret.push_str(&format!("\\{}[", names.note()));
+                    ret.push_str(&foot_name);
+                    ret.push(']');
+                },
+                MarkdownElement::Rule => {
+                    ret.push_str("\\thinrule\n\n");
+                },
+                MarkdownElement::Unsupported(description, range) => {
+                    let offset = range.as_ref().map(|r| r.start);
+                    if !*config.lenient() {
+                        return Err(RenderError::UnsupportedElement(format!("Markdown construct {}", description), offset));
+                    }
+                    config.warn_at(format!("skipped unsupported Markdown construct {}", description), offset);
+                    ret.push_str(&format!("% skipped unsupported Markdown construct {}\n", description.replace("\n", " ")));
+                },
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Outputs the options of an external figure according to the attributes of the image, e.g.
+    /// `[width=0.5\textwidth]`, or nothing if there are none.
+    fn figure_options(&self, attrs: &Attributes) -> String {
+        let options = image_size_options(attrs, self.config);
+        if options.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", options.join(","))
+        }
+    }
+
+    /// Outputs the setups preceding the start of the document.
+    pub fn preamble(&self, toc: &TableOfContents, encoding: OutputEncoding) -> String {
+        let mut ret = format!("\\enableregime[{}]\n", encoding.regime());
+        for environment in self.config.environment_files() {
+            ret.push_str(&format!("\\environment {}\n", environment));
+        }
+
+        let mut interaction = format!("title={ob}{t}{cb}", ob = '{', t = toc.title(), cb = '}');
+        if let Some(author) = toc.metadata().author() {
+            interaction.push_str(&format!(",author={ob}{a}{cb}", ob = '{', a = author, cb = '}'));
+        }
+        ret.push_str(&format!("\\setupinteraction[{}]\n", interaction));
+
+        if let Some(language) = toc.metadata().language() {
+            ret.push_str(&format!("\\mainlanguage[{}]\n", language));
+        }
+
+        if let Some(date) = toc.metadata().date() {
+            ret.push_str(&format!("\\def\\mdcontextdate{ob}{d}{cb}\n", ob = '{', d = date, cb = '}'));
+        }
+
+        ret
+    }
+}
+impl<'a> Renderer for ContextRenderer<'a> {
+    fn render(&self, frag: &MarkdownFragment) -> Result<String, RenderError> {
+        self.elements_to_tex(frag.elements())
+    }
+
+    fn escape_text(&self, text: &str) -> String {
+        escape_tex(text, self.config)
+    }
+
+    fn educate_quotes(&self, text: &str) -> String {
+        educate_quotes_in_context(text, self.config)
+    }
+
+    fn inline_code(&self, code: &str) -> String {
+        to_typing(code, self.config.command_names().type_inline())
+    }
+
+    fn begin_document(&self, toc: &TableOfContents, encoding: OutputEncoding) -> String {
+        format!("{}\n\\starttext\n\n\\mdcontextplacetoc\n\n", self.preamble(toc, encoding))
+    }
+
+    fn end_document(&self) -> String {
+        "\\stoptext\n".to_owned()
+    }
+
+    fn begin_matter(&self, matter: Matter) -> String {
+        format!("\n\\start{}\n", context_matter(matter))
+    }
+
+    fn end_matter(&self, matter: Matter) -> String {
+        format!("\n\\stop{}\n", context_matter(matter))
+    }
+
+    fn begin_language(&self, language: &str) -> String {
+        format!("\n\\start\\language[{}]\n", language)
+    }
+
+    fn end_language(&self) -> String {
+        "\n\\stop\n".to_owned()
+    }
+
+    fn heading(&self, level: &TOCLevel, numbered: bool, title: &str, short_title: Option<&str>) -> String {
+        match short_title {
+            Some(st) => format!(
+                "\n\\{lvl}[title={ob}{t}{cb},marking={ob}{st}{cb}]\n",
+                lvl = level.tex_string(numbered), ob = '{', t = title, st = st, cb = '}',
+            ),
+            None => format!("\n\\{lvl}{ob}{t}{cb}\n", lvl = level.tex_string(numbered), ob = '{', t = title, cb = '}'),
+        }
+    }
+
+    fn generated_back_matter(&self) -> String {
+        // the \complete variants add a \title with the localized name, like unnumbered chapters
+        let mut ret = String::new();
+        if *self.config.list_of_figures() && self.config.figure_count() > 0 {
+            ret.push_str("\n\\completelistoffigures\n");
+        }
+        if self.config.index_entry_count() > 0 {
+            ret.push_str("\n\\completeindex\n");
+        }
+        ret
+    }
+
+    fn error_marker(&self, message: &str) -> String {
+        format!(
+            "% error: {}\n\\framed{ob}error: {}{cb}\n\n",
+            message.replace("\n", "\n% "), self.render_text(message),
+            ob = '{', cb = '}',
+        )
+    }
+
+    fn verbatim_markers(&self) -> Vec<(String, String)> {
+        let typing = self.config.command_names().typing();
+        vec![
+            (format!("\\start{}", typing), format!("\\stop{}", typing)),
+            ("\\startlines".to_owned(), "\\stoplines".to_owned()),
+        ]
+    }
+
+    fn literal_markers(&self) -> (Vec<(String, String)>, Vec<String>) {
+        let names = self.config.command_names();
+        (
+            vec![(format!("\\start{}", names.typing()), format!("\\stop{}", names.typing()))],
+            vec![format!("\\{}", names.type_inline())],
+        )
+    }
+}
+
+fn context_matter(matter: Matter) -> &'static str {
+    match matter {
+        Matter::Front => "frontmatter",
+        Matter::Body => "bodymatter",
+        Matter::Appendices => "appendices",
+        Matter::Back => "backmatter",
+    }
+}
@@ -0,0 +1,195 @@
+use std::env;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+use crate::md_ast::ASTError;
+
+
+/// How errors and warnings about the input are reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorFormat {
+    /// Freeform text.
+    Human,
+    /// One JSON object per line, for editor integration.
+    Json,
+}
+impl ErrorFormat {
+    pub fn from_name(name: &str) -> Option<ErrorFormat> {
+        match name {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    /// The ANSI escape sequence that starts the color of the severity's label.
+    fn color(&self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+
+/// Whether human-readable diagnostics on stderr should be colored.
+///
+/// Colors are used if stderr is a terminal, unless the `NO_COLOR` environment variable is set
+/// (https://no-color.org/). Setting `CLICOLOR_FORCE` enables them even if stderr is not a
+/// terminal, e.g. in CI logs.
+pub fn use_color() -> bool {
+    let env_set = |name| env::var_os(name).map(|v| !v.is_empty() && v != "0").unwrap_or(false);
+    if env::var_os("NO_COLOR").map(|v| !v.is_empty()).unwrap_or(false) {
+        false
+    } else if env_set("CLICOLOR_FORCE") {
+        true
+    } else {
+        io::stderr().is_terminal()
+    }
+}
+
+
+/// An error or warning about the input, optionally with the file and byte offset it refers to.
+#[derive(Debug)]
+pub struct Diagnostic {
+    severity: Severity,
+    message: String,
+    file: Option<PathBuf>,
+    offset: Option<usize>,
+}
+impl Diagnostic {
+    pub fn error<M: AsRef<str>>(message: M) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.as_ref().to_owned(),
+            file: None,
+            offset: None,
+        }
+    }
+
+    pub fn warning<M: AsRef<str>>(message: M) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.as_ref().to_owned(),
+            file: None,
+            offset: None,
+        }
+    }
+
+    pub fn with_file(mut self, file: &Path) -> Diagnostic {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+
+    /// Sets the byte offset in the file that the diagnostic refers to, if known.
+    pub fn with_offset(mut self, offset: Option<usize>) -> Diagnostic {
+        self.offset = offset;
+        self
+    }
+
+    accessor!(severity, Severity);
+    accessor!(message, str);
+    accessor_opt!(file, Path);
+
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Formats the diagnostic as a single-line JSON object with the keys `severity`, `file`,
+    /// `offset` and `message`.
+    pub fn to_json(&self) -> String {
+        let file = match &self.file {
+            Some(f) => json_string(&f.to_string_lossy()),
+            None => "null".to_owned(),
+        };
+        let offset = match self.offset {
+            Some(o) => o.to_string(),
+            None => "null".to_owned(),
+        };
+        format!(
+            "{ob}\"severity\":{},\"file\":{},\"offset\":{},\"message\":{}{cb}",
+            json_string(self.severity.name()), file, offset, json_string(&self.message),
+            ob = '{', cb = '}',
+        )
+    }
+
+    /// Formats the diagnostic as `severity: file: message`, or `severity: file:offset: message`
+    /// if the byte offset is known, optionally coloring the severity.
+    pub fn to_human(&self, color: bool) -> String {
+        let label = if color {
+            format!("{}{}\x1b[0m", self.severity.color(), self.severity.name())
+        } else {
+            self.severity.name().to_owned()
+        };
+        match (&self.file, self.offset) {
+            (Some(f), Some(o)) => format!("{}: {}:{}: {}", label, f.display(), o, self.message),
+            (Some(f), None) => format!("{}: {}: {}", label, f.display(), self.message),
+            (None, Some(o)) => format!("{}: at byte {}: {}", label, o, self.message),
+            (None, None) => format!("{}: {}", label, self.message),
+        }
+    }
+
+    /// Outputs the diagnostic to stderr in the given format.
+    pub fn report(&self, format: ErrorFormat) {
+        match format {
+            ErrorFormat::Human => eprintln!("{}", self.to_human(use_color())),
+            ErrorFormat::Json => eprintln!("{}", self.to_json()),
+        }
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(formatter, "{}", self.to_human(false))
+    }
+}
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Diagnostic::error(message)
+    }
+}
+impl From<ASTError> for Diagnostic {
+    fn from(err: ASTError) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: err.message().to_owned(),
+            file: err.file().map(|f| f.to_path_buf()),
+            offset: err.offset(),
+        }
+    }
+}
+
+
+/// Quotes and escapes a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len() + 2);
+    ret.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
@@ -0,0 +1,250 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::texutil::char_escape;
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputEncoding {
+    Utf8,
+    Latin1,
+}
+impl OutputEncoding {
+    pub fn from_name(name: &str) -> Option<OutputEncoding> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(OutputEncoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(OutputEncoding::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn regime(&self) -> &'static str {
+        match self {
+            OutputEncoding::Utf8 => "utf",
+            OutputEncoding::Latin1 => "il1",
+        }
+    }
+}
+
+
+/// The characters encoded by the bytes 0x80 to 0x9F in Windows-1252. The five unassigned bytes
+/// are decoded to the C1 control characters with the same values, as browsers do.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+
+/// The encoding of the Markdown source files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputEncoding {
+    Utf8,
+    /// UTF-16 in the byte order given by the byte order mark, or little-endian without one.
+    Utf16,
+    Latin1,
+    Windows1252,
+}
+impl InputEncoding {
+    pub fn from_name(name: &str) -> Option<InputEncoding> {
+        match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(InputEncoding::Utf8),
+            "utf-16" | "utf16" => Some(InputEncoding::Utf16),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(InputEncoding::Latin1),
+            "windows-1252" | "cp1252" => Some(InputEncoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// Reads and decodes a source file.
+    pub fn read_file(&self, path: &Path) -> Result<String, String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        self.decode(&bytes)
+    }
+
+    /// Decodes the contents of a source file, dropping a byte order mark.
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        match self {
+            InputEncoding::Utf8 => {
+                let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+                match std::str::from_utf8(bytes) {
+                    Ok(text) => Ok(text.to_owned()),
+                    Err(_) if bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"\xFE\xFF") => Err(
+                        "not valid UTF-8; it appears to be UTF-16 (pass --input-encoding utf-16)".to_owned()
+                    ),
+                    Err(err) => Err(format!(
+                        "not valid UTF-8 (invalid byte at offset {}); is it UTF-16 or Latin-1? (pass --input-encoding)",
+                        err.valid_up_to(),
+                    )),
+                }
+            },
+            InputEncoding::Utf16 => {
+                let (bytes, big_endian) = if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+                    (rest, true)
+                } else {
+                    (bytes.strip_prefix(b"\xFF\xFE").unwrap_or(bytes), false)
+                };
+                if bytes.len() % 2 != 0 {
+                    return Err("not valid UTF-16 (odd number of bytes)".to_owned());
+                }
+                let units = bytes.chunks(2).map(|pair| if big_endian {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                });
+                char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .map_err(|err| format!("not valid UTF-16 (unpaired surrogate 0x{:04X})", err.unpaired_surrogate()))
+            },
+            InputEncoding::Latin1 => Ok(bytes.iter().map(|&b| char::from(b)).collect()),
+            InputEncoding::Windows1252 => Ok(bytes.iter().map(|&b| match b {
+                0x80..=0x9F => WINDOWS_1252_HIGH[usize::from(b - 0x80)],
+                other => char::from(other),
+            }).collect()),
+        }
+    }
+}
+
+
+/// Wraps a writer, transforming the (UTF-8) text written to it into the chosen output encoding.
+///
+/// Characters outside of Latin-1 are replaced by `\char"XXXX` if `escape_non_latin1` is set (or
+/// if the output encoding is Latin-1, where they cannot be represented otherwise). Material that
+/// is typeset literally, between lines starting with one of `literal_markers` (such as
+/// `\starttyping` and `\stoptyping`) and in the argument of one of `literal_commands` (such as
+/// `\type`), is never escaped, as the escapes would be typeset instead of the characters; where
+/// such a character cannot be represented in the output encoding, writing fails.
+///
+/// Text is processed line by line; `flush` also processes an incomplete last line.
+pub struct EncodingWriter<W: Write> {
+    inner: W,
+    encoding: OutputEncoding,
+    escape_non_latin1: bool,
+    literal_markers: Vec<(String, String)>,
+    literal_commands: Vec<String>,
+    // the marker ending the literal block being written
+    literal_end: Option<String>,
+    // the delimiter closing the argument of the literal command being written
+    literal_close: Option<char>,
+    mid_line: bool,
+    // the incomplete last line, which may end with the first bytes of a character
+    pending: Vec<u8>,
+}
+impl<W: Write> EncodingWriter<W> {
+    pub fn new(inner: W, encoding: OutputEncoding, escape_non_latin1: bool, literal_markers: Vec<(String, String)>, literal_commands: Vec<String>) -> EncodingWriter<W> {
+        EncodingWriter {
+            inner,
+            encoding,
+            escape_non_latin1: escape_non_latin1 || encoding == OutputEncoding::Latin1,
+            literal_markers,
+            literal_commands,
+            literal_end: None,
+            literal_close: None,
+            mid_line: false,
+            pending: Vec::new(),
+        }
+    }
+
+    fn encode_char(&self, c: char, literal: bool, out_bytes: &mut Vec<u8>) -> io::Result<()> {
+        let c_int: u32 = c.into();
+        if c_int > 0xFF && self.escape_non_latin1 && !literal {
+            out_bytes.extend(char_escape(c).as_bytes());
+        } else if self.encoding == OutputEncoding::Latin1 {
+            if c_int > 0xFF {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{:?} (U+{:04X}) in literal material such as code cannot be represented in Latin-1", c, c_int,
+                )));
+            }
+            out_bytes.push(c_int as u8);
+        } else {
+            let mut utf8_buf = [0u8; 4];
+            out_bytes.extend(c.encode_utf8(&mut utf8_buf).as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Encodes and outputs a line or, if `flush` was called in between, a part of one.
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        let mut literal_line = self.literal_end.is_some();
+        if !self.mid_line {
+            let trimmed = text.trim_start();
+            if let Some(end) = &self.literal_end {
+                if trimmed.starts_with(end.as_str()) {
+                    self.literal_end = None;
+                    literal_line = false;
+                }
+            } else if let Some((_start, end)) = self.literal_markers.iter().find(|(start, _end)| trimmed.starts_with(start.as_str())) {
+                self.literal_end = Some(end.clone());
+            }
+        }
+        self.mid_line = !text.ends_with('\n');
+
+        let mut out_bytes: Vec<u8> = Vec::with_capacity(text.len());
+        let mut index = 0;
+        while let Some(c) = text[index..].chars().next() {
+            let rest = &text[index..];
+            index += c.len_utf8();
+
+            if literal_line {
+                self.encode_char(c, true, &mut out_bytes)?;
+                continue;
+            }
+            if let Some(close) = self.literal_close {
+                if c == close || c == '\n' {
+                    self.literal_close = None;
+                    self.encode_char(c, false, &mut out_bytes)?;
+                } else {
+                    self.encode_char(c, true, &mut out_bytes)?;
+                }
+                continue;
+            }
+
+            let command = self.literal_commands.iter().find(|command| rest.starts_with(command.as_str()));
+            let delimiter = command.and_then(|command| rest[command.len()..].chars().next())
+                .filter(|d| !d.is_alphabetic() && !d.is_whitespace());
+            match (command, delimiter) {
+                (Some(command), Some(delimiter)) => {
+                    out_bytes.extend(command.as_bytes());
+                    self.encode_char(delimiter, false, &mut out_bytes)?;
+                    index += command.len() - c.len_utf8() + delimiter.len_utf8();
+                    self.literal_close = Some(if delimiter == '{' { '}' } else { delimiter });
+                },
+                _ => self.encode_char(c, false, &mut out_bytes)?,
+            }
+        }
+        self.inner.write_all(&out_bytes)
+    }
+}
+impl<W: Write> Write for EncodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let previous_length = self.pending.len();
+        self.pending.extend_from_slice(buf);
+        if let Err(err) = std::str::from_utf8(&self.pending) {
+            // an incomplete character at the end is completed by a later write
+            if err.error_len().is_some() {
+                self.pending.truncate(previous_length);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+
+        while let Some(newline_index) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline_index).collect();
+            self.write_text(std::str::from_utf8(&line).expect("line was validated"))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let valid_length = match std::str::from_utf8(&self.pending) {
+            Ok(t) => t.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        if valid_length > 0 {
+            let text: Vec<u8> = self.pending.drain(..valid_length).collect();
+            self.write_text(std::str::from_utf8(&text).expect("prefix was validated"))?;
+        }
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,551 @@
+use crate::encoding::OutputEncoding;
+use crate::linkcheck::{figure_label, float_reference, plain_text, table_label};
+use crate::md_ast::{Attributes, MarkdownElement, MarkdownFormat, MarkdownFragment, MarkdownTable};
+use crate::render::{Matter, RenderError, Renderer};
+use crate::texutil::{
+    admonition, asset_path, char_escape, columns_start, decode_entities, educate_quotes_in_context,
+    frag_to_collected_text, heading_section_depth, image_size_options, index_term, is_columns_end,
+    table_caption_parts, write_external_listing, tex_comment, TableStyle, TexConfig,
+};
+use crate::toc::{TableOfContents, TOCLevel};
+
+
+/// Escapes text for LaTeX.
+pub fn escape_latex(text: &str, config: &TexConfig) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => ret.push_str("\\textbackslash{}"),
+            '~' => ret.push_str("\\textasciitilde{}"),
+            '^' => ret.push_str("\\textasciicircum{}"),
+            '{' | '}' | '#' | '%' | '$' | '&' | '_' => {
+                ret.push('\\');
+                ret.push(c);
+            },
+            other if config.is_char_escaped(other) => ret.push_str(&char_escape(other)),
+            '\u{00A0}' => ret.push('~'),
+            '\u{2009}' | '\u{202F}' => ret.push_str("\\,"),
+            '\u{00AD}' => ret.push_str("\\-"),
+            other => ret.push(other),
+        }
+    }
+    ret
+}
+
+/// Escapes a URL for use in `\href` and `\includegraphics`.
+fn escape_url(url: &str) -> String {
+    url.replace('\\', "/").replace('%', "\\%").replace('#', "\\#")
+}
+
+fn section_command(depth: u32) -> &'static str {
+    match depth {
+        0 => "section",
+        1 => "subsection",
+        2 => "subsubsection",
+        3 => "paragraph",
+        _ => "subparagraph",
+    }
+}
+
+fn float_placement(placement: &str) -> &'static str {
+    match placement {
+        "top" => "t",
+        "bottom" => "b",
+        "force" => "h!",
+        "page" => "p",
+        _ => "h",
+    }
+}
+
+
+/// Renders LaTeX code (using the `book` document class).
+pub struct LatexRenderer<'a> {
+    config: &'a TexConfig,
+}
+impl<'a> LatexRenderer<'a> {
+    pub fn new(config: &'a TexConfig) -> LatexRenderer<'a> {
+        LatexRenderer {
+            config,
+        }
+    }
+
+    fn block_environment(&self, environment: &str, content: &str) -> String {
+        format!("\\begin{{{env}}}\n{}\\end{{{env}}}\n\n", content, env = environment)
+    }
+
+    fn code_block_to_latex(&self, language: Option<&str>, content: &MarkdownFragment) -> Result<String, RenderError> {
+        let code = frag_to_collected_text(content)?;
+        let mut ret = String::new();
+
+        if language == Some("verse") {
+            ret.push_str("\\begin{verse}\n");
+            let lines: Vec<String> = code.lines().map(|l| self.render_text(l)).collect();
+            ret.push_str(&lines.join(" \\\\\n"));
+            ret.push_str("\n\\end{verse}\n\n");
+            return Ok(ret);
+        }
+
+        if let Some(min_lines) = self.config.external_listing_min_lines() {
+            if code.lines().count() >= *min_lines {
+                let listing_path = write_external_listing(&code, language, self.config)?;
+                ret.push_str(&format!("\\verbatiminput{{{}}}\n\n", listing_path));
+                return Ok(ret);
+            }
+        }
+
+        // the verbatim environment cannot number lines; line numbering is ignored
+        ret.push_str("\\begin{verbatim}\n");
+        ret.push_str(&code);
+        if !code.is_empty() && !code.ends_with('\n') {
+            ret.push('\n');
+        }
+        ret.push_str("\\end{verbatim}\n\n");
+        Ok(ret)
+    }
+
+    /// Outputs the options of `\includegraphics` according to the attributes of the image, e.g.
+    /// `[width=0.5\textwidth]`, or nothing if there are none.
+    fn graphics_options(&self, attrs: &Attributes) -> String {
+        let options = image_size_options(attrs, self.config);
+        if options.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", options.join(","))
+        }
+    }
+
+    fn cell_to_latex(&self, cell: &MarkdownFragment) -> Result<String, RenderError> {
+        self.config.forget_preceding_text();
+        let mut ret = String::new();
+        for elem in cell.elements() {
+            let elem_tex = match elem {
+                MarkdownElement::Paragraph(subfrag) => {
+                    if !ret.is_empty() {
+                        ret.push(' ');
+                    }
+                    self.render(subfrag)?
+                },
+                MarkdownElement::Table(_) => {
+                    return Err(RenderError::MalformedTable("table nested in a table cell".to_owned()));
+                },
+                other => self.elements_to_latex(std::slice::from_ref(other))?,
+            };
+            ret.push_str(&elem_tex);
+        }
+        Ok(ret.trim().to_owned())
+    }
+
+    fn table_to_latex(&self, table: &MarkdownTable) -> Result<String, RenderError> {
+        let column_count = table.header_rows().iter().chain(table.body_rows())
+            .map(|row| row.len())
+            .chain(std::iter::once(table.alignments().len()))
+            .max()
+            .unwrap_or(0);
+
+        let (separator, rule) = match self.config.table_style() {
+            TableStyle::Grid => ("|", true),
+            _ => ("", false),
+        };
+        let mut column_spec = String::from(separator);
+        for i in 0..column_count {
+            let align = match table.alignments().get(i) {
+                Some('c') => 'c',
+                Some('r') => 'r',
+                _ => 'l',
+            };
+            column_spec.push(align);
+            column_spec.push_str(separator);
+        }
+
+        let mut ret = format!("\\begin{{tabular}}{{{}}}\n", column_spec);
+        if rule || *self.config.table_style() == TableStyle::Minimal {
+            ret.push_str("\\hline\n");
+        }
+        for (is_header, rows) in &[(true, table.header_rows()), (false, table.body_rows())] {
+            for row in rows.iter() {
+                let mut cells = Vec::with_capacity(column_count);
+                for cell in row {
+                    let cell_tex = self.cell_to_latex(cell)?;
+                    if *is_header && !cell_tex.is_empty() {
+                        cells.push(format!("\\textbf{{{}}}", cell_tex));
+                    } else {
+                        cells.push(cell_tex);
+                    }
+                }
+                cells.resize(column_count, String::new());
+                ret.push_str(&cells.join(" & "));
+                ret.push_str(" \\\\\n");
+                if rule {
+                    ret.push_str("\\hline\n");
+                }
+            }
+            if *is_header && !rows.is_empty() && *self.config.table_style() == TableStyle::Minimal {
+                ret.push_str("\\hline\n");
+            }
+        }
+        if *self.config.table_style() == TableStyle::Minimal {
+            ret.push_str("\\hline\n");
+        }
+        ret.push_str("\\end{tabular}\n");
+        Ok(ret)
+    }
+
+    fn elements_to_latex(&self, elements: &[MarkdownElement]) -> Result<String, RenderError> {
+        let config = self.config;
+        let mut ret = String::new();
+        let mut i = 0;
+        while i < elements.len() {
+            let elem = &elements[i];
+            i += 1;
+            if elem.is_block() {
+                config.forget_preceding_text();
+            }
+
+            match elem {
+                MarkdownElement::BlockQuote(subfrag) => {
+                    let mut content = String::new();
+                    let rest = match admonition(subfrag) {
+                        Some((title, first_para_rest)) => {
+                            content.push_str(&format!("\\textbf{{{}}}\n\n", title));
+                            if !first_para_rest.is_empty() {
+                                content.push_str(&self.elements_to_latex(first_para_rest)?);
+                                content.push_str("\n\n");
+                            }
+                            &subfrag.elements()[1..]
+                        },
+                        None => subfrag.elements().as_slice(),
+                    };
+                    content.push_str(&self.elements_to_latex(rest)?);
+                    ret.push_str(&self.block_environment("quote", &content));
+                },
+                MarkdownElement::Div(name, subfrag) => {
+                    let content = self.render(subfrag)?;
+                    ret.push_str(&self.block_environment(config.environment(name), &content));
+                },
+                MarkdownElement::Code(code) => {
+                    ret.push_str(&self.inline_code(code));
+                    config.note_preceding_text(code);
+                },
+                MarkdownElement::CodeBlock(info, subfrag) => {
+                    ret.push_str(&self.code_block_to_latex(info.language(), subfrag)?);
+                },
+                MarkdownElement::Formatting(fmt, subfrag) => {
+                    let command = match fmt {
+                        MarkdownFormat::Emphasis => "emph",
+                        MarkdownFormat::Strong => "textbf",
+                        MarkdownFormat::Strikethrough => "sout",
+                    };
+                    ret.push_str(&format!("\\{}{{{}}}", command, self.render(subfrag)?));
+                },
+                MarkdownElement::Heading(level, subfrag) => {
+                    let command = if config.heading_commands().is_empty() {
+                        if *level == 1 {
+                            // the heading of this level is already output as part of descending the ToC
+                            continue;
+                        }
+                        section_command(heading_section_depth(*level, config)).to_owned()
+                    } else {
+                        match config.heading_commands().get((*level - 1) as usize) {
+                            Some(c) => c.clone(),
+                            None => return Err(RenderError::MissingHeadingCommand(*level)),
+                        }
+                    };
+                    ret.push_str(&format!("\\{}{{{}}}\n", command, self.render(subfrag)?));
+                },
+                MarkdownElement::Link(url, subfrag) => {
+                    if url.trim().is_empty() {
+                        // nothing to link to, e.g. with a draft link `[text]()`
+                        config.warn(format!(
+                            "link {:?} has no destination; outputting its text only",
+                            plain_text(subfrag),
+                        ));
+                        ret.push_str(&self.render(subfrag)?);
+                        continue;
+                    }
+                    let subtex = match subfrag.elements().as_slice() {
+                        [MarkdownElement::Image(image_url, _, attrs)] if !image_url.trim().is_empty() => {
+                            format!(
+                                "\\includegraphics{}{{{}}}",
+                                self.graphics_options(attrs), escape_url(&asset_path(image_url, config)?),
+                            )
+                        },
+                        _ => self.render(subfrag)?,
+                    };
+                    if *config.number_floats() {
+                        if let Some(label) = float_reference(url) {
+                            // a reference to a numbered figure or table
+                            ret.push_str(&format!("{}~\\ref{{{}}}", subtex, label));
+                            continue;
+                        }
+                    }
+                    ret.push_str(&format!("\\href{{{}}}{{{}}}", escape_url(url), subtex));
+                },
+                MarkdownElement::Image(url, subfrag, attrs) => {
+                    if url.trim().is_empty() {
+                        // there is no file to include, e.g. with `![alt]()` or a reference definition `[ref]: <>`
+                        config.warn(format!(
+                            "image {:?} has no destination; outputting its description instead",
+                            plain_text(subfrag),
+                        ));
+                        ret.push_str(&self.render(subfrag)?);
+                        continue;
+                    }
+                    let graphic = format!(
+                        "\\includegraphics{}{{{}}}",
+                        self.graphics_options(attrs), escape_url(&asset_path(url, config)?),
+                    );
+                    let alignment = match attrs.get("align") {
+                        None => None,
+                        Some("left") => Some(("raggedright", "flushleft")),
+                        Some("center") => Some(("centering", "center")),
+                        Some("right") => Some(("raggedleft", "flushright")),
+                        Some(other) => {
+                            config.warn(format!("ignoring unknown image alignment {:?}", other));
+                            None
+                        },
+                    };
+                    let label = if *config.number_floats() { figure_label(subfrag) } else { None };
+                    if *config.float_figures() || label.is_some() {
+                        // the alt text becomes the caption
+                        let declaration = alignment.map(|(d, _e)| d).unwrap_or("centering");
+                        config.count_figure();
+                        ret.push_str(&format!(
+                            "\\begin{{figure}}[{}]\n\\{}\n{}\n\\caption{{{}}}\n",
+                            float_placement(config.float_placement()), declaration, graphic, self.render(subfrag)?,
+                        ));
+                        if let Some(l) = label {
+                            ret.push_str(&format!("\\label{{{}}}\n", l));
+                        }
+                        ret.push_str("\\end{figure}\n");
+                    } else if let Some((_declaration, environment)) = alignment {
+                        ret.push_str(&format!("\\begin{{{e}}}\n{}\n\\end{{{e}}}", graphic, e = environment));
+                    } else {
+                        ret.push_str(&graphic);
+                    }
+                },
+                MarkdownElement::List(start, items) => {
+                    match start {
+                        Some(s) => {
+                            ret.push_str("\n\\begin{enumerate}\n");
+                            if *s != 1 {
+                                ret.push_str(&format!("\\setcounter{{enumi}}{{{}}}\n", s.saturating_sub(1)));
+                            }
+                        },
+                        None => ret.push_str("\n\\begin{itemize}\n"),
+                    }
+                    for item in items {
+                        config.forget_preceding_text();
+                        ret.push_str("\\item ");
+                        ret.push_str(&self.render(item)?);
+                        if !ret.ends_with('\n') {
+                            ret.push('\n');
+                        }
+                    }
+                    if start.is_some() {
+                        ret.push_str("\\end{enumerate}\n");
+                    } else {
+                        ret.push_str("\\end{itemize}\n");
+                    }
+                },
+                MarkdownElement::Paragraph(subfrag) => {
+                    ret.push_str(&self.render(subfrag)?);
+                    ret.push_str("\n\n");
+                },
+                MarkdownElement::Table(table) => {
+                    let label = match elements.get(i) {
+                        Some(MarkdownElement::Paragraph(p)) if *config.number_floats() => table_label(p),
+                        _ => None,
+                    };
+                    let caption = if *config.table_captions() {
+                        match table_caption_parts(elements.get(i)) {
+                            Some((caption_start, caption_rest)) => {
+                                // the caption paragraph is consumed
+                                i += 1;
+                                let mut caption = self.render_text(caption_start);
+                                caption.push_str(&self.elements_to_latex(caption_rest)?);
+                                Some(caption.trim_end().to_owned())
+                            },
+                            None => None,
+                        }
+                    } else {
+                        None
+                    };
+                    let table_tex = self.table_to_latex(table)?;
+
+                    if *config.float_tables() || caption.is_some() {
+                        ret.push_str(&format!(
+                            "\\begin{{table}}[{}]\n\\centering\n",
+                            float_placement(config.float_placement()),
+                        ));
+                        ret.push_str(&table_tex);
+                        if let Some(cap) = caption {
+                            ret.push_str(&format!("\\caption{{{}}}\n", cap));
+                            if let Some(l) = label {
+                                ret.push_str(&format!("\\label{{{}}}\n", l));
+                            }
+                        }
+                        ret.push_str("\\end{table}\n\n");
+                    } else {
+                        ret.push_str(&table_tex);
+                        ret.push('\n');
+                    }
+                },
+                MarkdownElement::Text(text) => {
+                    ret.push_str(&self.render_text(text));
+                },
+                MarkdownElement::HtmlFragment(html) => {
+                    if let Some(term) = index_term(html) {
+                        config.count_index_entry();
+                        ret.push_str(&format!("\\index{{{}}}", self.render_text(&decode_entities(term))));
+                        if html.ends_with('\n') {
+                            ret.push('\n');
+                        }
+                        continue;
+                    }
+                    if let Some(columns) = columns_start(html) {
+                        ret.push_str(&format!("\\begin{{multicols}}{{{}}}\n", columns));
+                        continue;
+                    }
+                    if is_columns_end(html) {
+                        ret.push_str("\\end{multicols}\n\n");
+                        continue;
+                    }
+
+                    ret.push_str(&tex_comment(html));
+                },
+                MarkdownElement::FootnoteRef(foot_name) => {
+                    // footnote definitions are not supported; show the reference like Markdown does
+                    ret.push_str(&format!("\\textsuperscript{{{}}}", self.render_text(foot_name)));
+                },
+                MarkdownElement::Rule => {
+                    ret.push_str("\\begin{center}\\rule{0.5\\linewidth}{0.5pt}\\end{center}\n\n");
+                },
+                MarkdownElement::Unsupported(description, range) => {
+                    let offset = range.as_ref().map(|r| r.start);
+                    if !*config.lenient() {
+                        return Err(RenderError::UnsupportedElement(format!("Markdown construct {}", description), offset));
+                    }
+                    config.warn_at(format!("skipped unsupported Markdown construct {}", description), offset);
+                    ret.push_str(&format!("% skipped unsupported Markdown construct {}\n", description.replace("\n", " ")));
+                },
+            }
+        }
+        Ok(ret)
+    }
+}
+impl<'a> Renderer for LatexRenderer<'a> {
+    fn render(&self, frag: &MarkdownFragment) -> Result<String, RenderError> {
+        self.elements_to_latex(frag.elements())
+    }
+
+    fn escape_text(&self, text: &str) -> String {
+        escape_latex(text, self.config)
+    }
+
+    fn educate_quotes(&self, text: &str) -> String {
+        educate_quotes_in_context(text, self.config)
+    }
+
+    fn inline_code(&self, code: &str) -> String {
+        format!("\\texttt{{{}}}", escape_latex(code, self.config))
+    }
+
+    fn begin_document(&self, toc: &TableOfContents, encoding: OutputEncoding) -> String {
+        let input_encoding = match encoding {
+            OutputEncoding::Utf8 => "utf8",
+            OutputEncoding::Latin1 => "latin1",
+        };
+        let mut ret = String::new();
+        ret.push_str("\\documentclass{book}\n");
+        ret.push_str(&format!("\\usepackage[{}]{{inputenc}}\n", input_encoding));
+        ret.push_str("\\usepackage[T1]{fontenc}\n");
+        ret.push_str("\\usepackage{graphicx}\n");
+        ret.push_str("\\usepackage{verbatim}\n");
+        ret.push_str("\\usepackage[normalem]{ulem}\n");
+        ret.push_str("\\usepackage{multicol}\n");
+        ret.push_str("\\usepackage{makeidx}\n");
+        if let Some(language) = toc.metadata().language() {
+            ret.push_str("\\usepackage{babel}\n");
+            ret.push_str(&format!("\\babelprovide[import, main]{ob}{}{cb}\n", language, ob = '{', cb = '}'));
+        }
+        ret.push_str("\\usepackage{hyperref}\n");
+        ret.push_str("\\makeindex\n");
+        ret.push_str(&format!("\\title{{{}}}\n", toc.title()));
+        if let Some(author) = toc.metadata().author() {
+            ret.push_str(&format!("\\author{{{}}}\n", author));
+        }
+        if let Some(date) = toc.metadata().date() {
+            ret.push_str(&format!("\\date{{{}}}\n", date));
+        }
+        ret.push_str("\n\\begin{document}\n\n\\maketitle\n\\tableofcontents\n\n");
+        ret
+    }
+
+    fn end_document(&self) -> String {
+        "\\end{document}\n".to_owned()
+    }
+
+    fn begin_matter(&self, matter: Matter) -> String {
+        match matter {
+            Matter::Front => "\n\\frontmatter\n",
+            Matter::Body => "\n\\mainmatter\n",
+            Matter::Appendices => "\n\\appendix\n",
+            Matter::Back => "\n\\backmatter\n",
+        }.to_owned()
+    }
+
+    fn end_matter(&self, _matter: Matter) -> String {
+        String::new()
+    }
+
+    fn begin_language(&self, language: &str) -> String {
+        // babel must already know the language, e.g. from a class option
+        format!("\n\\begin{ob}otherlanguage{cb}{ob}{}{cb}\n", language, ob = '{', cb = '}')
+    }
+
+    fn end_language(&self) -> String {
+        "\n\\end{otherlanguage}\n".to_owned()
+    }
+
+    fn heading(&self, level: &TOCLevel, numbered: bool, title: &str, short_title: Option<&str>) -> String {
+        let command = match level {
+            TOCLevel::Part => "part",
+            TOCLevel::Chapter => "chapter",
+            TOCLevel::Section(i) => section_command(*i),
+        };
+        match (numbered, short_title) {
+            (true, Some(st)) => format!("\n\\{}[{}]{{{}}}\n", command, st, title),
+            (true, None) => format!("\n\\{}{{{}}}\n", command, title),
+            // starred headings take no short title
+            (false, _) => format!("\n\\{}*{{{}}}\n", command, title),
+        }
+    }
+
+    fn generated_back_matter(&self) -> String {
+        // unlike chapters, the lists do not add themselves to the table of contents
+        let mut ret = String::new();
+        if *self.config.list_of_figures() && self.config.figure_count() > 0 {
+            ret.push_str("\n\\cleardoublepage\n\\phantomsection\n\\addcontentsline{toc}{chapter}{\\listfigurename}\n\\listoffigures\n");
+        }
+        if self.config.index_entry_count() > 0 {
+            ret.push_str("\n\\cleardoublepage\n\\phantomsection\n\\addcontentsline{toc}{chapter}{\\indexname}\n\\printindex\n");
+        }
+        ret
+    }
+
+    fn error_marker(&self, message: &str) -> String {
+        format!(
+            "% error: {}\n\\fbox{{error: {}}}\n\n",
+            message.replace("\n", "\n% "), self.render_text(message),
+        )
+    }
+
+    fn verbatim_markers(&self) -> Vec<(String, String)> {
+        vec![("\\begin{verbatim}".to_owned(), "\\end{verbatim}".to_owned())]
+    }
+
+    fn literal_markers(&self) -> (Vec<(String, String)>, Vec<String>) {
+        // inline code is output as escaped text
+        (self.verbatim_markers(), Vec::new())
+    }
+}
@@ -0,0 +1,14 @@
+mod macros;
+pub mod context;
+pub mod diagnostic;
+pub mod encoding;
+pub mod latex;
+pub mod linkcheck;
+pub mod md_ast;
+pub mod md_writer;
+pub mod preprocess;
+pub mod pretty;
+pub mod render;
+pub mod stats;
+pub mod texutil;
+pub mod toc;
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::md_ast::{walk, MarkdownElement, MarkdownFragment, MarkdownVisitor};
+use crate::toc::percent_decode;
+
+
+/// Collects the plain text of a fragment, ignoring formatting.
+pub fn plain_text(frag: &MarkdownFragment) -> String {
+    let mut ret = String::new();
+    for elem in frag.elements() {
+        match elem {
+            MarkdownElement::Text(text) | MarkdownElement::Code(text) => ret.push_str(text),
+            MarkdownElement::Formatting(_, subfrag)
+                | MarkdownElement::Link(_, subfrag)
+                | MarkdownElement::Paragraph(subfrag) => ret.push_str(&plain_text(subfrag)),
+            _ => {},
+        }
+    }
+    ret
+}
+
+/// Derives the anchor slug of a heading the way mdBook and GitHub do: lowercase, spaces become
+/// hyphens, and punctuation is dropped.
+pub fn slugify(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter_map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                Some(c.to_lowercase().collect::<String>())
+            } else if c.is_whitespace() {
+                Some("-".to_owned())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Derives the label of a numbered figure from its alternative text, e.g. `fig:system-overview`.
+pub fn figure_label(alt_text: &MarkdownFragment) -> Option<String> {
+    let slug = slugify(&plain_text(alt_text));
+    if slug.is_empty() {
+        return None;
+    }
+    Some(format!("fig:{}", slug))
+}
+
+/// Derives the label of a numbered table from the `Table:` paragraph captioning it, e.g.
+/// `tab:measurements`.
+pub fn table_label(caption: &MarkdownFragment) -> Option<String> {
+    let slug = slugify(plain_text(caption).strip_prefix("Table:")?);
+    if slug.is_empty() {
+        return None;
+    }
+    Some(format!("tab:{}", slug))
+}
+
+/// If a link points to a numbered figure or table (`#fig:...` or `#tab:...`), returns its label.
+pub fn float_reference(target: &str) -> Option<&str> {
+    let label = target.strip_prefix('#')?;
+    if label.starts_with("fig:") || label.starts_with("tab:") {
+        Some(label)
+    } else {
+        None
+    }
+}
+
+#[derive(Default)]
+struct LinkCollector {
+    links: Vec<String>,
+    images: Vec<String>,
+    slugs: BTreeSet<String>,
+}
+impl MarkdownVisitor for LinkCollector {
+    fn visit_link(&mut self, url: &str, _content: &MarkdownFragment) {
+        self.links.push(url.to_owned());
+    }
+
+    fn visit_heading(&mut self, _level: u32, content: &MarkdownFragment) {
+        self.slugs.insert(slugify(&plain_text(content)));
+    }
+
+    fn visit_paragraph(&mut self, content: &MarkdownFragment) {
+        if let Some(label) = table_label(content) {
+            self.slugs.insert(label);
+        }
+    }
+
+    fn visit_image(&mut self, url: &str, alt_text: &MarkdownFragment) {
+        self.images.push(url.to_owned());
+        if let Some(label) = figure_label(alt_text) {
+            self.slugs.insert(label);
+        }
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn is_external(target: &str) -> bool {
+    target.contains("://") || target.starts_with("mailto:")
+}
+
+
+/// Gathers the links, images and heading anchors of all converted files and reports the links
+/// that point nowhere and the images that do not exist.
+pub struct LinkChecker {
+    links: Vec<(PathBuf, String)>,
+    images: Vec<(PathBuf, String)>,
+    slugs: BTreeMap<PathBuf, BTreeSet<String>>,
+}
+impl LinkChecker {
+    pub fn new() -> LinkChecker {
+        LinkChecker {
+            links: Vec::new(),
+            images: Vec::new(),
+            slugs: BTreeMap::new(),
+        }
+    }
+
+    /// Records the links and headings of a converted Markdown file.
+    pub fn add_file(&mut self, path: &Path, frag: &MarkdownFragment) {
+        let mut collector = LinkCollector::default();
+        walk(frag, &mut collector);
+
+        let norm_path = normalize(path);
+        for link in collector.links {
+            self.links.push((norm_path.clone(), link));
+        }
+        for image in collector.images {
+            self.images.push((norm_path.clone(), image));
+        }
+        self.slugs.entry(norm_path).or_default().append(&mut collector.slugs);
+    }
+
+    /// Returns a warning for each link to a nonexistent file or anchor.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (source, target) in &self.links {
+            if is_external(target) || target.is_empty() {
+                continue;
+            }
+
+            let (target_file, anchor) = match target.find('#') {
+                Some(hash_index) => (&target[..hash_index], Some(&target[hash_index+1..])),
+                None => (target.as_str(), None),
+            };
+
+            let target_path = if target_file.is_empty() {
+                source.clone()
+            } else {
+                let mut tp = source.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+                tp.push(percent_decode(target_file));
+                if !tp.exists() {
+                    warnings.push(format!("{}: link to nonexistent file {:?}", source.display(), target));
+                    continue;
+                }
+                normalize(&tp)
+            };
+
+            if let Some(a) = anchor {
+                // only files that are part of the book have known anchors
+                if let Some(target_slugs) = self.slugs.get(&target_path) {
+                    if !target_slugs.contains(a) {
+                        warnings.push(format!("{}: link to nonexistent anchor {:?}", source.display(), target));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Returns an error message for each local image that does not exist, resolved relative to
+    /// the file referencing it.
+    pub fn missing_images(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        for (source, target) in &self.images {
+            if is_external(target) || target.starts_with("data:") || target.trim().is_empty() {
+                continue;
+            }
+            let mut image_path = source.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+            image_path.push(percent_decode(target));
+            if !image_path.is_file() {
+                missing.push(format!("{}: image {:?} not found", source.display(), target));
+            }
+        }
+        missing
+    }
+}
+impl Default for LinkChecker {
+    fn default() -> Self {
+        LinkChecker::new()
+    }
+}
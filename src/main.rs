@@ -1,143 +1,1226 @@
-mod macros;
-mod md_ast;
 mod opts;
-mod texutil;
-mod toc;
 
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use clap::ErrorKind;
 use clap::derive::Clap;
 
-use crate::opts::Opts;
+use md_context::{md_ast, texutil, toc};
+use md_context::diagnostic::{Diagnostic, ErrorFormat};
+use md_context::encoding::{EncodingWriter, InputEncoding, OutputEncoding};
+use md_context::linkcheck::LinkChecker;
+use md_context::latex::LatexRenderer;
+use md_context::pretty::PrettyWriter;
+use md_context::context::ContextRenderer;
+use md_context::render::{Matter, Renderer};
+use md_context::stats::Statistics;
+use md_context::texutil::{TableStyle, TexConfig};
 
+use crate::opts::{BookOpts, BuildOpts, CheckOpts, Command, ConvertOpts, Opts, WatchOpts, SUBCOMMAND_NAMES};
 
-fn output_section(output_file: &mut File, section: &toc::TOCEntry, book_path: &str) -> i32 {
-    if let Err(err) = write!(
-        output_file,
-        "\n\\{lvl}{ob}{t}{cb}\n",
-        lvl = section.level().tex_string(),
-        ob = '{',
-        t = section.title(),
-        cb = '}'
-    ) {
-        eprintln!("failed to output section heading: {}", err);
+
+/// Reports an error that does not concern a specific place in the input.
+fn report_error<M: AsRef<str>>(message: M) {
+    Diagnostic::error(message).report(ErrorFormat::Human);
+}
+
+fn report_warning<M: AsRef<str>>(message: M) {
+    Diagnostic::warning(message).report(ErrorFormat::Human);
+}
+
+/// Returns the Markdown files making up a section: either the section path itself or, if it is a
+/// directory, all Markdown files within it in lexical order.
+fn section_files(section_path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !section_path.is_dir() {
+        return Ok(vec![section_path.to_path_buf()]);
+    }
+
+    let entries = match fs::read_dir(section_path) {
+        Ok(e) => e,
+        Err(err) => return Err(format!("failed to list section directory {:?}: {}", section_path, err)),
+    };
+    let mut md_files = Vec::new();
+    for entry_res in entries {
+        let entry = match entry_res {
+            Ok(e) => e,
+            Err(err) => return Err(format!("failed to list section directory {:?}: {}", section_path, err)),
+        };
+        let path = entry.path();
+        if path.is_file() && path.extension().map(|ext| ext == "md").unwrap_or(false) {
+            md_files.push(path);
+        }
+    }
+    md_files.sort();
+    Ok(md_files)
+}
+
+fn find_section_file(source_dirs: &[String], section_path: &Path) -> Result<PathBuf, Vec<PathBuf>> {
+    let mut tried_paths = Vec::with_capacity(source_dirs.len());
+    for source_dir in source_dirs {
+        let mut candidate: PathBuf = PathBuf::new();
+        candidate.push(source_dir);
+        candidate.push(section_path);
+        if candidate.is_file() || candidate.is_dir() {
+            return Ok(candidate);
+        }
+        tried_paths.push(candidate);
+    }
+    Err(tried_paths)
+}
+
+/// Formats a number of seconds since the Unix epoch as an ISO 8601 UTC timestamp.
+fn format_utc_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86400) as i64;
+    let secs_of_day = unix_secs % 86400;
+
+    // civil-from-days (proleptic Gregorian calendar)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60,
+    )
+}
+
+fn write_banner<W: Write>(output_file: &mut W, opts: &BuildOpts) -> std::io::Result<()> {
+    write!(output_file, "% generated by md-context {}\n", env!("CARGO_PKG_VERSION"))?;
+    write!(output_file, "% source directory: {}\n", opts.book.directory)?;
+    for src in &opts.book.src {
+        write!(output_file, "% additional source directory: {}\n", src)?;
+    }
+    if !opts.no_timestamp {
+        // honor SOURCE_DATE_EPOCH (https://reproducible-builds.org/specs/source-date-epoch/)
+        let now_secs = match env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse::<u64>().ok()) {
+            Some(sde) => sde,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        write!(output_file, "% generated at: {}\n", format_utc_timestamp(now_secs))?;
+    }
+    write!(output_file, "%\n")
+}
+
+fn collect_section_files(sections: &[toc::TOCEntry], source_dirs: &[String], files: &mut Vec<PathBuf>, missing: &mut Vec<PathBuf>) {
+    for section in sections {
+        if let Some(sp) = section.path() {
+            match find_section_file(source_dirs, sp) {
+                Ok(section_path) => match section_files(&section_path) {
+                    Ok(mut sfs) => files.append(&mut sfs),
+                    Err(_) => missing.push(section_path),
+                },
+                Err(_) => missing.push(sp.to_path_buf()),
+            }
+        }
+        collect_section_files(section.child_entries(), source_dirs, files, missing);
+    }
+}
+
+fn all_section_files(toc: &toc::TableOfContents, source_dirs: &[String]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+    for sections in &[toc.front_matter_sections(), toc.body_matter_sections(), toc.appendix_sections(), toc.back_matter_sections()] {
+        collect_section_files(sections, source_dirs, &mut files, &mut missing);
+    }
+    (files, missing)
+}
+
+/// Checks whether a file starts with the banner written by `write_banner`.
+fn is_generated_file(path: &Path) -> bool {
+    let mut start = [0u8; 25];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut start)) {
+        Ok(()) => &start == b"% generated by md-context",
+        Err(_) => false,
+    }
+}
+
+/// Creates (or truncates) the output file, creating its parent directory if it does not exist.
+///
+/// An existing file is not overwritten with `no_clobber`; overwriting a file that was not
+/// generated by md-context causes a warning unless `force` is set.
+fn create_output_file(out_file: &str, no_clobber: bool, force: bool) -> Result<File, String> {
+    let out_path = Path::new(out_file);
+    if out_path.exists() {
+        if no_clobber {
+            return Err(format!("output file {:?} already exists and --no-clobber was given", out_file));
+        }
+        if !force && !is_generated_file(out_path) {
+            report_warning(format!("overwriting {:?}, which was not generated by md-context (pass --force to silence this warning)", out_file));
+        }
+    }
+    if let Some(parent) = out_path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                return Err(format!("failed to create output directory {:?}: {}", parent, err));
+            }
+        }
+    }
+    File::create(out_path)
+        .map_err(|err| format!("failed to open output file {:?}: {}", out_file, err))
+}
+
+/// Converts a single Markdown document, read from standard input if `input` is `-`, without the
+/// book machinery. Only the converted body is output.
+fn convert_single(input: &str, opts: &BuildOpts, renderer: &dyn Renderer, config: &TexConfig, encoding: OutputEncoding, error_format: ErrorFormat) -> i32 {
+    let out_file = opts.out_file.as_str();
+    let mut md_bytes = Vec::new();
+    let (read_result, base_dir) = if input == "-" {
+        (io::stdin().read_to_end(&mut md_bytes), Path::new("."))
+    } else {
+        let base_dir = Path::new(input).parent().unwrap_or_else(|| Path::new("."));
+        (File::open(input).and_then(|mut f| f.read_to_end(&mut md_bytes)), base_dir)
+    };
+    if let Err(err) = read_result {
+        report_error(format!("failed to read {:?}: {}", input, err));
         return 1;
     }
+    let md_string = match config.input_encoding().decode(&md_bytes) {
+        Ok(s) => s,
+        Err(err) => {
+            report_error(format!("{:?} is {}", input, err));
+            return 1;
+        },
+    };
 
-    if let Some(sp) = section.path() {
-        let mut section_path: PathBuf = PathBuf::new();
-        section_path.push(book_path);
-        section_path.push(sp);
-        let section_frag = match md_ast::load(&section_path) {
-            Ok(ast) => ast,
+    let parsed = md_ast::parse_str(&md_string, base_dir, *config.input_encoding())
+        .and_then(|(frag, _front_matter)| md_ast::apply_conditionals(frag, config.profiles()));
+    let frag = match parsed {
+        Ok(frag) => frag,
+        Err(err) => {
+            Diagnostic::from(err.in_file(Path::new(input))).report(error_format);
+            return 1;
+        },
+    };
+    config.set_source_dir(base_dir);
+    let tex = match renderer.render(&frag) {
+        Ok(t) => texutil::post_process(t, config),
+        Err(err) => {
+            Diagnostic::error(format!("failed to transform {:?} to TeX: {}", input, err))
+                .with_file(Path::new(input))
+                .with_offset(err.offset())
+                .report(error_format);
+            return 1;
+        },
+    };
+    for (warning, offset) in config.take_positioned_warnings() {
+        Diagnostic::warning(warning).with_file(Path::new(input)).with_offset(offset).report(error_format);
+    }
+
+    let output: Box<dyn Write> = if out_file == "-" {
+        Box::new(io::stdout())
+    } else {
+        match create_output_file(out_file, opts.no_clobber, opts.force) {
+            Ok(f) => Box::new(f),
             Err(err) => {
-                eprintln!("failed to parse section: {}", err);
+                report_error(err);
+                return 1;
+            },
+        }
+    };
+    let (literal_markers, literal_commands) = renderer.literal_markers();
+    let mut output_writer = EncodingWriter::new(output, encoding, opts.escape_non_latin1, literal_markers, literal_commands);
+    if let Err(err) = output_writer.write_all(tex.as_bytes()).and_then(|_| output_writer.flush()) {
+        report_error(format!("failed to write output: {}", err));
+        return 1;
+    }
+    0
+}
+
+/// Prints the syntax tree of a Markdown file as JSON.
+#[cfg(feature = "json")]
+fn dump_ast(path: &Path, encoding: InputEncoding) -> i32 {
+    let frag = match md_ast::load_with_front_matter(path, encoding).map(|(frag, _front_matter)| frag) {
+        Ok(f) => f,
+        Err(err) => {
+            report_error(format!("failed to load {:?}: {}", path, err));
+            return 1;
+        },
+    };
+    match serde_json::to_string_pretty(&frag) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        },
+        Err(err) => {
+            report_error(format!("failed to serialize the syntax tree: {}", err));
+            1
+        },
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn dump_ast(_path: &Path, _encoding: InputEncoding) -> i32 {
+    report_error("dump-ast requires md-context to be built with the \"json\" feature");
+    1
+}
+
+/// Prints the table of contents as JSON.
+#[cfg(feature = "json")]
+fn dump_toc(toc: &toc::TableOfContents) -> i32 {
+    match serde_json::to_string_pretty(toc) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        },
+        Err(err) => {
+            report_error(format!("failed to serialize the table of contents: {}", err));
+            1
+        },
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn dump_toc(_toc: &toc::TableOfContents) -> i32 {
+    report_error("dump-toc requires md-context to be built with the \"json\" feature");
+    1
+}
+
+/// Prints the paths of all section files referenced by the ToC, one per line.
+fn list_files(toc: &toc::TableOfContents, source_dirs: &[String]) -> i32 {
+    let (files, missing) = all_section_files(toc, source_dirs);
+
+    for file in &files {
+        println!("{}", file.display());
+    }
+    for missing_file in &missing {
+        report_error(format!("section file not found: {}", missing_file.display()));
+    }
+
+    if missing.is_empty() { 0 } else { 1 }
+}
+
+/// Adds up the statistics of a section and its subsections.
+fn add_section_stats(section: &toc::TOCEntry, source_dirs: &[String], encoding: InputEncoding, stats: &mut Statistics) -> Result<(), String> {
+    if let Some(sp) = section.path() {
+        let section_path = match find_section_file(source_dirs, sp) {
+            Ok(p) => p,
+            Err(tried_paths) => {
+                return Err(format!("failed to find section {:?}; tried: {:?}", sp, tried_paths));
+            },
+        };
+        for file_path in section_files(&section_path)? {
+            let file_frag = match md_ast::load_with_front_matter(&file_path, encoding) {
+                Ok((ast, _front_matter)) => ast,
+                Err(err) => return Err(format!("failed to parse section {:?}: {}", file_path, err)),
+            };
+            *stats += Statistics::of_fragment(&file_frag);
+        }
+    }
+    for child_section in section.child_entries() {
+        add_section_stats(child_section, source_dirs, encoding, stats)?;
+    }
+    Ok(())
+}
+
+fn print_stats_line(stats: &Statistics, label: &str) {
+    println!(
+        "{:>8} {:>8} {:>7} {:>12}  {}",
+        stats.words(), stats.figures(), stats.tables(), stats.code_blocks(), label,
+    );
+}
+
+/// Prints the word count and the numbers of figures, tables and code blocks of each chapter and
+/// of the whole book.
+fn print_stats(toc: &toc::TableOfContents, source_dirs: &[String], encoding: InputEncoding) -> i32 {
+    println!("{:>8} {:>8} {:>7} {:>12}  {}", "words", "figures", "tables", "code blocks", "chapter");
+
+    let mut total = Statistics::new();
+    for sections in &[toc.front_matter_sections(), toc.body_matter_sections(), toc.appendix_sections(), toc.back_matter_sections()] {
+        // parts are not chapters themselves; list the chapters within them
+        let chapters = sections.iter().flat_map(|section| match section.level() {
+            toc::TOCLevel::Part => section.child_entries().iter().collect(),
+            _ => vec![section],
+        });
+        for chapter in chapters {
+            let mut stats = Statistics::new();
+            if let Err(err) = add_section_stats(chapter, source_dirs, encoding, &mut stats) {
+                report_error(err);
                 return 1;
+            }
+            print_stats_line(&stats, chapter.title());
+            total += stats;
+        }
+    }
+
+    print_stats_line(&total, "total");
+    0
+}
+
+fn escape_make_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.display().to_string().chars() {
+        match c {
+            ' ' | '#' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
             },
+            '$' => escaped.push_str("$$"),
+            other => escaped.push(other),
+        }
+    }
+    escaped
+}
+
+/// Writes a Make rule making the output file depend on the ToC and all section files.
+fn write_deps(deps_path: &str, out_file: &str, toc_path: &Path, section_files: &[PathBuf]) -> std::io::Result<()> {
+    let mut rule = escape_make_path(Path::new(out_file));
+    rule.push(':');
+    for dep in std::iter::once(&toc_path.to_path_buf()).chain(section_files) {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape_make_path(dep));
+    }
+    rule.push('\n');
+    fs::write(deps_path, rule)
+}
+
+/// The results of parsing section files in advance, by path.
+type PreloadedSections = HashMap<PathBuf, Result<(md_ast::MarkdownFragment, md_ast::FrontMatter), md_ast::ASTError>>;
+
+/// State shared while outputting the sections of a book.
+struct OutputContext<'a> {
+    source_dirs: &'a [String],
+    config: &'a TexConfig,
+    renderer: &'a dyn Renderer,
+    keep_going: bool,
+    check_images: bool,
+    error_format: ErrorFormat,
+    failures: Vec<Diagnostic>,
+    link_checker: LinkChecker,
+    // section files parsed in advance, in parallel
+    preloaded: PreloadedSections,
+}
+
+/// Reports broken links, missing images (if requested) and failed sections once all sections
+/// have been output.
+fn report_output_problems(ctx: &OutputContext) -> i32 {
+    for warning in ctx.link_checker.warnings() {
+        Diagnostic::warning(warning).report(ctx.error_format);
+    }
+
+    let mut code = 0;
+    if ctx.check_images {
+        // report them all at once instead of failing on the first one
+        for missing in ctx.link_checker.missing_images() {
+            Diagnostic::error(missing).report(ctx.error_format);
+            code = 1;
+        }
+    }
+
+    if !ctx.failures.is_empty() {
+        Diagnostic::error(format!("{} section(s) failed", ctx.failures.len())).report(ctx.error_format);
+        for failure in &ctx.failures {
+            failure.report(ctx.error_format);
+        }
+        return 1;
+    }
+
+    code
+}
+
+/// Loads and converts a section, returning its title, TeX body and language.
+fn section_to_tex(section: &toc::TOCEntry, ctx: &mut OutputContext) -> Result<(String, Option<String>, Option<String>), Diagnostic> {
+    let mut title = section.title().to_owned();
+    let mut language = None;
+    let sp = match section.path() {
+        Some(sp) => sp,
+        None => return Ok((title, None, None)),
+    };
+
+    let section_path = match find_section_file(ctx.source_dirs, sp) {
+        Ok(p) => p,
+        Err(tried_paths) => {
+            return Err(format!("failed to find section {:?}; tried: {:?}", sp, tried_paths).into());
+        },
+    };
+    let mut section_tex = String::new();
+    for (i, file_path) in section_files(&section_path)?.iter().enumerate() {
+        let loaded = match ctx.preloaded.remove(file_path) {
+            Some(l) => l,
+            None => md_ast::load_with_front_matter(file_path, *ctx.config.input_encoding()),
+        };
+        let (file_frag, front_matter) = match loaded {
+            Ok(ast) => ast,
+            Err(err) => return Err(err.into()),
+        };
+        let file_frag = match md_ast::apply_conditionals(file_frag, ctx.config.profiles()) {
+            Ok(frag) => frag,
+            Err(err) => return Err(err.in_file(file_path).into()),
         };
 
-        let section_tex = match texutil::frag_to_tex(&section_frag) {
-            Ok(tex) => tex,
+        // a title in the front matter (of the first file) overrides the one from the ToC
+        if i == 0 {
+            if let Some(fm_title) = front_matter.get("title") {
+                title = ctx.renderer.render_text(&texutil::decode_entities(fm_title));
+            }
+            if let Some(fm_language) = front_matter.get("language") {
+                if !texutil::is_language_code(fm_language.trim()) {
+                    let message = format!("invalid language code {:?}", fm_language);
+                    return Err(Diagnostic::error(message).with_file(file_path));
+                }
+                language = Some(fm_language.trim().to_owned());
+            }
+        }
+
+        ctx.link_checker.add_file(file_path, &file_frag);
+        ctx.config.set_source_dir(file_path.parent().unwrap_or_else(|| Path::new(".")));
+        ctx.config.set_heading_depth(section.level().heading_depth());
+
+        match ctx.renderer.render(&file_frag) {
+            Ok(tex) => section_tex.push_str(&tex),
             Err(err) => {
-                eprintln!("failed to transform section to TeX: {}", err);
-                return 1;
+                let message = format!("failed to transform section {:?} to TeX: {}", file_path, err);
+                return Err(Diagnostic::error(message).with_file(file_path).with_offset(err.offset()));
             }
         };
+        for (warning, offset) in ctx.config.take_positioned_warnings() {
+            Diagnostic::warning(warning).with_file(file_path).with_offset(offset).report(ctx.error_format);
+        }
+    }
+
+    Ok((title, Some(section_tex), language))
+}
+
+fn output_section<W: Write + ?Sized>(output_file: &mut W, section: &toc::TOCEntry, numbered: bool, ctx: &mut OutputContext) -> i32 {
+    let (title, section_tex, language) = match section_to_tex(section, ctx) {
+        Ok(tt) => tt,
+        Err(err) => {
+            err.report(ctx.error_format);
+            if !ctx.keep_going {
+                return 1;
+            }
 
-        if let Err(err) = write!(output_file, "{}", section_tex) {
-            eprintln!("failed to output section: {}", err);
+            // leave a visible marker in place of the section and carry on
+            let marker = ctx.renderer.error_marker(&err.to_string());
+            ctx.failures.push(err);
+            (section.title().to_owned(), Some(marker), None)
+        },
+    };
+
+    if let Some(lang) = &language {
+        if let Err(err) = write!(output_file, "{}", ctx.renderer.begin_language(lang)) {
+            report_error(format!("failed to output section language: {}", err));
+            return 1;
+        }
+    }
+
+    if let Err(err) = write!(output_file, "{}", ctx.renderer.heading(section.level(), numbered, &title, section.short_title())) {
+        report_error(format!("failed to output section heading: {}", err));
+        return 1;
+    }
+
+    if let Some(tex) = section_tex {
+        if let Err(err) = write!(output_file, "{}", tex) {
+            report_error(format!("failed to output section: {}", err));
             return 1;
         }
     }
 
     for child_section in section.child_entries() {
-        let code = output_section(output_file, child_section, book_path);
+        let code = output_section(output_file, child_section, numbered, ctx);
         if code != 0 {
             return 1;
         }
     }
 
+    if language.is_some() {
+        if let Err(err) = write!(output_file, "{}", ctx.renderer.end_language()) {
+            report_error(format!("failed to output end of section language: {}", err));
+            return 1;
+        }
+    }
+
+    0
+}
+
+/// Outputs the whole book, passing it through the post-processors before writing it.
+fn output_tex<W: Write>(output_file: &mut W, toc: &toc::TableOfContents, encoding: OutputEncoding, ctx: &mut OutputContext) -> i32 {
+    let mut document: Vec<u8> = Vec::new();
+    let code = assemble_tex(&mut document, toc, encoding, ctx);
+    if code != 0 {
+        return code;
+    }
+
+    let document = String::from_utf8(document).expect("TeX output is not valid UTF-8");
+    if let Err(err) = output_file.write_all(texutil::post_process(document, ctx.config).as_bytes()) {
+        report_error(format!("failed to write output: {}", err));
+        return 1;
+    }
     0
 }
 
-fn output_tex(output_file: &mut File, toc: &toc::TableOfContents, book_path: &str) -> i32 {
-    if let Err(err) = write!(
-        output_file,
-        "\\setupinteraction[title={ob}{t}{cb}]\n\n\\starttext\n\n\\mdcontextplacetoc\n\n",
-        ob = '{', t = toc.title(), cb = '}',
-    ) {
-        eprintln!("error writing preamble: {}", err);
+/// The sections of each matter of the book, along with whether their headings are numbered.
+fn matter_sections(toc: &toc::TableOfContents) -> Vec<(Matter, &Vec<toc::TOCEntry>, bool)> {
+    // front and back matter (preface, afterword, etc.) use the unnumbered heading variants
+    vec![
+        (Matter::Front, toc.front_matter_sections(), false),
+        (Matter::Body, toc.body_matter_sections(), true),
+        (Matter::Appendices, toc.appendix_sections(), true),
+        (Matter::Back, toc.back_matter_sections(), false),
+    ]
+}
+
+/// Returns the material the renderer generates at the end of the given matter, such as the
+/// index and lists at the end of the back matter.
+fn generated_matter(matter: Matter, renderer: &dyn Renderer) -> String {
+    // the index and lists go into the back matter, so that is needed even without back matter
+    // sections
+    if matter == Matter::Back { renderer.generated_back_matter() } else { String::new() }
+}
+
+fn assemble_tex<W: Write>(output_file: &mut W, toc: &toc::TableOfContents, encoding: OutputEncoding, ctx: &mut OutputContext) -> i32 {
+    if let Err(err) = write!(output_file, "{}", ctx.renderer.begin_document(toc, encoding)) {
+        report_error(format!("failed to write preamble: {}", err));
         return 1;
     }
 
-    let sections = vec![
-        ("frontmatter", toc.front_matter_sections()),
-        ("bodymatter", toc.body_matter_sections()),
-        ("appendices", toc.appendix_sections()),
-        ("backmatter", toc.back_matter_sections()),
-    ];
-    for (matter_tex, matter_sections) in sections {
-        if matter_sections.is_empty() {
+    for (matter, sections, numbered) in matter_sections(toc) {
+        let mut body: Vec<u8> = Vec::new();
+        for section in sections {
+            let code = output_section(&mut body, section, numbered, ctx);
+            if code != 0 {
+                return code;
+            }
+        }
+
+        // only known now, as the sections add index entries and figures
+        let generated = generated_matter(matter, ctx.renderer);
+        if sections.is_empty() && generated.is_empty() {
             continue;
         }
 
-        if let Err(err) = write!(output_file, "\n\\start{}\n", matter_tex) {
-            eprintln!("error writing opening of {}: {}", matter_tex, err);
+        if let Err(err) = write!(output_file, "{}", ctx.renderer.begin_matter(matter)) {
+            report_error(format!("failed to write opening of {:?} matter: {}", matter, err));
             return 1;
         }
 
-        for section in matter_sections {
-            let code = output_section(output_file, section, book_path);
-            if code != 0 {
-                return code;
-            }
+        if let Err(err) = output_file.write_all(&body) {
+            report_error(format!("failed to write {:?} matter: {}", matter, err));
+            return 1;
+        }
+
+        if let Err(err) = write!(output_file, "{}", generated) {
+            report_error(format!("failed to write generated back matter: {}", err));
+            return 1;
         }
 
-        if let Err(err) = write!(output_file, "\n\\stop{}\n", matter_tex) {
-            eprintln!("error writing end of {}: {}", matter_tex, err);
+        if let Err(err) = write!(output_file, "{}", ctx.renderer.end_matter(matter)) {
+            report_error(format!("failed to write end of {:?} matter: {}", matter, err));
             return 1;
         }
     }
 
-    if let Err(err) = write!(output_file, "\\stoptext\n") {
-        eprintln!("error writing postamble: {}", err);
+    if let Err(err) = write!(output_file, "{}", ctx.renderer.end_document()) {
+        report_error(format!("failed to write postamble: {}", err));
         return 1;
     }
 
     0
 }
 
-fn do_main() -> i32 {
-    let args: Vec<String> = env::args().collect();
-    let opts: Opts = match Opts::try_parse_from(args) {
-        Ok(o) => o,
+/// Creates one of the files of a ConTeXt project and fills it using `write_body`, passing the
+/// result through the post-processors.
+fn write_project_file<F>(path: &Path, opts: &BuildOpts, encoding: OutputEncoding, ctx: &mut OutputContext, write_body: F) -> i32
+    where F: FnOnce(&mut dyn Write, &mut OutputContext) -> i32
+{
+    let file = match create_output_file(&path.to_string_lossy(), opts.no_clobber, opts.force) {
+        Ok(f) => f,
         Err(err) => {
-            eprint!("{}", err);
+            report_error(err);
+            return 1;
+        },
+    };
+    let (literal_markers, literal_commands) = ctx.renderer.literal_markers();
+    let mut output_writer = EncodingWriter::new(file, encoding, opts.escape_non_latin1, literal_markers, literal_commands);
+    if let Err(err) = write_banner(&mut output_writer, opts) {
+        report_error(format!("failed to write banner to {:?}: {}", path, err));
+        return 1;
+    }
+    let mut body: Vec<u8> = Vec::new();
+    let code = write_body(&mut body, ctx);
+    if code != 0 {
+        return code;
+    }
+    let body = String::from_utf8(body).expect("TeX output is not valid UTF-8");
+    let mut pretty_writer = PrettyWriter::new(&mut output_writer, opts.indent, opts.wrap, ctx.renderer.verbatim_markers());
+    let written = pretty_writer.write_all(texutil::post_process(body, ctx.config).as_bytes())
+        .and_then(|_| pretty_writer.finish());
+    if let Err(err) = written {
+        report_error(format!("failed to write {:?}: {}", path, err));
+        return 1;
+    }
+    0
+}
+
+/// Outputs the book as a ConTeXt project instead of a single file.
+///
+/// The output file becomes the product, which includes one component per top-level section. The
+/// setups go into an environment loaded by the project, so each component can also be compiled
+/// on its own.
+fn output_project(toc: &toc::TableOfContents, encoding: OutputEncoding, opts: &BuildOpts, ctx: &mut OutputContext) -> i32 {
+    let out_path = Path::new(&opts.out_file);
+    let out_dir = out_path.parent().unwrap_or_else(|| Path::new(""));
+    let product = match out_path.file_stem() {
+        Some(stem) => stem.to_string_lossy().into_owned(),
+        None => {
+            report_error(format!("invalid output file name {:?}", opts.out_file));
+            return 1;
+        },
+    };
+    let preamble = ContextRenderer::new(ctx.config).preamble(toc, encoding);
+
+    let code = write_project_file(&out_dir.join("environment.tex"), opts, encoding, ctx, |output_file, _ctx| {
+        match write!(output_file, "\\startenvironment environment\n\n{}\n\\stopenvironment\n", preamble) {
+            Ok(()) => 0,
+            Err(err) => {
+                report_error(format!("failed to write environment: {}", err));
+                1
+            },
+        }
+    });
+    if code != 0 {
+        return code;
+    }
+
+    let code = write_project_file(&out_dir.join("project.tex"), opts, encoding, ctx, |output_file, _ctx| {
+        let project = format!(
+            "\\startproject project\n\\environment environment\n\n\\product {}\n\n\\stopproject\n",
+            product,
+        );
+        match write!(output_file, "{}", project) {
+            Ok(()) => 0,
+            Err(err) => {
+                report_error(format!("failed to write project: {}", err));
+                1
+            },
+        }
+    });
+    if code != 0 {
+        return code;
+    }
+
+    // the components are output first, as whether there is an index is only known afterwards
+    let mut matter_components = Vec::new();
+    let mut component_number = 0;
+    for (matter, sections, numbered) in matter_sections(toc) {
+        let mut components = Vec::new();
+        for section in sections {
+            component_number += 1;
+            let component = format!("component_{:02}", component_number);
+            let component_path = out_dir.join(format!("{}.tex", component));
+            let code = write_project_file(&component_path, opts, encoding, ctx, |output_file, ctx| {
+                let start = format!(
+                    "\\startcomponent {}\n\\project project\n\\product {}\n",
+                    component, product,
+                );
+                if let Err(err) = write!(output_file, "{}", start) {
+                    report_error(format!("failed to write opening of {}: {}", component, err));
+                    return 1;
+                }
+                let code = output_section(output_file, section, numbered, ctx);
+                if code != 0 {
+                    return code;
+                }
+                if let Err(err) = write!(output_file, "\n\\stopcomponent\n") {
+                    report_error(format!("failed to write end of {}: {}", component, err));
+                    return 1;
+                }
+                0
+            });
+            if code != 0 {
+                return code;
+            }
+            components.push(component);
+        }
+        matter_components.push((matter, components));
+    }
+
+    let mut product_body = String::new();
+    for (matter, components) in matter_components {
+        let generated = generated_matter(matter, ctx.renderer);
+        if components.is_empty() && generated.is_empty() {
+            continue;
+        }
+
+        product_body.push_str(&ctx.renderer.begin_matter(matter));
+        for component in components {
+            product_body.push_str(&format!("\\component {}\n", component));
+        }
+        product_body.push_str(&generated);
+        product_body.push_str(&ctx.renderer.end_matter(matter));
+    }
+
+    write_project_file(out_path, opts, encoding, ctx, |output_file, _ctx| {
+        let product_tex = format!(
+            "\\startproduct {}\n\\project project\n\n\\mdcontextplacetoc\n{}\n\\stopproduct\n",
+            product, product_body,
+        );
+        match write!(output_file, "{}", product_tex) {
+            Ok(()) => 0,
+            Err(err) => {
+                report_error(format!("failed to write product: {}", err));
+                1
+            },
+        }
+    })
+}
+
+fn parse_input_encoding(name: &str) -> Result<InputEncoding, String> {
+    InputEncoding::from_name(name)
+        .ok_or_else(|| format!("unknown input encoding {:?}", name))
+}
+
+/// Parses the name of an error format given on the command line.
+fn parse_error_format(name: &str) -> Result<ErrorFormat, String> {
+    ErrorFormat::from_name(name)
+        .ok_or_else(|| format!("unknown error format {:?}", name))
+}
+
+/// Creates the configuration needed to read the book from the command-line options.
+fn make_book_config(opts: &BookOpts) -> Result<TexConfig, String> {
+    let mut config = TexConfig::new();
+    *config.input_encoding_mut() = parse_input_encoding(&opts.input_encoding)?;
+    Ok(config)
+}
+
+/// Creates the configuration of the conversion from the command-line options.
+fn make_config(book_opts: &BookOpts, opts: &ConvertOpts) -> Result<TexConfig, String> {
+    let mut config = make_book_config(book_opts)?;
+    for escape_range in &opts.escape_chars {
+        match texutil::parse_codepoint_range(escape_range) {
+            Ok(r) => config.escaped_chars_mut().push(r),
+            Err(err) => return Err(format!("invalid --escape-chars value: {}", err)),
+        }
+    }
+
+    *config.table_captions_mut() = opts.table_captions;
+    *config.float_tables_mut() = opts.float_tables;
+    *config.float_figures_mut() = opts.float_figures;
+    *config.list_of_figures_mut() = opts.list_of_figures;
+    *config.number_floats_mut() = opts.number_floats;
+    *config.number_code_lines_mut() = opts.number_code_lines;
+    *config.explicit_paragraphs_mut() = opts.explicit_paragraphs;
+    *config.narrower_quotes_mut() = opts.narrower_quotes;
+    *config.profiles_mut() = opts.profile.clone();
+    match opts.float_placement.as_str() {
+        "here" | "top" | "bottom" | "force" | "page" => {
+            *config.float_placement_mut() = opts.float_placement.clone();
+        },
+        other => return Err(format!("unknown float placement {:?}", other)),
+    }
+
+    *config.lenient_mut() = opts.lenient;
+    *config.smart_quotes_mut() = !opts.no_smart_quotes;
+    if let Some(language) = &opts.language {
+        if !texutil::is_language_code(language) {
+            return Err(format!("invalid language code {:?}", language));
+        }
+        *config.language_mut() = Some(language.clone());
+    }
+    if let Some(command_map_path) = &opts.command_map {
+        let command_map = fs::read_to_string(command_map_path)
+            .map_err(|err| format!("failed to read command map {:?}: {}", command_map_path, err))?;
+        if let Err(err) = config.command_names_mut().load_overrides(&command_map) {
+            return Err(format!("invalid command map {:?}: {}", command_map_path, err));
+        }
+    }
+    for mapping in &opts.environment {
+        let eq_index = match mapping.find('=') {
+            Some(i) => i,
+            None => return Err(format!("invalid environment mapping {:?}; expected NAME=ENVIRONMENT", mapping)),
+        };
+        config.environments_mut().insert(
+            mapping[..eq_index].trim().to_owned(),
+            mapping[eq_index+1..].trim().trim_start_matches('\\').to_owned(),
+        );
+    }
+    if !opts.use_environment.is_empty() && opts.backend != "context" {
+        return Err("--use-environment is only supported by the context backend".to_owned());
+    }
+    *config.environment_files_mut() = opts.use_environment.clone();
+    if let Some(heading_commands) = &opts.heading_commands {
+        *config.heading_commands_mut() = heading_commands.split(',')
+            .map(|c| c.trim().trim_start_matches('\\').to_owned())
+            .collect();
+    }
+    *config.table_style_mut() = match TableStyle::from_name(&opts.table_style) {
+        Some(ts) => ts,
+        None => return Err(format!("unknown table style {:?}", opts.table_style)),
+    };
+
+    Ok(config)
+}
+
+/// Returns the number of threads to parse section files with.
+fn job_count(opts: &ConvertOpts) -> Result<usize, String> {
+    match opts.jobs {
+        Some(0) => Err("--jobs must be at least 1".to_owned()),
+        Some(jobs) => Ok(jobs),
+        None => Ok(thread::available_parallelism().map(|n| n.get()).unwrap_or(1)),
+    }
+}
+
+/// Parses all section files of the book in parallel.
+fn preload_sections(toc: &toc::TableOfContents, source_dirs: &[String], jobs: usize, encoding: InputEncoding) -> PreloadedSections {
+    let (files, _missing) = all_section_files(toc, source_dirs);
+    let loaded = md_ast::load_all_with_front_matter(&files, jobs, encoding);
+    files.into_iter().zip(loaded).collect()
+}
+
+fn make_renderer<'a>(backend: &str, config: &'a TexConfig) -> Result<Box<dyn Renderer + 'a>, String> {
+    match backend {
+        "context" => Ok(Box::new(ContextRenderer::new(config))),
+        "latex" => Ok(Box::new(LatexRenderer::new(config))),
+        other => Err(format!("unknown backend {:?}", other)),
+    }
+}
+
+/// Loads the table of contents, keeping only the sections selected by the options, and returns
+/// it along with the directories in which to look for section files.
+fn load_book(opts: &BookOpts, renderer: &dyn Renderer, config: &TexConfig) -> Result<(toc::TableOfContents, Vec<String>), String> {
+    let mut toc = toc::load_toc_with_renderer(&opts.directory, &opts.toc_file, renderer, config)
+        .map_err(|err| format!("failed to load TOC: {}", err))?;
+
+    for tag in &opts.only {
+        for sections in toc.all_sections_mut() {
+            toc::retain_tagged(sections, tag);
+        }
+    }
+    for tag in &opts.exclude {
+        for sections in toc.all_sections_mut() {
+            toc::remove_tagged(sections, tag);
+        }
+    }
+
+    if let Some(section_path) = &opts.section {
+        let mut found = false;
+        for sections in toc.all_sections_mut() {
+            found |= toc::retain_subtree(sections, Path::new(section_path));
+        }
+        if !found {
+            return Err(format!("section {:?} not found in the TOC", section_path));
+        }
+    }
+
+    // sections are looked up in the book directory first, then in each additional source directory
+    let mut source_dirs = vec![opts.directory.clone()];
+    source_dirs.extend(opts.src.iter().cloned());
+
+    Ok((toc, source_dirs))
+}
+
+fn build(opts: &BuildOpts) -> i32 {
+    let error_format = match parse_error_format(&opts.convert.error_format) {
+        Ok(ef) => ef,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    let jobs = match job_count(&opts.convert) {
+        Ok(j) => j,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    let mut config = match make_config(&opts.book, &opts.convert) {
+        Ok(c) => c,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    *config.external_listing_min_lines_mut() = opts.external_listings;
+    *config.copy_assets_mut() = opts.copy_assets;
+    if let Some(parent) = Path::new(&opts.out_file).parent() {
+        *config.output_dir_mut() = parent.to_path_buf();
+    }
+    if opts.project && opts.convert.backend != "context" {
+        report_error("--project is only supported by the context backend");
+        return 1;
+    }
+
+    let output_encoding = match OutputEncoding::from_name(&opts.output_encoding) {
+        Some(oe) => oe,
+        None => {
+            report_error(format!("unknown output encoding {:?}", opts.output_encoding));
+            return 1;
+        },
+    };
+
+    let renderer = match make_renderer(&opts.convert.backend, &config) {
+        Ok(r) => r,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+
+    if opts.single || opts.book.directory == "-" {
+        return convert_single(
+            &opts.book.directory, opts, renderer.as_ref(), &config, output_encoding, error_format,
+        );
+    }
+
+    let (toc, source_dirs) = match load_book(&opts.book, renderer.as_ref(), &config) {
+        Ok(b) => b,
+        Err(err) => {
+            report_error(err);
             return 1;
         },
     };
 
-    let mut output_file = match File::create(&opts.out_file) {
+    if let Some(deps_path) = &opts.deps {
+        let toc_path = match toc::find_toc_file(&opts.book.directory, &opts.book.toc_file) {
+            Ok(tp) => tp,
+            Err(err) => {
+                report_error(format!("failed to find TOC: {}", err));
+                return 1;
+            },
+        };
+        let (files, _missing) = all_section_files(&toc, &source_dirs);
+        if let Err(err) = write_deps(deps_path, &opts.out_file, &toc_path, &files) {
+            report_error(format!("failed to write dependencies to {:?}: {}", deps_path, err));
+            return 1;
+        }
+    }
+
+    let preloaded = preload_sections(&toc, &source_dirs, jobs, *config.input_encoding());
+    let mut ctx = OutputContext {
+        source_dirs: &source_dirs,
+        config: &config,
+        renderer: renderer.as_ref(),
+        keep_going: opts.keep_going,
+        check_images: opts.self_contained,
+        error_format,
+        failures: Vec::new(),
+        link_checker: LinkChecker::new(),
+        preloaded,
+    };
+
+    if opts.project {
+        let code = output_project(&toc, output_encoding, opts, &mut ctx);
+        if code != 0 {
+            return code;
+        }
+        return report_output_problems(&ctx);
+    }
+
+    let output_file = match create_output_file(&opts.out_file, opts.no_clobber, opts.force) {
         Ok(f) => f,
         Err(err) => {
-            eprintln!("failed to open output file {:?}: {:?}", opts.out_file, err);
+            report_error(err);
             return 1;
         },
     };
+    let (literal_markers, literal_commands) = renderer.literal_markers();
+    let mut output_writer = EncodingWriter::new(output_file, output_encoding, opts.escape_non_latin1, literal_markers, literal_commands);
+    if let Err(err) = write_banner(&mut output_writer, opts) {
+        report_error(format!("failed to write banner: {}", err));
+        return 1;
+    }
+    let mut pretty_writer = PrettyWriter::new(&mut output_writer, opts.indent, opts.wrap, renderer.verbatim_markers());
+    let code = output_tex(&mut pretty_writer, &toc, output_encoding, &mut ctx);
+    if code != 0 {
+        return code;
+    }
+    if let Err(err) = pretty_writer.finish() {
+        report_error(format!("failed to write output: {}", err));
+        return 1;
+    }
 
-    let toc = match toc::load_toc(&opts.directory) {
+    report_output_problems(&ctx)
+}
+
+/// Converts every section of the book, discarding the output, and reports all problems found.
+fn check(opts: &CheckOpts) -> i32 {
+    let error_format = match parse_error_format(&opts.convert.error_format) {
+        Ok(ef) => ef,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    let jobs = match job_count(&opts.convert) {
+        Ok(j) => j,
         Err(err) => {
-            eprintln!("failed to load TOC: {}", err);
+            report_error(err);
+            return 1;
+        },
+    };
+    let config = match make_config(&opts.book, &opts.convert) {
+        Ok(c) => c,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    let renderer = match make_renderer(&opts.convert.backend, &config) {
+        Ok(r) => r,
+        Err(err) => {
+            report_error(err);
+            return 1;
+        },
+    };
+    let (toc, source_dirs) = match load_book(&opts.book, renderer.as_ref(), &config) {
+        Ok(b) => b,
+        Err(err) => {
+            report_error(err);
             return 1;
         },
-        Ok(t) => t,
     };
 
-    output_tex(&mut output_file, &toc, &opts.directory)
+    // a check should report every failing section, not only the first one
+    let preloaded = preload_sections(&toc, &source_dirs, jobs, *config.input_encoding());
+    let mut ctx = OutputContext {
+        source_dirs: &source_dirs,
+        config: &config,
+        renderer: renderer.as_ref(),
+        keep_going: true,
+        check_images: true,
+        error_format,
+        failures: Vec::new(),
+        link_checker: LinkChecker::new(),
+        preloaded,
+    };
+    let code = output_tex(&mut io::sink(), &toc, OutputEncoding::Utf8, &mut ctx);
+    if code != 0 {
+        return code;
+    }
+
+    report_output_problems(&ctx)
+}
+
+/// Returns the files whose modification triggers a rebuild in watch mode.
+fn watched_files(opts: &BuildOpts) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    if let Some(command_map_path) = &opts.convert.command_map {
+        files.push(PathBuf::from(command_map_path));
+    }
+    if opts.single {
+        files.push(PathBuf::from(&opts.book.directory));
+        return Ok(files);
+    }
+
+    let toc_path = toc::find_toc_file(&opts.book.directory, &opts.book.toc_file)
+        .map_err(|err| format!("failed to find TOC: {}", err))?;
+    files.push(toc_path);
+    // the ToC is loaded again so that changes to it are picked up
+    let config = make_book_config(&opts.book)?;
+    if let Ok((toc, source_dirs)) = load_book(&opts.book, &ContextRenderer::new(&config), &config) {
+        let (mut section_files, _missing) = all_section_files(&toc, &source_dirs);
+        files.append(&mut section_files);
+    }
+    Ok(files)
+}
+
+fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files.iter()
+        .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Builds the book again whenever one of its files changes. Only returns on error.
+fn watch(opts: &WatchOpts) -> i32 {
+    if opts.build.book.directory == "-" {
+        report_error("cannot watch standard input");
+        return 1;
+    }
+
+    loop {
+        if build(&opts.build) == 0 {
+            eprintln!("wrote {}", opts.build.out_file);
+        }
+
+        let files = match watched_files(&opts.build) {
+            Ok(f) => f,
+            Err(err) => {
+                report_error(err);
+                return 1;
+            },
+        };
+        let times = modification_times(&files);
+        eprintln!("watching {} file(s) for changes", files.len());
+        while modification_times(&files) == times {
+            thread::sleep(Duration::from_millis(opts.interval));
+        }
+    }
+}
+
+fn do_main() -> i32 {
+    let mut args: Vec<String> = env::args().collect();
+
+    // without a subcommand, the arguments are those of build, as before there were subcommands
+    let has_subcommand = match args.get(1) {
+        Some(first) => SUBCOMMAND_NAMES.contains(&first.as_str())
+            || ["help", "-h", "--help", "-V", "--version"].contains(&first.as_str()),
+        None => false,
+    };
+    if !has_subcommand {
+        args.insert(1, "build".to_owned());
+    }
+
+    let opts: Opts = match Opts::try_parse_from(args) {
+        Ok(o) => o,
+        Err(err) => {
+            if err.kind == ErrorKind::VersionDisplayed || err.kind == ErrorKind::HelpDisplayed {
+                print!("{}", err);
+                return 0;
+            }
+            eprint!("{}", err);
+            return 1;
+        },
+    };
+
+    match &opts.command {
+        Command::Build(build_opts) => build(build_opts),
+        Command::Check(check_opts) => check(check_opts),
+        Command::Stats(book_opts) => match make_book_config(book_opts).and_then(|config| {
+            let (toc, source_dirs) = load_book(book_opts, &ContextRenderer::new(&config), &config)?;
+            Ok((toc, source_dirs, *config.input_encoding()))
+        }) {
+            Ok((toc, source_dirs, encoding)) => print_stats(&toc, &source_dirs, encoding),
+            Err(err) => {
+                report_error(err);
+                1
+            },
+        },
+        Command::DumpAst(dump_ast_opts) => match parse_input_encoding(&dump_ast_opts.input_encoding) {
+            Ok(encoding) => dump_ast(Path::new(&dump_ast_opts.file), encoding),
+            Err(err) => {
+                report_error(err);
+                1
+            },
+        },
+        Command::DumpToc(dump_toc_opts) => match make_book_config(&dump_toc_opts.book).and_then(|config| load_book(&dump_toc_opts.book, &ContextRenderer::new(&config), &config)) {
+            Ok((toc, source_dirs)) => if dump_toc_opts.list_files {
+                list_files(&toc, &source_dirs)
+            } else {
+                dump_toc(&toc)
+            },
+            Err(err) => {
+                report_error(err);
+                1
+            },
+        },
+        Command::Watch(watch_opts) => watch(watch_opts),
+    }
 }
 
 fn main() {
@@ -1,14 +1,25 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::fs::File;
 use std::io::Read;
 use std::iter::{FromIterator, IntoIterator};
-use std::path::Path;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
-use pulldown_cmark::{Alignment, Event, Parser, Tag};
+use pulldown_cmark::{Alignment, CodeBlockKind, Event, OffsetIter, Parser, Tag};
+
+use crate::encoding::InputEncoding;
+use crate::preprocess;
+
+pub use crate::md_writer::to_markdown;
 
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MarkdownFragment {
     elements: Vec<MarkdownElement>,
 }
@@ -21,7 +32,29 @@ impl MarkdownFragment {
     accessor_and_mut!(elements, elements_mut, Vec<MarkdownElement>);
 }
 
+/// Key-value pairs from a YAML front matter block at the beginning of a Markdown file.
+///
+/// Only simple `key: value` lines are understood; nested structures are ignored.
+#[derive(Debug, Default)]
+pub struct FrontMatter {
+    entries: BTreeMap<String, String>,
+}
+impl FrontMatter {
+    pub fn new() -> FrontMatter {
+        FrontMatter {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    accessor_and_mut!(entries, entries_mut, BTreeMap<String, String>);
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|v| v.as_str())
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum MarkdownFormat {
     Emphasis,
     Strong,
@@ -29,6 +62,7 @@ pub enum MarkdownFormat {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MarkdownTable {
     alignments: Vec<char>,
     header_rows: Vec<Vec<MarkdownFragment>>,
@@ -52,31 +86,268 @@ impl MarkdownTable {
     accessor_and_mut!(body_rows, body_rows_mut, Vec<Vec<MarkdownFragment>>);
 }
 
+/// Pandoc-style attributes in braces, e.g. `{#overview .wide width=50%}`.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Attributes {
+    id: Option<String>,
+    classes: Vec<String>,
+    values: Vec<(String, String)>,
+}
+impl Attributes {
+    pub fn new() -> Attributes {
+        Attributes::default()
+    }
+
+    /// Parses attributes in braces at the beginning of the text, returning them along with the
+    /// rest of the text. Returns `None` if the text does not start with a valid attribute list.
+    pub fn parse_prefix(text: &str) -> Option<(Attributes, &str)> {
+        let is_name_char = |c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == ':';
+
+        let mut attrs = Attributes::new();
+        let mut rest = text.strip_prefix('{')?;
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('}') {
+                return Some((attrs, after));
+            }
+
+            let (sigil, after_sigil) = match rest.chars().next()? {
+                c @ '#' | c @ '.' => (Some(c), &rest[1..]),
+                _ => (None, rest),
+            };
+            let name_end = after_sigil.find(|c: char| !is_name_char(c)).unwrap_or(after_sigil.len());
+            if name_end == 0 {
+                return None;
+            }
+            let name = &after_sigil[..name_end];
+            rest = &after_sigil[name_end..];
+
+            match sigil {
+                Some('#') => attrs.id = Some(name.to_owned()),
+                Some(_) => attrs.classes.push(name.to_owned()),
+                None => {
+                    rest = rest.strip_prefix('=')?;
+                    let value = if let Some(quoted) = rest.strip_prefix('"') {
+                        let end = quoted.find('"')?;
+                        rest = &quoted[end+1..];
+                        &quoted[..end]
+                    } else {
+                        let end = rest.find(|c: char| c.is_whitespace() || c == '}').unwrap_or(rest.len());
+                        let value = &rest[..end];
+                        rest = &rest[end..];
+                        value
+                    };
+                    attrs.values.push((name.to_owned(), value.to_owned()));
+                },
+            }
+        }
+    }
+
+    accessor_opt!(id, str);
+    accessor!(classes, Vec<String>);
+    accessor_and_mut!(values, values_mut, Vec<(String, String)>);
+
+    /// Returns the value of the last attribute with the given key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter().rev()
+            .find(|(k, _v)| k == key)
+            .map(|(_k, v)| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.id.is_none() && self.classes.is_empty() && self.values.is_empty()
+    }
+}
+
+/// The information in the info string of a fenced code block.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CodeInfo {
+    language: Option<String>,
+    attributes: Vec<String>,
+}
+impl CodeInfo {
+    pub fn new(language: Option<String>, attributes: Vec<String>) -> CodeInfo {
+        CodeInfo {
+            language,
+            attributes,
+        }
+    }
+
+    /// Parses an info string. The language is the first token, delimited by whitespace or a comma
+    /// (as in `rust,ignore`); the remaining tokens are attributes. Pandoc-style attributes in
+    /// braces are also understood, e.g. `python {.numberLines}` or `{.python .numberLines}`, in
+    /// which case the first class names the language if none precedes the braces.
+    pub fn parse(info: &str) -> CodeInfo {
+        let info = info.trim();
+        let (head, braced) = match info.find('{') {
+            Some(brace_index) if info.ends_with('}') => (&info[..brace_index], Some(&info[brace_index+1..info.len()-1])),
+            _ => (info, None),
+        };
+
+        let mut tokens = head.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|t| !t.is_empty());
+        let mut language = tokens.next().map(|t| t.to_owned());
+        let mut attributes: Vec<String> = tokens.map(|t| t.to_owned()).collect();
+
+        if let Some(braced_attrs) = braced {
+            for token in braced_attrs.split_whitespace() {
+                if language.is_none() && token.starts_with('.') && token.len() > 1 {
+                    language = Some(token[1..].to_owned());
+                } else {
+                    attributes.push(token.to_owned());
+                }
+            }
+        }
+
+        CodeInfo::new(language, attributes)
+    }
+
+    accessor_opt!(language, str);
+    accessor!(attributes, Vec<String>);
+
+    /// Whether the attributes ask for numbered lines, using either `.line-numbers` or Pandoc's
+    /// `.numberLines`.
+    pub fn numbers_lines(&self) -> bool {
+        self.attributes.iter()
+            .map(|a| a.trim_start_matches('.'))
+            .any(|a| a == "line-numbers" || a == "numberLines")
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum MarkdownElement {
     Text(String),
     Heading(u32, MarkdownFragment),
     Paragraph(MarkdownFragment),
-    List(Vec<MarkdownFragment>),
+    /// A list; the number is the start value of an ordered list, or `None` for an unordered one.
+    List(Option<u64>, Vec<MarkdownFragment>),
     Link(String, MarkdownFragment),
-    Image(String, MarkdownFragment),
+    /// An image with its URL, its alternative text and the attributes in braces following it.
+    Image(String, MarkdownFragment, Attributes),
     Code(String),
     BlockQuote(MarkdownFragment),
-    CodeBlock(MarkdownFragment),
+    CodeBlock(CodeInfo, MarkdownFragment),
     Formatting(MarkdownFormat, MarkdownFragment),
     Table(MarkdownTable),
     HtmlFragment(String),
     FootnoteRef(String),
+    /// A thematic break (`---`, `***` or `___` on a line of its own).
+    Rule,
+    /// A fenced div (`::: name`) wrapping its contents in a named environment.
+    Div(String, MarkdownFragment),
+    /// A construct that is not supported, with its description and, if known, its byte range in
+    /// the source. Its contents are dropped.
+    Unsupported(String, Option<Range<usize>>),
+}
+impl MarkdownElement {
+    /// Whether the element is a block, as opposed to inline content within a paragraph.
+    pub fn is_block(&self) -> bool {
+        matches!(
+            self,
+            MarkdownElement::BlockQuote(_) | MarkdownElement::CodeBlock(_, _)
+                | MarkdownElement::Div(_, _) | MarkdownElement::Heading(_, _)
+                | MarkdownElement::List(_, _) | MarkdownElement::Paragraph(_)
+                | MarkdownElement::Rule | MarkdownElement::Table(_)
+        )
+    }
+
+    /// A short, human-readable name of the kind of element.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            MarkdownElement::Text(_) => "text",
+            MarkdownElement::Heading(_, _) => "heading",
+            MarkdownElement::Paragraph(_) => "paragraph",
+            MarkdownElement::List(_, _) => "list",
+            MarkdownElement::Link(_, _) => "link",
+            MarkdownElement::Image(_, _, _) => "image",
+            MarkdownElement::Code(_) => "inline code",
+            MarkdownElement::BlockQuote(_) => "block quote",
+            MarkdownElement::CodeBlock(_, _) => "code block",
+            MarkdownElement::Formatting(_, _) => "formatting",
+            MarkdownElement::Table(_) => "table",
+            MarkdownElement::HtmlFragment(_) => "HTML",
+            MarkdownElement::FootnoteRef(_) => "footnote reference",
+            MarkdownElement::Rule => "thematic break",
+            MarkdownElement::Div(_, _) => "fenced div",
+            MarkdownElement::Unsupported(_, _) => "unsupported construct",
+        }
+    }
+}
+impl Display for MarkdownElement {
+    /// Outputs the kind of element with a short excerpt of simple contents (without nested
+    /// fragments), to keep error messages readable.
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), FmtError> {
+        let detail = match self {
+            MarkdownElement::Text(s) | MarkdownElement::Code(s) | MarkdownElement::HtmlFragment(s)
+                | MarkdownElement::Unsupported(s, _) => Some(s.as_str()),
+            MarkdownElement::Link(url, _) | MarkdownElement::Image(url, _, _) => Some(url.as_str()),
+            MarkdownElement::FootnoteRef(name) | MarkdownElement::Div(name, _) => Some(name.as_str()),
+            MarkdownElement::Heading(_, _) | MarkdownElement::Paragraph(_) | MarkdownElement::List(_, _)
+                | MarkdownElement::BlockQuote(_) | MarkdownElement::CodeBlock(_, _)
+                | MarkdownElement::Formatting(_, _) | MarkdownElement::Table(_)
+                | MarkdownElement::Rule => None,
+        };
+        match detail {
+            Some(d) if d.chars().count() > 40 => {
+                let excerpt: String = d.chars().take(40).collect();
+                write!(formatter, "{} {:?}...", self.kind_name(), excerpt)
+            },
+            Some(d) => write!(formatter, "{} {:?}", self.kind_name(), d),
+            None => write!(formatter, "{}", self.kind_name()),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ASTError {
     message: String,
+    file: Option<PathBuf>,
+    offset: Option<usize>,
 }
 impl ASTError {
     pub fn new<M: AsRef<str>>(message: M) -> ASTError {
         ASTError {
             message: message.as_ref().to_owned(),
+            file: None,
+            offset: None,
+        }
+    }
+
+    accessor!(message, str);
+    accessor_opt!(file, Path);
+
+    /// The byte offset at which the error was detected. For errors from `parse_str` and the
+    /// loading functions, this is an offset in the whole file, front matter included; errors in
+    /// included text point at the include directive. For errors from `parse_with_offsets`, it is
+    /// an offset in the parsed text.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Sets the offset of the error unless it is already known.
+    pub fn at_offset(mut self, offset: usize) -> ASTError {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+
+    /// Replaces the offset of the error, e.g. with that of the include directive when the error
+    /// occurred in the included file.
+    pub(crate) fn relocated(mut self, offset: usize) -> ASTError {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Prefixes the message with the file in which the error occurred and records the file.
+    pub fn in_file(self, path: &Path) -> ASTError {
+        ASTError {
+            message: format!("failed to parse Markdown file {:?}: {}", path, self.message),
+            file: Some(path.to_path_buf()),
+            offset: self.offset,
         }
     }
 }
@@ -88,7 +359,132 @@ impl Display for ASTError {
 impl Error for ASTError {}
 
 
-fn parse_table_row<'a>(mut parser: &mut Parser<'a>) -> Result<Vec<MarkdownFragment>, ASTError> {
+/// Receives the elements of a Markdown fragment as it is traversed by `walk`.
+///
+/// All methods do nothing by default, so implementations only need to handle the elements they
+/// are interested in. Child fragments are walked after their parent has been visited.
+pub trait MarkdownVisitor {
+    fn visit_text(&mut self, _text: &str) {}
+    fn visit_heading(&mut self, _level: u32, _content: &MarkdownFragment) {}
+    fn visit_paragraph(&mut self, _content: &MarkdownFragment) {}
+    fn visit_list(&mut self, _start: Option<u64>, _items: &[MarkdownFragment]) {}
+    fn visit_list_item(&mut self, _content: &MarkdownFragment) {}
+    fn visit_link(&mut self, _url: &str, _content: &MarkdownFragment) {}
+    fn visit_image(&mut self, _url: &str, _alt_text: &MarkdownFragment) {}
+    fn visit_code(&mut self, _code: &str) {}
+    fn visit_block_quote(&mut self, _content: &MarkdownFragment) {}
+    /// Called for code blocks. Their contents are not walked.
+    fn visit_code_block(&mut self, _language: Option<&str>, _content: &MarkdownFragment) {}
+    fn visit_formatting(&mut self, _format: &MarkdownFormat, _content: &MarkdownFragment) {}
+    fn visit_table(&mut self, _table: &MarkdownTable) {}
+    fn visit_table_cell(&mut self, _content: &MarkdownFragment) {}
+    fn visit_html(&mut self, _html: &str) {}
+    fn visit_footnote_ref(&mut self, _name: &str) {}
+    fn visit_rule(&mut self) {}
+    fn visit_div(&mut self, _name: &str, _content: &MarkdownFragment) {}
+    fn visit_unsupported(&mut self, _description: &str) {}
+}
+
+/// Traverses a Markdown fragment depth-first, passing each element to the visitor.
+pub fn walk<V: MarkdownVisitor + ?Sized>(frag: &MarkdownFragment, visitor: &mut V) {
+    for elem in frag.elements() {
+        match elem {
+            MarkdownElement::Text(text) => visitor.visit_text(text),
+            MarkdownElement::Heading(level, content) => {
+                visitor.visit_heading(*level, content);
+                walk(content, visitor);
+            },
+            MarkdownElement::Paragraph(content) => {
+                visitor.visit_paragraph(content);
+                walk(content, visitor);
+            },
+            MarkdownElement::List(start, items) => {
+                visitor.visit_list(*start, items);
+                for item in items {
+                    visitor.visit_list_item(item);
+                    walk(item, visitor);
+                }
+            },
+            MarkdownElement::Link(url, content) => {
+                visitor.visit_link(url, content);
+                walk(content, visitor);
+            },
+            MarkdownElement::Image(url, alt_text, _) => {
+                visitor.visit_image(url, alt_text);
+                walk(alt_text, visitor);
+            },
+            MarkdownElement::Code(code) => visitor.visit_code(code),
+            MarkdownElement::BlockQuote(content) => {
+                visitor.visit_block_quote(content);
+                walk(content, visitor);
+            },
+            MarkdownElement::CodeBlock(info, content) => {
+                visitor.visit_code_block(info.language(), content);
+            },
+            MarkdownElement::Formatting(format, content) => {
+                visitor.visit_formatting(format, content);
+                walk(content, visitor);
+            },
+            MarkdownElement::Table(table) => {
+                visitor.visit_table(table);
+                for row in table.header_rows().iter().chain(table.body_rows()) {
+                    for cell in row {
+                        visitor.visit_table_cell(cell);
+                        walk(cell, visitor);
+                    }
+                }
+            },
+            MarkdownElement::HtmlFragment(html) => visitor.visit_html(html),
+            MarkdownElement::FootnoteRef(name) => visitor.visit_footnote_ref(name),
+            MarkdownElement::Rule => visitor.visit_rule(),
+            MarkdownElement::Div(name, content) => {
+                visitor.visit_div(name, content);
+                walk(content, visitor);
+            },
+            MarkdownElement::Unsupported(description, _) => visitor.visit_unsupported(description),
+        }
+    }
+}
+
+
+/// Describes a parser event in error messages.
+fn describe_event(event: &Event) -> String {
+    fn describe_tag(tag: &Tag) -> String {
+        match tag {
+            Tag::Table(_) => "a table".to_owned(),
+            Tag::TableHead => "a table header".to_owned(),
+            Tag::TableRow => "a table row".to_owned(),
+            Tag::TableCell => "a table cell".to_owned(),
+            Tag::Paragraph => "a paragraph".to_owned(),
+            Tag::Heading(level) => format!("a level {} heading", level),
+            Tag::List(_) => "a list".to_owned(),
+            Tag::Item => "a list item".to_owned(),
+            Tag::BlockQuote => "a block quote".to_owned(),
+            Tag::CodeBlock(_) => "a code block".to_owned(),
+            other => format!("{:?}", other),
+        }
+    }
+
+    let excerpt = |text: &str| -> String {
+        if text.chars().count() > 40 {
+            format!("{:?}...", text.chars().take(40).collect::<String>())
+        } else {
+            format!("{:?}", text)
+        }
+    };
+
+    match event {
+        Event::Start(tag) => format!("start of {}", describe_tag(tag)),
+        Event::End(tag) => format!("end of {}", describe_tag(tag)),
+        Event::Text(text) => format!("text {}", excerpt(text)),
+        Event::Code(code) => format!("inline code {}", excerpt(code)),
+        Event::Html(html) => format!("HTML {}", excerpt(html)),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Parses the cells of a table row; `row_name` and `column_count` describe the row in errors.
+fn parse_table_row<'a, I: EventSource<'a>>(parser: &mut I, row_name: &str, column_count: usize) -> Result<Vec<MarkdownFragment>, ASTError> {
     let mut vals = Vec::new();
     while let Some(event) = parser.next() {
         match event {
@@ -96,18 +492,21 @@ fn parse_table_row<'a>(mut parser: &mut Parser<'a>) -> Result<Vec<MarkdownFragme
                 break;
             },
             Event::Start(Tag::TableCell) => {
-                let val = parse_until_end_event(&mut parser)?;
+                let val = parse_until_end_event(parser)?;
                 vals.push(val);
             },
             _ => {
-                return Err(ASTError::new(format!("unhandled table row parser event {:?}", event)));
+                return Err(ASTError::new(format!(
+                    "unexpected {} in {} (after {} cell(s)) of a table with {} column(s); a table row may only contain cells, and tables cannot be nested",
+                    describe_event(&event), row_name, vals.len(), column_count,
+                )));
             },
         }
     }
     Ok(vals)
 }
 
-fn parse_table<'a>(mut parser: &mut Parser<'a>, align_chars: Vec<char>) -> Result<MarkdownTable, ASTError> {
+fn parse_table<'a, I: EventSource<'a>>(parser: &mut I, align_chars: Vec<char>) -> Result<MarkdownTable, ASTError> {
     let mut header_rows = Vec::new();
     let mut body_rows = Vec::new();
     while let Some(event) = parser.next() {
@@ -116,15 +515,19 @@ fn parse_table<'a>(mut parser: &mut Parser<'a>, align_chars: Vec<char>) -> Resul
                 break;
             },
             Event::Start(Tag::TableHead) => {
-                let row = parse_table_row(&mut parser)?;
+                let row = parse_table_row(parser, "the header row", align_chars.len())?;
                 header_rows.push(row);
             },
             Event::Start(Tag::TableRow) => {
-                let row = parse_table_row(&mut parser)?;
+                let row_name = format!("body row {}", body_rows.len() + 1);
+                let row = parse_table_row(parser, &row_name, align_chars.len())?;
                 body_rows.push(row);
             },
             _ => {
-                return Err(ASTError::new(format!("unhandled table parser event {:?}", event)));
+                return Err(ASTError::new(format!(
+                    "unexpected {} between the rows of a table with {} column(s)",
+                    describe_event(&event), align_chars.len(),
+                )));
             },
         }
     }
@@ -132,7 +535,7 @@ fn parse_table<'a>(mut parser: &mut Parser<'a>, align_chars: Vec<char>) -> Resul
 }
 
 
-fn parse_until_end_event<'a>(mut parser: &mut Parser<'a>) -> Result<MarkdownFragment, ASTError> {
+fn parse_until_end_event<'a, I: EventSource<'a>>(parser: &mut I) -> Result<MarkdownFragment, ASTError> {
     let mut elements = Vec::new();
     while let Some(event) = parser.next() {
         match event {
@@ -149,24 +552,28 @@ fn parse_until_end_event<'a>(mut parser: &mut Parser<'a>) -> Result<MarkdownFrag
                 elements.push(MarkdownElement::Text("\n".to_owned()));
             },
             Event::Start(Tag::Paragraph) => {
-                let subfrag = parse_until_end_event(&mut parser)?;
+                let subfrag = parse_until_end_event(parser)?;
                 elements.push(MarkdownElement::Paragraph(subfrag));
             },
             Event::Start(Tag::Heading(level)) => {
-                let subfrag = parse_until_end_event(&mut parser)?;
+                let subfrag = parse_until_end_event(parser)?;
                 elements.push(MarkdownElement::Heading(level, subfrag));
             },
-            Event::Start(Tag::List(_)) => {
-                let items = parse_list_items(&mut parser)?;
-                elements.push(MarkdownElement::List(items));
+            Event::Start(Tag::List(start)) => {
+                let items = parse_list_items(parser)?;
+                elements.push(MarkdownElement::List(start, items));
             },
             Event::Start(Tag::BlockQuote) => {
-                let subfrag = parse_until_end_event(&mut parser)?;
+                let subfrag = parse_until_end_event(parser)?;
                 elements.push(MarkdownElement::BlockQuote(subfrag));
             },
-            Event::Start(Tag::CodeBlock(_)) => {
-                let subfrag = parse_until_end_event(&mut parser)?;
-                elements.push(MarkdownElement::CodeBlock(subfrag));
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let info = match kind {
+                    CodeBlockKind::Fenced(info) => CodeInfo::parse(&info),
+                    CodeBlockKind::Indented => CodeInfo::new(None, Vec::new()),
+                };
+                let subfrag = parse_until_end_event(parser)?;
+                elements.push(MarkdownElement::CodeBlock(info, subfrag));
             },
             Event::Start(Tag::Emphasis) | Event::Start(Tag::Strong) | Event::Start(Tag::Strikethrough) => {
                 let format: MarkdownFormat = match event {
@@ -175,18 +582,18 @@ fn parse_until_end_event<'a>(mut parser: &mut Parser<'a>) -> Result<MarkdownFrag
                     Event::Start(Tag::Strikethrough) => MarkdownFormat::Strikethrough,
                     _ => return Err(ASTError::new(format!("incorrectly handled formatting tag {:?}", event))),
                 };
-                let subfrag = parse_until_end_event(&mut parser)?;
+                let subfrag = parse_until_end_event(parser)?;
                 elements.push(MarkdownElement::Formatting(format, subfrag));
             },
             Event::Start(Tag::Link(link_type, dest, title)) => {
                 // FIXME: don't ignore the title
-                let subfrag = parse_until_end_event(&mut parser)?;
+                let subfrag = parse_until_end_event(parser)?;
                 elements.push(MarkdownElement::Link(dest.as_ref().to_owned(), subfrag));
             },
             Event::Start(Tag::Image(link_type, dest, title)) => {
                 // FIXME: don't ignore the title
-                let subfrag = parse_until_end_event(&mut parser)?;
-                elements.push(MarkdownElement::Image(dest.as_ref().to_owned(), subfrag));
+                let subfrag = parse_until_end_event(parser)?;
+                elements.push(MarkdownElement::Image(dest.as_ref().to_owned(), subfrag, Attributes::new()));
             },
             Event::Start(Tag::Table(alignments)) => {
                 let align_chars: Vec<char> = alignments.iter().map(|al| match al {
@@ -195,24 +602,69 @@ fn parse_until_end_event<'a>(mut parser: &mut Parser<'a>) -> Result<MarkdownFrag
                     Alignment::Center => 'c',
                     Alignment::Right => 'r',
                 }).collect();
-                let table = parse_table(&mut parser, align_chars)?;
+                let table = parse_table(parser, align_chars)?;
                 elements.push(MarkdownElement::Table(table));
             },
             Event::Html(html) => {
+                // the parser passes on a comment spanning several lines in pieces; keep it whole
+                if let Some(MarkdownElement::HtmlFragment(prev)) = elements.last_mut() {
+                    if ends_in_open_comment(prev) {
+                        prev.push_str(&html);
+                        continue;
+                    }
+                }
                 elements.push(MarkdownElement::HtmlFragment(html.as_ref().to_owned()));
             },
             Event::FootnoteReference(fn_name) => {
                 elements.push(MarkdownElement::FootnoteRef(fn_name.as_ref().to_owned()));
             },
-            _ => {
-                return Err(ASTError::new(format!("unhandled parser event {:?}", event)));
+            Event::Rule => {
+                elements.push(MarkdownElement::Rule);
+            },
+            Event::Start(tag) => {
+                // skip the whole construct; whether this is an error is decided when rendering
+                let range = parser.last_range();
+                parse_until_end_event(parser)?;
+                elements.push(MarkdownElement::Unsupported(format!("{:?}", tag), range));
             },
+            other => {
+                elements.push(MarkdownElement::Unsupported(format!("{:?}", other), parser.last_range()));
+            },
+        }
+    }
+    Ok(MarkdownFragment::new(attach_image_attributes(elements)))
+}
+
+/// Whether the HTML contains the start of a comment that it does not end.
+fn ends_in_open_comment(html: &str) -> bool {
+    match html.rfind("<!--") {
+        Some(start) => !html[start+"<!--".len()..].contains("-->"),
+        None => false,
+    }
+}
+
+/// Moves attributes in braces directly following an image (`![alt](url){width=50%}`), which the
+/// parser passes on as text, into the image.
+fn attach_image_attributes(elements: Vec<MarkdownElement>) -> Vec<MarkdownElement> {
+    let mut ret: Vec<MarkdownElement> = Vec::with_capacity(elements.len());
+    for elem in elements {
+        if let (MarkdownElement::Text(text), Some(MarkdownElement::Image(_, _, attrs))) = (&elem, ret.last_mut()) {
+            if attrs.is_empty() {
+                if let Some((parsed, rest)) = Attributes::parse_prefix(text) {
+                    *attrs = parsed;
+                    if !rest.is_empty() {
+                        ret.push(MarkdownElement::Text(rest.to_owned()));
+                    }
+                    continue;
+                }
+            }
         }
+        ret.push(elem);
     }
-    Ok(MarkdownFragment::new(elements))
+    ret
 }
 
-fn parse_list_items<'a>(mut parser: &mut Parser<'a>) -> Result<Vec<MarkdownFragment>, ASTError> {
+fn parse_list_items<'a, I: EventSource<'a>>(parser: &mut I) -> Result<Vec<MarkdownFragment>, ASTError> {
     let mut items: Vec<MarkdownFragment> = Vec::new();
     while let Some(event) = parser.next() {
         match event {
@@ -220,7 +672,7 @@ fn parse_list_items<'a>(mut parser: &mut Parser<'a>) -> Result<Vec<MarkdownFragm
                 break;
             },
             Event::Start(Tag::Item) => {
-                let item_frag = parse_until_end_event(&mut parser)?;
+                let item_frag = parse_until_end_event(parser)?;
                 items.push(item_frag);
             },
             evt => {
@@ -231,19 +683,263 @@ fn parse_list_items<'a>(mut parser: &mut Parser<'a>) -> Result<Vec<MarkdownFragm
     Ok(items)
 }
 
-pub fn parse<'a>(mut parser: &mut Parser<'a>) -> Result<MarkdownFragment, ASTError> {
+/// Gathers the elements between `<!-- div: name -->` and `<!-- /div -->` markers (as output by
+/// `preprocess::mark_fenced_divs`) into `MarkdownElement::Div`s.
+fn group_divs(elements: Vec<MarkdownElement>) -> Result<Vec<MarkdownElement>, ASTError> {
+    // the bottom of the stack collects the top-level elements
+    let mut stack: Vec<(Option<String>, Vec<MarkdownElement>)> = vec![(None, Vec::new())];
+    for elem in elements {
+        if let MarkdownElement::HtmlFragment(html) = &elem {
+            let marker = html.trim();
+            if let Some(name) = marker.strip_prefix("<!-- div: ").and_then(|m| m.strip_suffix(" -->")) {
+                stack.push((Some(name.to_owned()), Vec::new()));
+                continue;
+            }
+            if marker == "<!-- /div -->" {
+                if stack.len() < 2 {
+                    return Err(ASTError::new("div end marker without a start marker"));
+                }
+                let (name, children) = stack.pop().unwrap();
+                let div = MarkdownElement::Div(name.unwrap(), MarkdownFragment::new(children));
+                stack.last_mut().unwrap().1.push(div);
+                continue;
+            }
+        }
+        stack.last_mut().unwrap().1.push(elem);
+    }
+    if stack.len() > 1 {
+        return Err(ASTError::new(format!("div {:?} is never closed", stack.last().unwrap().0.as_ref().unwrap())));
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+/// If the HTML is an `<!-- if name -->` marker, returns the name.
+fn conditional_start(html: &str) -> Option<&str> {
+    let content = html.trim().strip_prefix("<!--")?.strip_suffix("-->")?.trim();
+    let name = content.strip_prefix("if ")?.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(name)
+}
+
+fn is_conditional_end(html: &str) -> bool {
+    let content = html.trim().strip_prefix("<!--").and_then(|c| c.strip_suffix("-->"));
+    content.map(|c| c.trim() == "endif").unwrap_or(false)
+}
+
+fn apply_conditionals_to_elements(elements: Vec<MarkdownElement>, profiles: &[String]) -> Result<Vec<MarkdownElement>, ASTError> {
+    let filter = |frag: MarkdownFragment| apply_conditionals(frag, profiles);
+
+    let mut ret = Vec::with_capacity(elements.len());
+    // the names of the enclosing conditions and whether each of them holds
+    let mut conditions: Vec<(String, bool)> = Vec::new();
+    for elem in elements {
+        if let MarkdownElement::HtmlFragment(html) = &elem {
+            if let Some(name) = conditional_start(html) {
+                conditions.push((name.to_owned(), profiles.iter().any(|p| p == name)));
+                continue;
+            }
+            if is_conditional_end(html) {
+                if conditions.pop().is_none() {
+                    return Err(ASTError::new("<!-- endif --> without a matching <!-- if ... -->"));
+                }
+                continue;
+            }
+        }
+        if !conditions.iter().all(|(_name, holds)| *holds) {
+            continue;
+        }
+
+        let filtered = match elem {
+            MarkdownElement::Heading(level, content) => MarkdownElement::Heading(level, filter(content)?),
+            MarkdownElement::Paragraph(content) => MarkdownElement::Paragraph(filter(content)?),
+            MarkdownElement::List(start, items) => MarkdownElement::List(
+                start,
+                items.into_iter().map(filter).collect::<Result<Vec<_>, _>>()?,
+            ),
+            MarkdownElement::Link(url, content) => MarkdownElement::Link(url, filter(content)?),
+            MarkdownElement::BlockQuote(content) => MarkdownElement::BlockQuote(filter(content)?),
+            MarkdownElement::Formatting(format, content) => MarkdownElement::Formatting(format, filter(content)?),
+            MarkdownElement::Table(mut table) => {
+                for row in table.header_rows.iter_mut().chain(table.body_rows.iter_mut()) {
+                    let cells = std::mem::take(row);
+                    *row = cells.into_iter().map(filter).collect::<Result<Vec<_>, _>>()?;
+                }
+                MarkdownElement::Table(table)
+            },
+            MarkdownElement::Div(name, content) => MarkdownElement::Div(name, filter(content)?),
+            // images, code and raw HTML have no Markdown content that could contain markers
+            other => other,
+        };
+        ret.push(filtered);
+    }
+    if let Some((name, _holds)) = conditions.last() {
+        return Err(ASTError::new(format!("<!-- if {} --> is never closed by <!-- endif -->", name)));
+    }
+    Ok(ret)
+}
+
+/// Keeps the content between `<!-- if name -->` and `<!-- endif -->` markers only if `name` is
+/// one of the given profiles, removing it otherwise, e.g. to output some passages only in print.
+///
+/// Conditions may be nested; the content of nested regions is kept only if all of their
+/// conditions hold. A region must end in the same block (or at the same level of the document)
+/// as it starts.
+pub fn apply_conditionals(frag: MarkdownFragment, profiles: &[String]) -> Result<MarkdownFragment, ASTError> {
+    Ok(MarkdownFragment::new(apply_conditionals_to_elements(frag.elements, profiles)?))
+}
+
+/// The events of a parser, possibly along with where in the source they come from.
+trait EventSource<'a>: Iterator<Item = Event<'a>> {
+    /// The byte range in the source of the most recent event, if known.
+    fn last_range(&self) -> Option<Range<usize>>;
+}
+
+/// Passes on the events of a parser that does not report source positions.
+struct Untracked<'p, I>(&'p mut I);
+impl<'a, 'p, I: Iterator<Item = Event<'a>>> Iterator for Untracked<'p, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        self.0.next()
+    }
+}
+impl<'a, 'p, I: Iterator<Item = Event<'a>>> EventSource<'a> for Untracked<'p, I> {
+    fn last_range(&self) -> Option<Range<usize>> {
+        None
+    }
+}
+
+/// Passes on the events of a parser, remembering the source range of the most recent one.
+struct OffsetTracker<'a> {
+    inner: OffsetIter<'a>,
+    last_range: Range<usize>,
+}
+impl<'a> Iterator for OffsetTracker<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        let (event, range) = self.inner.next()?;
+        self.last_range = range;
+        Some(event)
+    }
+}
+impl<'a> EventSource<'a> for OffsetTracker<'a> {
+    fn last_range(&self) -> Option<Range<usize>> {
+        Some(self.last_range.clone())
+    }
+}
+
+fn parse_events<'a, I: EventSource<'a>>(parser: &mut I) -> Result<MarkdownFragment, ASTError> {
     let mut elements: Vec<MarkdownElement> = Vec::new();
     loop {
-        let mut subfrag = parse_until_end_event(&mut parser)?;
+        let mut subfrag = parse_until_end_event(parser)?;
         if subfrag.elements().is_empty() {
             break;
         }
         elements.append(&mut subfrag.elements);
     }
-    Ok(MarkdownFragment::new(elements))
+    Ok(MarkdownFragment::new(group_divs(elements)?))
+}
+
+/// Parses the events of a Markdown parser into a fragment.
+pub fn parse<'a, I: Iterator<Item = Event<'a>>>(parser: &mut I) -> Result<MarkdownFragment, ASTError> {
+    parse_events(&mut Untracked(parser))
+}
+
+/// Like `parse`, but errors carry the source offset of the event at which parsing failed and
+/// unsupported constructs carry their source range.
+pub fn parse_with_offsets(parser: Parser) -> Result<MarkdownFragment, ASTError> {
+    let mut tracker = OffsetTracker {
+        inner: parser.into_offset_iter(),
+        last_range: 0..0,
+    };
+    parse_events(&mut tracker)
+        .map_err(|err| err.at_offset(tracker.last_range.start))
+}
+
+/// Converts the source ranges of the unsupported constructs in the fragment.
+fn relocate_unsupported(frag: &mut MarkdownFragment, to_source: &dyn Fn(usize) -> usize) {
+    for elem in frag.elements_mut() {
+        match elem {
+            MarkdownElement::Unsupported(_, Some(range)) => {
+                *range = to_source(range.start)..to_source(range.end);
+            },
+            MarkdownElement::Heading(_, content) | MarkdownElement::Paragraph(content)
+                | MarkdownElement::Link(_, content) | MarkdownElement::Image(_, content, _)
+                | MarkdownElement::BlockQuote(content) | MarkdownElement::Formatting(_, content)
+                | MarkdownElement::Div(_, content) => relocate_unsupported(content, to_source),
+            MarkdownElement::List(_, items) => {
+                for item in items {
+                    relocate_unsupported(item, to_source);
+                }
+            },
+            MarkdownElement::Table(table) => {
+                for cell in table.header_rows.iter_mut().chain(table.body_rows.iter_mut()).flatten() {
+                    relocate_unsupported(cell, to_source);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Splits a leading `---`-delimited YAML front matter block off the Markdown text.
+pub fn split_front_matter(md_string: &str) -> (FrontMatter, &str) {
+    let mut front_matter = FrontMatter::new();
+
+    let mut lines = md_string.split_inclusive('\n');
+    let mut offset = match lines.next() {
+        Some(first) if first.trim_end() == "---" => first.len(),
+        _ => return (front_matter, md_string),
+    };
+
+    let mut closed = false;
+    let mut yaml_lines = Vec::new();
+    for line in lines {
+        offset += line.len();
+        let trimmed = line.trim_end();
+        if trimmed == "---" || trimmed == "..." {
+            closed = true;
+            break;
+        }
+        yaml_lines.push(trimmed);
+    }
+    if !closed {
+        // not front matter after all (probably a thematic break)
+        return (front_matter, md_string);
+    }
+
+    for line in yaml_lines {
+        if line.starts_with(' ') || line.starts_with('#') {
+            // nested value or comment
+            continue;
+        }
+        if let Some(colon_index) = line.find(':') {
+            let key = line[..colon_index].trim();
+            let mut value = line[colon_index+1..].trim();
+            if value.len() >= 2 && (
+                (value.starts_with('"') && value.ends_with('"'))
+                || (value.starts_with('\'') && value.ends_with('\''))
+            ) {
+                value = &value[1..value.len()-1];
+            }
+            if !key.is_empty() {
+                front_matter.entries_mut().insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    (front_matter, &md_string[offset..])
 }
 
+/// Loads a UTF-8 Markdown file.
 pub fn load(path: &Path) -> Result<MarkdownFragment, ASTError> {
+    load_with_front_matter(path, InputEncoding::Utf8).map(|(frag, _front_matter)| frag)
+}
+
+pub fn load_with_front_matter(path: &Path, encoding: InputEncoding) -> Result<(MarkdownFragment, FrontMatter), ASTError> {
     let mut md_file: File = match File::open(&path) {
         Ok(f) => f,
         Err(err) => {
@@ -252,23 +948,74 @@ pub fn load(path: &Path) -> Result<MarkdownFragment, ASTError> {
             )));
         },
     };
-    let mut md_string: String = String::new();
-    if let Err(err) = md_file.read_to_string(&mut md_string) {
+    let mut md_bytes: Vec<u8> = Vec::new();
+    if let Err(err) = md_file.read_to_end(&mut md_bytes) {
         return Err(ASTError::new(format!(
             "failed to read Markdown file {:?}: {}", path, err,
         )));
     };
+    let md_string = match encoding.decode(&md_bytes) {
+        Ok(s) => s,
+        Err(err) => return Err(ASTError::new(format!("Markdown file {:?} is {}", path, err))),
+    };
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_str(&md_string, base_dir, encoding)
+        .map_err(|err| err.in_file(path))
+}
+
+/// The result of loading a Markdown file along with its front matter.
+type LoadResult = Result<(MarkdownFragment, FrontMatter), ASTError>;
+
+/// Loads several Markdown files using up to `jobs` threads, returning the results in the order
+/// of `paths`.
+pub fn load_all_with_front_matter(paths: &[PathBuf], jobs: usize, encoding: InputEncoding) -> Vec<LoadResult> {
+    let next_index = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<LoadResult>>> =
+        Mutex::new(paths.iter().map(|_| None).collect());
 
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(paths.len()) {
+            scope.spawn(|| loop {
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                if index >= paths.len() {
+                    break;
+                }
+                let result = load_with_front_matter(&paths[index], encoding);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+        .into_iter()
+        .map(|r| r.expect("file not loaded"))
+        .collect()
+}
+
+/// Parses a Markdown document, splitting off its front matter. Included files are resolved
+/// relative to `base_dir` and read in `encoding`.
+pub fn parse_str(md_string: &str, base_dir: &Path, encoding: InputEncoding) -> Result<(MarkdownFragment, FrontMatter), ASTError> {
     let mut options = pulldown_cmark::Options::empty();
     options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
     options.insert(pulldown_cmark::Options::ENABLE_TABLES);
-    let mut md_parser = pulldown_cmark::Parser::new_ext(&md_string, options);
-    let md_frag = match parse(&mut md_parser) {
-        Ok(ast) => ast,
-        Err(err) => return Err(ASTError::new(format!(
-            "failed to parse Markdown file {:?}: {}", path, err,
-        ))),
+    let (front_matter, md_body) = split_front_matter(md_string);
+    let front_matter_length = md_string.len() - md_body.len();
+
+    // report offsets in the file as written, not in the preprocessed text
+    let relocate = |err: ASTError, to_body: &dyn Fn(usize) -> usize| {
+        let offset = err.offset().map(|o| to_body(o) + front_matter_length);
+        ASTError { offset, ..err }
     };
+    let (md_expanded, include_map) = preprocess::expand_includes(md_body, base_dir, encoding)
+        .map_err(|err| relocate(err, &|o| o))?;
+    let (md_marked, div_map) = preprocess::mark_fenced_divs(&md_expanded)
+        .map_err(|err| relocate(err, &|o| include_map.to_input(o)))?;
+    let md_parser = pulldown_cmark::Parser::new_ext(&md_marked, options);
+    let to_body = |o| include_map.to_input(div_map.to_input(o));
+    let mut md_frag = parse_with_offsets(md_parser)
+        .map_err(|err| relocate(err, &to_body))?;
+    relocate_unsupported(&mut md_frag, &|o| to_body(o) + front_matter_length);
 
-    Ok(md_frag)
+    Ok((md_frag, front_matter))
 }
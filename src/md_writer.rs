@@ -0,0 +1,352 @@
+use crate::md_ast::{Attributes, CodeInfo, MarkdownElement, MarkdownFormat, MarkdownFragment, MarkdownTable};
+
+
+/// Serializes a Markdown fragment back into CommonMark (with the table, strikethrough, image
+/// attribute and fenced div extensions understood by the parser).
+///
+/// Parsing the output yields the same fragment, except for what the parser drops: link titles,
+/// the contents of unsupported constructs and the original choice of markers and whitespace.
+/// This makes the function suitable for normalizing source files.
+pub fn to_markdown(frag: &MarkdownFragment) -> String {
+    let mut ret = blocks_to_markdown(frag, false);
+    if !ret.is_empty() {
+        ret.push('\n');
+    }
+    ret
+}
+
+
+/// Outputs the elements of a fragment as a sequence of blocks, consecutive inline elements
+/// forming one block. Blocks are separated by blank lines unless `tight` (as in the items of a
+/// tight list). The result does not end with a newline.
+fn blocks_to_markdown(frag: &MarkdownFragment, tight: bool) -> String {
+    let separator = if tight { "\n" } else { "\n\n" };
+    let mut blocks: Vec<String> = Vec::new();
+    let mut inline_run: Vec<&MarkdownElement> = Vec::new();
+    // whether the previous block was a list, whether it was ordered and whether it used the
+    // alternative markers
+    let mut previous_list: Option<(bool, bool)> = None;
+
+    for elem in frag.elements() {
+        if !elem.is_block() {
+            inline_run.push(elem);
+            continue;
+        }
+        if !inline_run.is_empty() {
+            blocks.push(inline_to_markdown(&inline_run).trim_end_matches('\n').to_owned());
+            inline_run.clear();
+            previous_list = None;
+        }
+
+        // a list directly following another one of the same kind would be merged into it
+        let alternate = match (elem, previous_list) {
+            (MarkdownElement::List(start, _), Some((ordered, alternate))) if start.is_some() == ordered => !alternate,
+            _ => false,
+        };
+        previous_list = match elem {
+            MarkdownElement::List(start, _) => Some((start.is_some(), alternate)),
+            _ => None,
+        };
+        blocks.push(block_to_markdown(elem, alternate));
+    }
+    if !inline_run.is_empty() {
+        blocks.push(inline_to_markdown(&inline_run).trim_end_matches('\n').to_owned());
+    }
+
+    blocks.join(separator)
+}
+
+/// Outputs a block element; `alternate` selects the alternative list markers.
+fn block_to_markdown(elem: &MarkdownElement, alternate: bool) -> String {
+    match elem {
+        MarkdownElement::Heading(level, content) => {
+            let text = fragment_inline_to_markdown(content);
+            if text.contains('\n') && *level <= 2 {
+                // only Setext headings may span several lines
+                let underline = if *level == 1 { "===" } else { "---" };
+                format!("{}\n{}", text, underline)
+            } else {
+                format!("{} {}", "#".repeat(*level as usize), text)
+            }
+        },
+        MarkdownElement::Paragraph(content) => fragment_inline_to_markdown(content),
+        MarkdownElement::List(start, items) => list_to_markdown(*start, items, alternate),
+        MarkdownElement::BlockQuote(content) => {
+            prefix_lines(&blocks_to_markdown(content, false), "> ", "> ")
+        },
+        MarkdownElement::CodeBlock(info, content) => code_block_to_markdown(info, content),
+        MarkdownElement::Table(table) => table_to_markdown(table),
+        MarkdownElement::Rule => "***".to_owned(),
+        MarkdownElement::Div(name, content) => {
+            let inner = blocks_to_markdown(content, false);
+            if inner.is_empty() {
+                format!("::: {}\n:::", name)
+            } else {
+                format!("::: {}\n{}\n:::", name, inner)
+            }
+        },
+        other => inline_to_markdown(&[other]),
+    }
+}
+
+fn list_to_markdown(start: Option<u64>, items: &[MarkdownFragment], alternate: bool) -> String {
+    // the items of a loose list contain paragraphs, those of a tight list contain the text directly
+    let tight = !items.iter()
+        .any(|item| item.elements().iter().any(|e| matches!(e, MarkdownElement::Paragraph(_))));
+
+    let mut rendered_items: Vec<String> = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let marker = match start {
+            Some(s) => format!("{}{}", s + i as u64, if alternate { ")" } else { "." }),
+            None => (if alternate { "*" } else { "-" }).to_owned(),
+        };
+        let content = blocks_to_markdown(item, tight);
+        if content.is_empty() {
+            rendered_items.push(marker);
+            continue;
+        }
+        let indent = " ".repeat(marker.len() + 1);
+        rendered_items.push(prefix_lines(&content, &format!("{} ", marker), &indent));
+    }
+    rendered_items.join(if tight { "\n" } else { "\n\n" })
+}
+
+fn code_block_to_markdown(info: &CodeInfo, content: &MarkdownFragment) -> String {
+    let mut code = String::new();
+    for elem in content.elements() {
+        if let MarkdownElement::Text(text) = elem {
+            code.push_str(text);
+        }
+    }
+    if !code.is_empty() && !code.ends_with('\n') {
+        code.push('\n');
+    }
+
+    let info_string = match info.language() {
+        Some(language) => {
+            let mut tokens = vec![language.to_owned()];
+            tokens.extend(info.attributes().iter().cloned());
+            tokens.join(" ")
+        },
+        // in braces, the first attribute is not taken for the language
+        None if !info.attributes().is_empty() => format!("{{{}}}", info.attributes().join(" ")),
+        None => String::new(),
+    };
+
+    // backticks cannot appear in the info string of a backtick fence
+    let fence_char = if info_string.contains('`') { '~' } else { '`' };
+    let fence = fence_char.to_string().repeat(longest_run(&code, fence_char).max(2) + 1);
+    format!("{}{}\n{}{}", fence, info_string, code, fence)
+}
+
+fn table_to_markdown(table: &MarkdownTable) -> String {
+    let columns = table.alignments().len();
+    let row_to_markdown = |row: &[MarkdownFragment]| -> String {
+        let mut cells: Vec<String> = row.iter()
+            .map(fragment_inline_to_markdown)
+            .collect();
+        cells.resize(columns, String::new());
+        format!("| {} |", cells.join(" | "))
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    match table.header_rows().first() {
+        Some(header) => lines.push(row_to_markdown(header)),
+        None => lines.push(row_to_markdown(&[])),
+    }
+    let delimiters: Vec<&str> = table.alignments().iter()
+        .map(|a| match a {
+            'l' => ":--",
+            'c' => ":-:",
+            'r' => "--:",
+            _ => "---",
+        })
+        .collect();
+    lines.push(format!("| {} |", delimiters.join(" | ")));
+    for row in table.body_rows() {
+        lines.push(row_to_markdown(row));
+    }
+    lines.join("\n")
+}
+
+
+fn fragment_inline_to_markdown(frag: &MarkdownFragment) -> String {
+    let elements: Vec<&MarkdownElement> = frag.elements().iter().collect();
+    inline_to_markdown(&elements)
+}
+
+fn inline_to_markdown(elements: &[&MarkdownElement]) -> String {
+    let mut ret = String::new();
+    // the parser splits text at escapes and entities; escape it as a whole
+    let mut text_run = String::new();
+    for (i, elem) in elements.iter().enumerate() {
+        match elem {
+            MarkdownElement::Text(text) => {
+                text_run.push_str(text);
+                if matches!(elements.get(i + 1), Some(MarkdownElement::Text(_))) {
+                    continue;
+                }
+                // an exclamation mark must not turn a following link into an image
+                let before_link = matches!(elements.get(i + 1), Some(MarkdownElement::Link(_, _)));
+                let line = &ret[ret.rfind('\n').map(|i| i + 1).unwrap_or(0)..];
+                let escaped = escape_text(&text_run, line, before_link);
+                ret.push_str(&escaped);
+                text_run.clear();
+            },
+            MarkdownElement::Code(code) => ret.push_str(&code_span(code)),
+            MarkdownElement::Link(url, content) => {
+                ret.push_str(&format!("[{}]({})", fragment_inline_to_markdown(content), link_destination(url)));
+            },
+            MarkdownElement::Image(url, alt_text, attrs) => {
+                ret.push_str(&format!("![{}]({})", fragment_inline_to_markdown(alt_text), link_destination(url)));
+                if !attrs.is_empty() {
+                    ret.push_str(&attributes_to_markdown(attrs));
+                }
+            },
+            MarkdownElement::Formatting(format, content) => {
+                let delimiter = match format {
+                    MarkdownFormat::Emphasis => {
+                        // *(**strong**)* would be read as ***strong***
+                        let nested = |e: Option<&MarkdownElement>| matches!(e, Some(MarkdownElement::Formatting(_, _)));
+                        if nested(content.elements().first()) || nested(content.elements().last()) {
+                            "_"
+                        } else {
+                            "*"
+                        }
+                    },
+                    MarkdownFormat::Strong => "**",
+                    MarkdownFormat::Strikethrough => "~~",
+                };
+                ret.push_str(&format!("{}{}{}", delimiter, fragment_inline_to_markdown(content), delimiter));
+            },
+            MarkdownElement::HtmlFragment(html) => ret.push_str(html),
+            MarkdownElement::FootnoteRef(name) => ret.push_str(&format!("[^{}]", name)),
+            MarkdownElement::Unsupported(description, _) => {
+                // hard line breaks are the only unsupported construct that can be restored
+                if description == "HardBreak" {
+                    ret.push_str("\\\n");
+                }
+            },
+            block => {
+                ret.push_str(&block_to_markdown(block, false));
+                ret.push('\n');
+            },
+        }
+    }
+    ret
+}
+
+/// Escapes the characters of text that Markdown would otherwise interpret; `line` is what
+/// precedes the text on its line.
+fn escape_text(text: &str, line: &str, before_link: bool) -> String {
+    let mut ret = String::with_capacity(text.len());
+    let mut line_start = line.is_empty();
+    // keeps "1." or "1)" at the beginning of a line from starting a list
+    let mut line_digits = !line.is_empty() && line.chars().all(|c| c.is_ascii_digit());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let next = chars.peek().copied();
+        let escape = match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '|' | '~' | '{' | '}' => true,
+            '!' => next == Some('[') || (next.is_none() && before_link),
+            '&' => next.map(|n| n.is_ascii_alphanumeric() || n == '#').unwrap_or(false),
+            // a trailing '#' could close an ATX heading
+            '#' => line_start || next.is_none(),
+            '>' | '-' | '+' | '=' | ':' => line_start,
+            '.' | ')' => line_digits,
+            _ => false,
+        };
+        if escape {
+            ret.push('\\');
+        }
+        ret.push(c);
+
+        line_digits = c.is_ascii_digit() && (line_start || line_digits);
+        line_start = c == '\n';
+    }
+    ret
+}
+
+/// Outputs inline code, delimited by more backticks than it contains in a row.
+fn code_span(code: &str) -> String {
+    let delimiter = "`".repeat(longest_run(code, '`') + 1);
+    let needs_padding = code.starts_with('`') || code.ends_with('`')
+        || (code.starts_with(' ') && code.ends_with(' ') && !code.trim().is_empty());
+    if needs_padding {
+        format!("{} {} {}", delimiter, code, delimiter)
+    } else {
+        format!("{}{}{}", delimiter, code, delimiter)
+    }
+}
+
+fn link_destination(url: &str) -> String {
+    let mut ret = String::with_capacity(url.len() + 2);
+    let needs_brackets = url.is_empty()
+        || url.contains(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '<' || c == '>');
+    if needs_brackets {
+        ret.push('<');
+    }
+    for c in url.chars() {
+        if c == '\\' || c == '&' || c == '<' || c == '>' {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    if needs_brackets {
+        ret.push('>');
+    }
+    ret
+}
+
+fn attributes_to_markdown(attrs: &Attributes) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(id) = attrs.id() {
+        parts.push(format!("#{}", id));
+    }
+    for class in attrs.classes() {
+        parts.push(format!(".{}", class));
+    }
+    for (key, value) in attrs.values() {
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '}') {
+            parts.push(format!("{}=\"{}\"", key, value));
+        } else {
+            parts.push(format!("{}={}", key, value));
+        }
+    }
+    format!("{{{}}}", parts.join(" "))
+}
+
+
+/// The length of the longest run of the character in the text.
+fn longest_run(text: &str, c: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for d in text.chars() {
+        if d == c {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+/// Prefixes the first line of the text with `first` and the others with `rest`, leaving blank
+/// lines empty (apart from the `>` of a block quote).
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    let mut ret = String::with_capacity(text.len() + first.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            ret.push('\n');
+        }
+        let prefix = if i == 0 { first } else { rest };
+        if line.is_empty() {
+            ret.push_str(prefix.trim_end());
+        } else {
+            ret.push_str(prefix);
+            ret.push_str(line);
+        }
+    }
+    ret
+}
@@ -1,11 +1,217 @@
 use clap::Clap;
 
+
+/// The names of the subcommands; any other first argument is passed to `build`.
+pub(crate) const SUBCOMMAND_NAMES: [&str; 6] = ["build", "check", "stats", "dump-ast", "dump-toc", "watch"];
+
+
 #[derive(Clap)]
-#[clap()]
+#[clap(version = env!("CARGO_PKG_VERSION"))]
 pub(crate) struct Opts {
-    #[clap(default_value = "src", about = "The directory from which to load the book.")]
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clap)]
+pub(crate) enum Command {
+    #[clap(about = "Convert the book to a TeX file. This is the default if no subcommand is given.")]
+    Build(BuildOpts),
+
+    #[clap(about = "Load and convert the whole book without writing any output, reporting all problems including missing images.")]
+    Check(CheckOpts),
+
+    #[clap(about = "Print the word count and the numbers of figures, tables and code blocks of each chapter.")]
+    Stats(BookOpts),
+
+    #[clap(about = "Print the syntax tree of a Markdown file as JSON. Requires the \"json\" feature.")]
+    DumpAst(DumpAstOpts),
+
+    #[clap(about = "Print the table of contents as JSON. Requires the \"json\" feature.")]
+    DumpToc(DumpTocOpts),
+
+    #[clap(about = "Build the book, then build it again whenever the table of contents or a section file changes.")]
+    Watch(WatchOpts),
+}
+
+#[derive(Clap)]
+pub(crate) struct BookOpts {
+    #[clap(default_value = "src", about = "The directory from which to load the book, or - to convert a single document from standard input.")]
     pub directory: String,
 
-    #[clap(default_value = "book.tex", about = "The output TeX file.")]
+    #[clap(long, default_value = "SUMMARY.md", about = "The name of the table of contents file within the book directory. If it does not exist, SUMMARY.md, TOC.md and index.md are tried.")]
+    pub toc_file: String,
+
+    #[clap(long, default_value = "utf-8", about = "The encoding of the Markdown files (utf-8, utf-16, latin-1 or windows-1252).")]
+    pub input_encoding: String,
+
+    #[clap(long = "src", number_of_values = 1, about = "An additional directory in which to look for section files. May be passed multiple times; directories are searched in order after the book directory.")]
+    pub src: Vec<String>,
+
+    #[clap(long, number_of_values = 1, about = "Only output the ToC entries tagged with this tag (using `<!-- tag -->` or `{tag}` after the link in the ToC), and the entries containing them.")]
+    pub only: Vec<String>,
+
+    #[clap(long, number_of_values = 1, about = "Do not output the ToC entries tagged with this tag.")]
+    pub exclude: Vec<String>,
+
+    #[clap(long, about = "Only output the section with this path (as given in the ToC) and its subsections.")]
+    pub section: Option<String>,
+}
+
+#[derive(Clap)]
+pub(crate) struct ConvertOpts {
+    #[clap(long, default_value = "context", about = "The TeX format to output (context or latex).")]
+    pub backend: String,
+
+    #[clap(long, default_value = "human", about = "The format of errors and warnings about the input (human, or json for one object per line with the keys severity, file, offset and message).")]
+    pub error_format: String,
+
+    #[clap(short, long, about = "The number of threads parsing section files in parallel. Defaults to the number of CPUs.")]
+    pub jobs: Option<usize>,
+
+    #[clap(long, about = "Skip unsupported Markdown constructs with a warning (and a comment in the output) instead of failing.")]
+    pub lenient: bool,
+
+    #[clap(long, about = "Keep straight double quotes instead of replacing them with typographic ones.")]
+    pub no_smart_quotes: bool,
+
+    #[clap(long, about = "The code of the main language of the book (e.g. \"de\"), which determines hyphenation. Overrides the language in the front matter of the ToC.")]
+    pub language: Option<String>,
+
+    #[clap(long = "escape-chars", number_of_values = 1, about = "A codepoint (U+00A0) or range of codepoints (U+2000-U+200A) to output as \\char\"XXXX. May be passed multiple times; U+FFFD is always escaped.")]
+    pub escape_chars: Vec<String>,
+
+    #[clap(long, about = "Use a paragraph starting with \"Table:\" directly after a table as the table's caption.")]
+    pub table_captions: bool,
+
+    #[clap(long, about = "Place tables as floats.")]
+    pub float_tables: bool,
+
+    #[clap(long, about = "Place images as floats, using their alternative text as caption.")]
+    pub float_figures: bool,
+
+    #[clap(long, about = "Place a list of figures in the back matter if there are captioned figures (see --float-figures and --number-floats). Like the index, it gets an entry in the table of contents.")]
+    pub list_of_figures: bool,
+
+    #[clap(long, about = "Label images with alternative text as fig:SLUG and captioned tables as tab:SLUG, placing them as numbered floats, and turn links to #fig:SLUG or #tab:SLUG into numbered references.")]
+    pub number_floats: bool,
+
+    #[clap(long, default_value = "here", about = "The default placement of floats (here, top, bottom, force or page).")]
+    pub float_placement: String,
+
+    #[clap(long, about = "Number the lines of all code blocks, not only those with a .line-numbers or .numberLines attribute. Only supported by the context backend.")]
+    pub number_code_lines: bool,
+
+    #[clap(long, about = "Wrap each paragraph in \\startparagraph and \\stopparagraph instead of only separating paragraphs by blank lines. Only supported by the context backend.")]
+    pub explicit_paragraphs: bool,
+
+    #[clap(long, about = "Output block quotes as \\startnarrower environments instead of \\startblockquote, so that each level of nesting is indented further. Only supported by the context backend.")]
+    pub narrower_quotes: bool,
+
+    #[clap(long, default_value = "none", about = "The frame style of tables (none, minimal or grid).")]
+    pub table_style: String,
+
+    #[clap(long, about = "A comma-separated list of the ConTeXt commands to use for each Markdown heading level, e.g. \"chapter,section,subsection\". By default, level-1 headings are skipped (their title comes from the table of contents) and deeper levels become sections.")]
+    pub heading_commands: Option<String>,
+
+    #[clap(long, number_of_values = 1, about = "Map the name of a fenced div (`::: name`) to the ConTeXt environment wrapping its contents, e.g. \"warning=framedtext\". May be passed multiple times; unmapped names are used as environment names directly.")]
+    pub environment: Vec<String>,
+
+    #[clap(long, number_of_values = 1, about = "Output the content between <!-- if NAME --> and <!-- endif --> where NAME is this profile, e.g. \"print\" or \"ebook\"; such content is left out otherwise. May be passed multiple times.")]
+    pub profile: Vec<String>,
+
+    #[clap(long, number_of_values = 1, about = "Load the ConTeXt environment file with this name (using \\environment) before the start of the document. May be passed multiple times.")]
+    pub use_environment: Vec<String>,
+
+    #[clap(long, about = "A TOML file of `element = \"command\"` lines overriding the ConTeXt commands used for blockquote, admonition, itemize, typing, type, section, goto, note, figure, table, strikethrough and index.")]
+    pub command_map: Option<String>,
+}
+
+#[derive(Clap)]
+pub(crate) struct BuildOpts {
+    #[clap(flatten)]
+    pub book: BookOpts,
+
+    #[clap(default_value = "book.tex", about = "The output TeX file, or - for standard output when converting a single document.")]
     pub out_file: String,
+
+    #[clap(long, about = "Convert the single Markdown document given instead of the book directory, without a table of contents or document preamble.")]
+    pub single: bool,
+
+    #[clap(long, about = "Output a ConTeXt project: the output file becomes a product with one component per top-level section, next to project.tex and environment.tex.")]
+    pub project: bool,
+
+    #[clap(short, long, about = "Keep going when a section fails to load or convert, marking it in the output, and report all failures at the end.")]
+    pub keep_going: bool,
+
+    #[clap(long, about = "Fail if an image referenced by the book does not exist, listing all missing images. The check subcommand always does this.")]
+    pub self_contained: bool,
+
+    #[clap(long, about = "Refuse to overwrite an existing output file.")]
+    pub no_clobber: bool,
+
+    #[clap(long, about = "Overwrite the output file without a warning even if it was not generated by md-context.")]
+    pub force: bool,
+
+    #[clap(long, about = "Do not note the time of generation in the output file.")]
+    pub no_timestamp: bool,
+
+    #[clap(long, about = "Write a Makefile rule listing the files the output depends on into this file.")]
+    pub deps: Option<String>,
+
+    #[clap(long, default_value = "utf-8", about = "The encoding of the output TeX file (utf-8 or latin-1).")]
+    pub output_encoding: String,
+
+    #[clap(long, about = "Output characters outside of Latin-1 as \\char\"XXXX.")]
+    pub escape_non_latin1: bool,
+
+    #[clap(long, about = "Write code blocks with at least this many lines to separate files in a \"listings\" directory next to the output file and include them using \\typefile.")]
+    pub external_listings: Option<usize>,
+
+    #[clap(long, about = "Copy local images into an \"assets\" directory next to the output file and reference them there.")]
+    pub copy_assets: bool,
+
+    #[clap(long, default_value = "0", about = "Indent the output by this many spaces per level of nesting of environments.")]
+    pub indent: usize,
+
+    #[clap(long, about = "Wrap output lines longer than this many characters where possible.")]
+    pub wrap: Option<usize>,
+
+    #[clap(flatten)]
+    pub convert: ConvertOpts,
+}
+
+#[derive(Clap)]
+pub(crate) struct CheckOpts {
+    #[clap(flatten)]
+    pub book: BookOpts,
+
+    #[clap(flatten)]
+    pub convert: ConvertOpts,
+}
+
+#[derive(Clap)]
+pub(crate) struct DumpAstOpts {
+    #[clap(about = "The Markdown file whose syntax tree to print.")]
+    pub file: String,
+
+    #[clap(long, default_value = "utf-8", about = "The encoding of the Markdown files (utf-8, utf-16, latin-1 or windows-1252).")]
+    pub input_encoding: String,
+}
+
+#[derive(Clap)]
+pub(crate) struct DumpTocOpts {
+    #[clap(flatten)]
+    pub book: BookOpts,
+
+    #[clap(long, about = "Print the paths of all section files referenced by the table of contents instead of the table of contents itself.")]
+    pub list_files: bool,
+}
+
+#[derive(Clap)]
+pub(crate) struct WatchOpts {
+    #[clap(flatten)]
+    pub build: BuildOpts,
+
+    #[clap(long, default_value = "1000", about = "How often to look for changes, in milliseconds.")]
+    pub interval: u64,
 }
@@ -0,0 +1,313 @@
+use std::ops::Range;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::encoding::InputEncoding;
+use crate::md_ast::ASTError;
+
+
+lazy_static! {
+    static ref INCLUDE_RE: Regex = Regex::new("\\{\\{#(?:rustdoc_)?include\\s+([^}]+?)\\s*\\}\\}").unwrap();
+    static ref DIV_OPEN_RE: Regex = Regex::new("^:{3,}\\s*(?:\\{\\s*\\.([A-Za-z]+)\\s*\\}|([A-Za-z]+))\\s*:*\\s*$").unwrap();
+    static ref DIV_CLOSE_RE: Regex = Regex::new("^:{3,}\\s*$").unwrap();
+    static ref CODE_FENCE_RE: Regex = Regex::new("^ {0,3}(`{3,}|~{3,})").unwrap();
+}
+
+/// How deeply includes may be nested before we assume a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    output_start: usize,
+    input_start: usize,
+    copied: bool,
+}
+
+/// Maps byte offsets in the output of a preprocessing step back to offsets in its input.
+///
+/// Offsets in text that was copied unchanged map to the same text in the input; offsets in text
+/// that replaced something (an expanded include or a fenced div marker) map to the beginning of
+/// what it replaced.
+#[derive(Clone, Debug, Default)]
+pub struct OffsetMap {
+    segments: Vec<Segment>,
+    output_length: usize,
+    input_length: usize,
+}
+impl OffsetMap {
+    pub fn new() -> OffsetMap {
+        OffsetMap::default()
+    }
+
+    /// Records that the next `length` bytes of the input were copied to the output.
+    fn copied(&mut self, length: usize) {
+        let extends_last = self.segments.last().map(|s| s.copied).unwrap_or(false);
+        if !extends_last {
+            self.segments.push(Segment {
+                output_start: self.output_length,
+                input_start: self.input_length,
+                copied: true,
+            });
+        }
+        self.output_length += length;
+        self.input_length += length;
+    }
+
+    /// Records that the next `input_length` bytes of the input were replaced by `output_length`
+    /// bytes of output.
+    fn replaced(&mut self, input_length: usize, output_length: usize) {
+        self.segments.push(Segment {
+            output_start: self.output_length,
+            input_start: self.input_length,
+            copied: false,
+        });
+        self.output_length += output_length;
+        self.input_length += input_length;
+    }
+
+    /// Converts an offset in the output into the corresponding offset in the input.
+    pub fn to_input(&self, offset: usize) -> usize {
+        match self.segments.iter().rev().find(|s| s.output_start <= offset) {
+            Some(s) if s.copied => s.input_start + (offset - s.output_start),
+            Some(s) => s.input_start,
+            None => offset,
+        }
+    }
+}
+
+
+/// Selects the lines of an included file according to an mdBook-style range specification:
+/// `N` (only line N), `N:` (line N to the end), `:M` (start to line M), `N:M` or an anchor name.
+fn select_lines(text: &str, range_spec: &str) -> Result<String, String> {
+    let lines: Vec<&str> = text.lines().collect();
+
+    let parse_line = |s: &str| -> Result<Option<usize>, String> {
+        if s.is_empty() {
+            return Ok(None);
+        }
+        match s.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(format!("invalid line number {:?}", s)),
+        }
+    };
+
+    let is_numeric = !is_anchor(range_spec);
+    let (first, last) = if !is_numeric {
+        // anchor: everything between "ANCHOR: name" and "ANCHOR_END: name"
+        let start_marker = format!("ANCHOR: {}", range_spec);
+        let end_marker = format!("ANCHOR_END: {}", range_spec);
+        let start = match lines.iter().position(|l| l.contains(&start_marker)) {
+            Some(s) => s + 1,
+            None => return Err(format!("anchor {:?} not found", range_spec)),
+        };
+        let end = lines.iter().skip(start).position(|l| l.contains(&end_marker))
+            .map(|e| start + e)
+            .unwrap_or(lines.len());
+        (start, end)
+    } else if let Some(colon_index) = range_spec.find(':') {
+        let first = parse_line(&range_spec[..colon_index])?.unwrap_or(1);
+        let last = parse_line(&range_spec[colon_index+1..])?.unwrap_or(lines.len());
+        (first - 1, last.min(lines.len()))
+    } else {
+        let line = parse_line(range_spec)?.unwrap_or(1);
+        (line - 1, line.min(lines.len()))
+    };
+
+    if first >= last {
+        return Ok(String::new());
+    }
+    let mut selected = String::new();
+    for line in &lines[first..last] {
+        if !is_numeric && (line.contains("ANCHOR:") || line.contains("ANCHOR_END:")) {
+            // markers of other anchors nested within this one
+            continue;
+        }
+        selected.push_str(line);
+        selected.push('\n');
+    }
+    Ok(selected)
+}
+
+fn is_anchor(range_spec: &str) -> bool {
+    !range_spec.chars().all(|c| c.is_ascii_digit() || c == ':')
+}
+
+/// Wraps code in a fenced code block whose fence is longer than any backtick run in the code.
+fn fence_code(code: &str, language: &str) -> String {
+    let mut longest_run = 0;
+    let mut current_run = 0;
+    for c in code.chars() {
+        if c == '`' {
+            current_run += 1;
+            longest_run = longest_run.max(current_run);
+        } else {
+            current_run = 0;
+        }
+    }
+    let fence = "`".repeat((longest_run + 1).max(3));
+    format!("{}{}\n{}{}\n", fence, language, code, fence)
+}
+
+/// Follows fenced code blocks line by line, with `code_fence` holding the fence of the block the
+/// previous line was in. Returns whether the line belongs to a code block, fences included.
+fn is_code_line<'a>(line: &'a str, code_fence: &mut Option<&'a str>) -> bool {
+    let trimmed = line.trim_end();
+    if let Some(fence) = *code_fence {
+        if trimmed.trim_start().starts_with(fence) && trimmed.trim().chars().all(|c| fence.starts_with(c)) {
+            *code_fence = None;
+        }
+        return true;
+    }
+    if let Some(caps) = CODE_FENCE_RE.captures(trimmed) {
+        *code_fence = Some(caps.get(1).unwrap().as_str());
+        return true;
+    }
+    false
+}
+
+/// Returns the byte ranges of the lines within fenced code blocks, not counting the fences.
+fn fenced_code_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut code_fence = None;
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let was_in_block = code_fence.is_some();
+        if is_code_line(line, &mut code_fence) && was_in_block && code_fence.is_some() {
+            ranges.push(line_start..line_start + line.len());
+        }
+        line_start += line.len();
+    }
+    ranges
+}
+
+fn expand_includes_at_depth(text: &str, base_dir: &Path, encoding: InputEncoding, depth: usize) -> Result<(String, OffsetMap), ASTError> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(ASTError::new(format!(
+            "includes nested more than {} levels deep; is there a cycle?", MAX_INCLUDE_DEPTH,
+        )));
+    }
+
+    let code_ranges = fenced_code_ranges(text);
+    let mut expanded = String::with_capacity(text.len());
+    let mut map = OffsetMap::new();
+    let mut copied_up_to = 0;
+    for caps in INCLUDE_RE.captures_iter(text) {
+        let directive = caps.get(0).unwrap();
+        expanded.push_str(&text[copied_up_to..directive.start()]);
+        map.copied(directive.start() - copied_up_to);
+        copied_up_to = directive.end();
+
+        let argument = caps.get(1).unwrap().as_str();
+        let (file_name, range_spec) = match argument.find(':') {
+            Some(colon_index) => (&argument[..colon_index], Some(&argument[colon_index+1..])),
+            None => (argument, None),
+        };
+        let include_path = base_dir.join(file_name);
+
+        let included = match encoding.read_file(&include_path) {
+            Ok(s) => s,
+            Err(err) => {
+                return Err(ASTError::new(format!(
+                    "failed to read included file {:?}: {}", include_path, err,
+                )).at_offset(directive.start()));
+            },
+        };
+        let selected = match range_spec {
+            Some(rs) => match select_lines(&included, rs) {
+                Ok(s) => s,
+                Err(err) => {
+                    return Err(ASTError::new(format!(
+                        "failed to include {:?}: {}", include_path, err,
+                    )).at_offset(directive.start()));
+                },
+            },
+            None => included,
+        };
+
+        let in_code_block = code_ranges.iter().any(|r| r.contains(&directive.start()));
+        let replacement = if in_code_block && range_spec.is_some() {
+            // the author already put the listing into a code block; the line break after the
+            // directive ends the last line
+            selected.strip_suffix('\n').unwrap_or(&selected).to_owned()
+        } else if range_spec.map(is_anchor).unwrap_or(false) {
+            // anchored regions are code listings taken from source files
+            let language = include_path.extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or("");
+            fence_code(&selected, language)
+        } else {
+            let include_dir = include_path.parent().unwrap_or(base_dir);
+            match expand_includes_at_depth(&selected, include_dir, encoding, depth + 1) {
+                Ok((s, _included_map)) => s,
+                // an offset within the included file would be misleading in this one
+                Err(err) => return Err(err.relocated(directive.start())),
+            }
+        };
+        map.replaced(directive.len(), replacement.len());
+        expanded.push_str(&replacement);
+    }
+    expanded.push_str(&text[copied_up_to..]);
+    map.copied(text.len() - copied_up_to);
+
+    Ok((expanded, map))
+}
+
+/// Expands mdBook-style `{{#include file}}` directives, resolving paths relative to `base_dir`.
+///
+/// An include of an anchored region (`{{#include file.rs:name}}`) is output as a fenced code
+/// block, with the file's extension as its language, unless it is already within one. Returns the expanded text along with the
+/// mapping of its offsets back to `text`; errors carry the offset of the failing directive.
+pub fn expand_includes(text: &str, base_dir: &Path, encoding: InputEncoding) -> Result<(String, OffsetMap), ASTError> {
+    expand_includes_at_depth(text, base_dir, encoding, 0)
+}
+
+/// Replaces Pandoc-style fenced div lines (`::: name` or `::: {.name}` and the closing `:::`)
+/// with `<!-- div: name -->` and `<!-- /div -->` markers, which the parser turns into
+/// `MarkdownElement::Div`s.
+///
+/// Fences within fenced code blocks are left alone. Returns the marked text along with the
+/// mapping of its offsets back to `text`; errors carry the offset of the offending fence.
+pub fn mark_fenced_divs(text: &str) -> Result<(String, OffsetMap), ASTError> {
+    let mut ret = String::with_capacity(text.len());
+    let mut map = OffsetMap::new();
+    let mut open_divs: Vec<(&str, usize)> = Vec::new();
+    let mut code_fence: Option<&str> = None;
+    let mut line_start = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        let offset = line_start;
+        line_start += line.len();
+
+        if is_code_line(line, &mut code_fence) {
+            ret.push_str(line);
+            map.copied(line.len());
+            continue;
+        }
+
+        let marker = if let Some(caps) = DIV_OPEN_RE.captures(trimmed) {
+            let name = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+            open_divs.push((name, offset));
+            format!("\n<!-- div: {} -->\n\n", name)
+        } else if DIV_CLOSE_RE.is_match(trimmed) {
+            if open_divs.pop().is_none() {
+                return Err(ASTError::new("closing ::: fence without an opening one").at_offset(offset));
+            }
+            "\n<!-- /div -->\n\n".to_owned()
+        } else {
+            ret.push_str(line);
+            map.copied(line.len());
+            continue;
+        };
+        map.replaced(line.len(), marker.len());
+        ret.push_str(&marker);
+    }
+
+    if let Some((name, offset)) = open_divs.last() {
+        return Err(ASTError::new(format!("fenced div {:?} is never closed", name)).at_offset(*offset));
+    }
+    Ok((ret, map))
+}
@@ -0,0 +1,194 @@
+use std::io::{self, Write};
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+
+lazy_static! {
+    static ref OPEN_RE: Regex = Regex::new("\\\\(?:start([a-zA-Z]+)|begin\\{([a-zA-Z*]+)\\}|b[A-Z][A-Za-z]*)").unwrap();
+    static ref CLOSE_RE: Regex = Regex::new("\\\\(?:stop([a-zA-Z]+)|end\\{([a-zA-Z*]+)\\}|e[A-Z][A-Za-z]*)").unwrap();
+}
+
+
+/// Wraps a writer, collapsing runs of blank lines in the TeX code written to it into a single
+/// blank line. Optionally, lines are also indented according to the nesting of
+/// `\startX`/`\stopX`, `\begin{X}`/`\end{X}` and `\bX`/`\eX` pairs and long lines are wrapped.
+///
+/// The contents of verbatim environments are passed through unchanged. Text is processed line by
+/// line; call `finish` to output an incomplete last line.
+pub struct PrettyWriter<W: Write> {
+    inner: W,
+    indent_width: usize,
+    wrap_column: Option<usize>,
+    verbatim_markers: Vec<(String, String)>,
+    level: usize,
+    verbatim_end: Option<String>,
+    blank_line_run: usize,
+    pending: Vec<u8>,
+}
+impl<W: Write> PrettyWriter<W> {
+    /// `verbatim_markers` are the beginnings of the lines starting and ending verbatim material,
+    /// e.g. `("\\starttyping", "\\stoptyping")`.
+    pub fn new(inner: W, indent_width: usize, wrap_column: Option<usize>, verbatim_markers: Vec<(String, String)>) -> PrettyWriter<W> {
+        PrettyWriter {
+            inner,
+            indent_width,
+            wrap_column,
+            verbatim_markers,
+            level: 0,
+            verbatim_end: None,
+            blank_line_run: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Outputs the incomplete last line, if any, and flushes the underlying writer.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.write_line(decode_line(&line)?, "")?;
+        }
+        self.inner.flush()
+    }
+
+    /// Processes a line and outputs it followed by `ending`, which is empty for an incomplete last
+    /// line.
+    fn write_line(&mut self, line: &str, ending: &str) -> io::Result<()> {
+        if let Some(end) = &self.verbatim_end {
+            if line.trim_start().starts_with(end.as_str()) {
+                self.verbatim_end = None;
+                let indent = " ".repeat(self.level * self.indent_width);
+                return write!(self.inner, "{}{}{}", indent, line.trim_start(), ending);
+            }
+            return write!(self.inner, "{}{}", line, ending);
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            self.blank_line_run += 1;
+            if self.blank_line_run > 1 {
+                return Ok(());
+            }
+            return write!(self.inner, "{}", ending);
+        }
+        self.blank_line_run = 0;
+
+        let verbatim = self.verbatim_markers.iter()
+            .find(|(start, _end)| trimmed.starts_with(start.as_str()));
+        if let Some((_start, end)) = verbatim {
+            // the contents stay as they are until the end
+            self.verbatim_end = Some(end.clone());
+        }
+
+        if self.indent_width == 0 && self.wrap_column.is_none() {
+            return write!(self.inner, "{}{}", line, ending);
+        }
+
+        let opens = OPEN_RE.captures_iter(trimmed)
+            .filter(|caps| !is_document_environment(caps))
+            .count();
+        let closes = CLOSE_RE.captures_iter(trimmed)
+            .filter(|caps| !is_document_environment(caps))
+            .count();
+        let starts_with_close = CLOSE_RE.captures(trimmed)
+            .map(|caps| caps.get(0).unwrap().start() == 0 && !is_document_environment(&caps))
+            .unwrap_or(false);
+
+        let print_level = if starts_with_close { self.level.saturating_sub(1) } else { self.level };
+        let indent = " ".repeat(print_level * self.indent_width);
+
+        if self.verbatim_end.is_some() {
+            // the level does not change within verbatim environments
+            return write!(self.inner, "{}{}{}", indent, trimmed, ending);
+        }
+
+        self.level = (self.level + opens).saturating_sub(closes);
+
+        if trimmed.starts_with('%') {
+            return write!(self.inner, "{}{}{}", indent, trimmed, ending);
+        }
+        match self.wrap_column {
+            Some(column) => {
+                let wrapped_lines = wrap_line(trimmed, column.saturating_sub(indent.len()));
+                let last_index = wrapped_lines.len() - 1;
+                for (i, wrapped) in wrapped_lines.iter().enumerate() {
+                    let wrapped_ending = if i == last_index { ending } else { "\n" };
+                    write!(self.inner, "{}{}{}", indent, wrapped, wrapped_ending)?;
+                }
+                Ok(())
+            },
+            None => write!(self.inner, "{}{}{}", indent, trimmed, ending),
+        }
+    }
+}
+impl<W: Write> Write for PrettyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // lines are only decoded once they are complete; a newline byte is never part of a
+        // multibyte character
+        self.pending.extend_from_slice(buf);
+        while let Some(newline_index) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline_index).collect();
+            self.write_line(decode_line(&line[..line.len()-1])?, "\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Flushes the underlying writer. An incomplete last line is kept back until it is completed
+    /// or `finish` is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+fn decode_line(line: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(line)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+
+/// Whether an environment match is the whole document, which is not indented.
+fn is_document_environment(caps: &Captures) -> bool {
+    caps.get(1).or_else(|| caps.get(2))
+        .map(|m| m.as_str() == "text" || m.as_str() == "document")
+        .unwrap_or(false)
+}
+
+/// Splits a line into lines at most `column` characters long, if possible. Lines are only broken
+/// at spaces outside of braces, so that arguments (especially verbatim ones) stay intact.
+fn wrap_line(line: &str, column: usize) -> Vec<&str> {
+    let mut breaks = Vec::new();
+    let mut brace_depth = 0usize;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '{' {
+            brace_depth += 1;
+        } else if c == '}' {
+            brace_depth = brace_depth.saturating_sub(1);
+        } else if c == ' ' && brace_depth == 0 {
+            breaks.push(i);
+        }
+    }
+    breaks.push(line.len());
+
+    let mut ret = Vec::new();
+    let mut line_start = 0;
+    let mut last_break: Option<usize> = None;
+    for b in breaks {
+        if line[line_start..b].chars().count() > column {
+            if let Some(lb) = last_break {
+                if lb > line_start {
+                    ret.push(&line[line_start..lb]);
+                    line_start = lb + 1;
+                }
+            }
+        }
+        last_break = Some(b);
+    }
+    ret.push(&line[line_start..]);
+    ret
+}
@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+use crate::encoding::OutputEncoding;
+use crate::md_ast::MarkdownFragment;
+use crate::toc::{TableOfContents, TOCLevel};
+
+
+/// The divisions of a book.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Matter {
+    Front,
+    Body,
+    Appendices,
+    Back,
+}
+
+
+/// The reasons why Markdown cannot be rendered.
+#[derive(Debug)]
+pub enum RenderError {
+    /// A Markdown construct that the output format cannot represent, with its byte offset in the
+    /// source if known.
+    UnsupportedElement(String, Option<usize>),
+
+    /// A table whose structure cannot be output, such as one nested in another table.
+    MalformedTable(String),
+
+    /// Formatting other than emphasis, strong emphasis or strikethrough.
+    InvalidFormatting(String),
+
+    /// A heading level beyond the configured heading commands.
+    MissingHeadingCommand(u32),
+
+    /// An external code listing could not be written.
+    Listing(String),
+
+    /// An image could not be copied into the assets directory.
+    Asset(String),
+}
+impl Display for RenderError {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), FmtError> {
+        match self {
+            RenderError::UnsupportedElement(desc, _offset) => write!(formatter, "unsupported {}", desc),
+            RenderError::MalformedTable(desc) => write!(formatter, "malformed table: {}", desc),
+            RenderError::InvalidFormatting(desc) => write!(formatter, "unexpected formatting type: {}", desc),
+            RenderError::MissingHeadingCommand(level) => write!(formatter, "no command configured for heading level {}", level),
+            RenderError::Listing(message) => write!(formatter, "{}", message),
+            RenderError::Asset(message) => write!(formatter, "{}", message),
+        }
+    }
+}
+impl Error for RenderError {
+}
+impl RenderError {
+    /// The byte offset in the source of the Markdown that could not be rendered, if known.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            RenderError::UnsupportedElement(_, offset) => *offset,
+            _ => None,
+        }
+    }
+}
+
+
+/// Turns Markdown and the structure of a book into the code of a specific TeX format.
+pub trait Renderer {
+    /// Renders a Markdown fragment.
+    fn render(&self, frag: &MarkdownFragment) -> Result<String, RenderError>;
+
+    /// Escapes text so that it is output literally.
+    fn escape_text(&self, text: &str) -> String;
+
+    /// Replaces straight double quotes with typographic ones, unless disabled.
+    fn educate_quotes(&self, text: &str) -> String;
+
+    /// Outputs inline code.
+    fn inline_code(&self, code: &str) -> String;
+
+    /// Renders plain text that has not passed through the Markdown parser.
+    fn render_text(&self, text: &str) -> String {
+        // educate first so that the quotes in escapes such as \char"XXXX are left alone
+        self.escape_text(&self.educate_quotes(text))
+    }
+
+    /// Outputs everything up to (and including) the beginning of the document's body.
+    fn begin_document(&self, toc: &TableOfContents, encoding: OutputEncoding) -> String;
+
+    /// Outputs the end of the document.
+    fn end_document(&self) -> String;
+
+    fn begin_matter(&self, matter: Matter) -> String;
+
+    fn end_matter(&self, matter: Matter) -> String;
+
+    /// Outputs the beginning of a section in a language other than the main language.
+    fn begin_language(&self, language: &str) -> String;
+
+    fn end_language(&self) -> String;
+
+    /// Outputs the heading of a ToC entry; `title` and `short_title` (for running headers) have
+    /// already been rendered.
+    fn heading(&self, level: &TOCLevel, numbered: bool, title: &str, short_title: Option<&str>) -> String;
+
+    /// Outputs the generated parts of the back matter, each with a heading that is listed in the
+    /// table of contents: the list of figures (if requested and there are captioned figures) and
+    /// the index (if there are index entries). Empty if there is nothing to generate.
+    fn generated_back_matter(&self) -> String;
+
+    /// Outputs a visible marker in place of a section that failed to convert.
+    fn error_marker(&self, message: &str) -> String;
+
+    /// The lines beginning and ending verbatim material, whose contents must not be reformatted.
+    fn verbatim_markers(&self) -> Vec<(String, String)>;
+
+    /// The lines beginning and ending material that is typeset literally, and the commands that
+    /// typeset their argument literally; characters within must not be replaced by escapes.
+    fn literal_markers(&self) -> (Vec<(String, String)>, Vec<String>);
+}
@@ -0,0 +1,107 @@
+use std::ops::AddAssign;
+
+use crate::md_ast::{walk, MarkdownFragment, MarkdownTable, MarkdownVisitor};
+
+
+/// Word and element counts of (a part of) a book.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Statistics {
+    words: usize,
+    figures: usize,
+    tables: usize,
+    code_blocks: usize,
+}
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics::default()
+    }
+
+    /// Counts the words and elements of a Markdown fragment.
+    pub fn of_fragment(frag: &MarkdownFragment) -> Statistics {
+        let mut counter = StatisticsCounter {
+            stats: Statistics::new(),
+            in_word: false,
+        };
+        walk(frag, &mut counter);
+        counter.stats
+    }
+
+    accessor!(words, usize);
+    accessor!(figures, usize);
+    accessor!(tables, usize);
+    accessor!(code_blocks, usize);
+}
+impl AddAssign for Statistics {
+    fn add_assign(&mut self, other: Statistics) {
+        self.words += other.words;
+        self.figures += other.figures;
+        self.tables += other.tables;
+        self.code_blocks += other.code_blocks;
+    }
+}
+
+
+struct StatisticsCounter {
+    stats: Statistics,
+    in_word: bool,
+}
+impl StatisticsCounter {
+    fn count_words(&mut self, text: &str) {
+        // words may be split across text elements, so track word boundaries across them
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.in_word = false;
+            } else if !self.in_word {
+                self.in_word = true;
+                self.stats.words += 1;
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        self.in_word = false;
+    }
+}
+impl MarkdownVisitor for StatisticsCounter {
+    fn visit_text(&mut self, text: &str) {
+        self.count_words(text);
+    }
+
+    fn visit_code(&mut self, code: &str) {
+        self.count_words(code);
+    }
+
+    fn visit_heading(&mut self, _level: u32, _content: &MarkdownFragment) {
+        self.end_block();
+    }
+
+    fn visit_paragraph(&mut self, _content: &MarkdownFragment) {
+        self.end_block();
+    }
+
+    fn visit_block_quote(&mut self, _content: &MarkdownFragment) {
+        self.end_block();
+    }
+
+    fn visit_list_item(&mut self, _content: &MarkdownFragment) {
+        self.end_block();
+    }
+
+    fn visit_image(&mut self, _url: &str, _alt_text: &MarkdownFragment) {
+        self.stats.figures += 1;
+    }
+
+    fn visit_code_block(&mut self, _language: Option<&str>, _content: &MarkdownFragment) {
+        self.end_block();
+        self.stats.code_blocks += 1;
+    }
+
+    fn visit_table(&mut self, _table: &MarkdownTable) {
+        self.end_block();
+        self.stats.tables += 1;
+    }
+
+    fn visit_table_cell(&mut self, _content: &MarkdownFragment) {
+        self.end_block();
+    }
+}
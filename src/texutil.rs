@@ -1,29 +1,490 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 
-use crate::md_ast::{MarkdownElement, MarkdownFormat, MarkdownFragment};
+use crate::context::ContextRenderer;
+use crate::encoding::InputEncoding;
+use crate::md_ast::{Attributes, MarkdownElement, MarkdownFragment};
+use crate::render::{RenderError, Renderer};
+use crate::toc::percent_decode;
 
 
 lazy_static! {
     static ref EDUCATED_QUOTE_RE: Regex = Regex::new("(?m)(^|.)\"").unwrap();
+    static ref ENTITY_RE: Regex = Regex::new("&(?:#([0-9]{1,7})|#[xX]([0-9A-Fa-f]{1,6})|([A-Za-z][A-Za-z0-9]*));").unwrap();
+    static ref URL_RE: Regex = Regex::new("^(?:[A-Za-z][A-Za-z0-9+.-]*://|www\\.|doi:|mailto:)\\S+$").unwrap();
+    static ref COLUMNS_COMMENT_RE: Regex = Regex::new("^\\s*<!--\\s*columns:\\s*([0-9]+)\\s*-->\\s*$").unwrap();
+    static ref END_COLUMNS_COMMENT_RE: Regex = Regex::new("^\\s*<!--\\s*endcolumns\\s*-->\\s*$").unwrap();
+    static ref TEX_DIMENSION_RE: Regex = Regex::new("^[0-9]*\\.?[0-9]+(?:pt|pc|in|bp|cm|mm|dd|cc|sp|em|ex)$").unwrap();
+    static ref PERCENTAGE_RE: Regex = Regex::new("^([0-9]*\\.?[0-9]+)%$").unwrap();
+    static ref PIXELS_RE: Regex = Regex::new("^([0-9]*\\.?[0-9]+)(?:px)?$").unwrap();
+    static ref LANGUAGE_CODE_RE: Regex = Regex::new("^[A-Za-z]{2,8}(?:-[A-Za-z0-9]{1,8})*$").unwrap();
+    static ref INDEX_COMMENT_RE: Regex = Regex::new("(?s)^\\s*<!--\\s*index:\\s*(.*?)\\s*-->\\s*$").unwrap();
+}
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TableStyle {
+    /// No frame setup; ConTeXt's defaults apply.
+    None,
+    /// Rules above and below the header row and below the last row.
+    Minimal,
+    /// Frames around every cell.
+    Grid,
+}
+impl TableStyle {
+    pub fn from_name(name: &str) -> Option<TableStyle> {
+        match name {
+            "none" => Some(TableStyle::None),
+            "minimal" => Some(TableStyle::Minimal),
+            "grid" => Some(TableStyle::Grid),
+            _ => None,
+        }
+    }
+}
+
+
+/// The names of the ConTeXt commands and environments used for the various elements.
+pub struct CommandNames {
+    blockquote: String,
+    admonition: String,
+    itemize: String,
+    typing: String,
+    type_inline: String,
+    section: String,
+    goto: String,
+    hyphenated_url: String,
+    note: String,
+    figure: String,
+    table: String,
+    strikethrough: String,
+    index: String,
+}
+impl CommandNames {
+    pub fn new() -> CommandNames {
+        CommandNames {
+            blockquote: "blockquote".to_owned(),
+            admonition: "framedtext".to_owned(),
+            itemize: "itemize".to_owned(),
+            typing: "typing".to_owned(),
+            type_inline: "type".to_owned(),
+            section: "section".to_owned(),
+            goto: "goto".to_owned(),
+            hyphenated_url: "hyphenatedurl".to_owned(),
+            note: "note".to_owned(),
+            figure: "externalfigure".to_owned(),
+            table: "TABLE".to_owned(),
+            strikethrough: "overstrike".to_owned(),
+            index: "index".to_owned(),
+        }
+    }
+
+    accessor!(blockquote, str);
+    accessor!(admonition, str);
+    accessor!(itemize, str);
+    accessor!(typing, str);
+    accessor!(type_inline, str);
+    accessor!(section, str);
+    accessor!(goto, str);
+    accessor!(hyphenated_url, str);
+    accessor!(note, str);
+    accessor!(figure, str);
+    accessor!(table, str);
+    accessor!(strikethrough, str);
+    accessor!(index, str);
+
+    /// Sets the command for the element with the given (logical) name.
+    pub fn set(&mut self, element: &str, command: &str) -> Result<(), String> {
+        let slot = match element {
+            "blockquote" => &mut self.blockquote,
+            "admonition" => &mut self.admonition,
+            "itemize" => &mut self.itemize,
+            "typing" => &mut self.typing,
+            "type" => &mut self.type_inline,
+            "section" => &mut self.section,
+            "goto" => &mut self.goto,
+            "url" => &mut self.hyphenated_url,
+            "note" => &mut self.note,
+            "figure" => &mut self.figure,
+            "table" => &mut self.table,
+            "strikethrough" => &mut self.strikethrough,
+            "index" => &mut self.index,
+            other => return Err(format!("unknown element {:?}", other)),
+        };
+        *slot = command.trim_start_matches('\\').to_owned();
+        Ok(())
+    }
+
+    /// Applies overrides from a simple TOML file consisting of `element = "command"` lines.
+    pub fn load_overrides(&mut self, toml_text: &str) -> Result<(), String> {
+        for (line_index, raw_line) in toml_text.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+                continue;
+            }
+
+            let eq_index = match line.find('=') {
+                Some(i) => i,
+                None => return Err(format!("line {}: expected `element = \"command\"`", line_index + 1)),
+            };
+            let key = line[..eq_index].trim();
+            let value = line[eq_index+1..].trim();
+            if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+                return Err(format!("line {}: the command must be a quoted string", line_index + 1));
+            }
+            let command = value[1..value.len()-1].replace("\\\\", "\\");
+
+            if let Err(err) = self.set(key, &command) {
+                return Err(format!("line {}: {}", line_index + 1, err));
+            }
+        }
+        Ok(())
+    }
+}
+impl Default for CommandNames {
+    fn default() -> Self {
+        CommandNames::new()
+    }
 }
 
 
-pub fn escape_tex(text: &str) -> String {
+/// A function transforming a complete TeX document, e.g. to inject macros or apply regex
+/// replacements. See `post_process`.
+pub type PostProcessor = Box<dyn Fn(String) -> String>;
+
+
+pub struct TexConfig {
+    escaped_chars: Vec<(char, char)>,
+    table_captions: bool,
+    float_tables: bool,
+    float_figures: bool,
+    list_of_figures: bool,
+    number_floats: bool,
+    number_code_lines: bool,
+    explicit_paragraphs: bool,
+    narrower_quotes: bool,
+    profiles: Vec<String>,
+    float_placement: String,
+    external_listing_min_lines: Option<usize>,
+    table_style: TableStyle,
+    heading_commands: Vec<String>,
+    command_names: CommandNames,
+    environments: BTreeMap<String, String>,
+    environment_files: Vec<String>,
+    output_dir: PathBuf,
+    copy_assets: bool,
+    lenient: bool,
+    smart_quotes: bool,
+    language: Option<String>,
+    input_encoding: InputEncoding,
+    post_processors: Vec<PostProcessor>,
+    preceding_char: Cell<Option<char>>,
+    heading_depth: Cell<u32>,
+    source_dir: RefCell<PathBuf>,
+    copied_assets: RefCell<BTreeMap<PathBuf, String>>,
+    listing_counter: Cell<usize>,
+    index_entry_counter: Cell<usize>,
+    figure_counter: Cell<usize>,
+    warnings: RefCell<Vec<(String, Option<usize>)>>,
+}
+impl TexConfig {
+    pub fn new() -> TexConfig {
+        TexConfig {
+            escaped_chars: vec![('\u{FFFD}', '\u{FFFD}')],
+            table_captions: false,
+            float_tables: false,
+            float_figures: false,
+            list_of_figures: false,
+            number_floats: false,
+            number_code_lines: false,
+            explicit_paragraphs: false,
+            narrower_quotes: false,
+            profiles: Vec::new(),
+            float_placement: "here".to_owned(),
+            external_listing_min_lines: None,
+            table_style: TableStyle::None,
+            heading_commands: Vec::new(),
+            command_names: CommandNames::new(),
+            environments: BTreeMap::new(),
+            environment_files: Vec::new(),
+            output_dir: PathBuf::new(),
+            copy_assets: false,
+            lenient: false,
+            smart_quotes: true,
+            language: None,
+            input_encoding: InputEncoding::Utf8,
+            post_processors: Vec::new(),
+            preceding_char: Cell::new(None),
+            heading_depth: Cell::new(1),
+            source_dir: RefCell::new(PathBuf::new()),
+            copied_assets: RefCell::new(BTreeMap::new()),
+            listing_counter: Cell::new(0),
+            index_entry_counter: Cell::new(0),
+            figure_counter: Cell::new(0),
+            warnings: RefCell::new(Vec::new()),
+        }
+    }
+
+    accessor_and_mut!(escaped_chars, escaped_chars_mut, Vec<(char, char)>);
+    accessor_and_mut!(table_captions, table_captions_mut, bool);
+    accessor_and_mut!(float_tables, float_tables_mut, bool);
+    accessor_and_mut!(float_figures, float_figures_mut, bool);
+    accessor_and_mut!(list_of_figures, list_of_figures_mut, bool);
+    accessor_and_mut!(number_floats, number_floats_mut, bool);
+    accessor_and_mut!(number_code_lines, number_code_lines_mut, bool);
+    accessor_and_mut!(explicit_paragraphs, explicit_paragraphs_mut, bool);
+    // block quotes become \startnarrower environments, which indent further when nested
+    accessor_and_mut!(narrower_quotes, narrower_quotes_mut, bool);
+    // the names of the `<!-- if name -->` regions to output
+    accessor_and_mut!(profiles, profiles_mut, Vec<String>);
+    accessor_and_mut!(float_placement, float_placement_mut, String);
+    accessor_and_mut!(external_listing_min_lines, external_listing_min_lines_mut, Option<usize>);
+    accessor_and_mut!(table_style, table_style_mut, TableStyle);
+    accessor_and_mut!(heading_commands, heading_commands_mut, Vec<String>);
+    accessor_and_mut!(command_names, command_names_mut, CommandNames);
+    accessor_and_mut!(environments, environments_mut, BTreeMap<String, String>);
+    accessor_and_mut!(environment_files, environment_files_mut, Vec<String>);
+    accessor_and_mut!(output_dir, output_dir_mut, PathBuf);
+    accessor_and_mut!(copy_assets, copy_assets_mut, bool);
+    accessor_and_mut!(lenient, lenient_mut, bool);
+    accessor_and_mut!(smart_quotes, smart_quotes_mut, bool);
+    accessor_and_mut!(language, language_mut, Option<String>);
+    accessor_and_mut!(input_encoding, input_encoding_mut, InputEncoding);
+    accessor_and_mut!(post_processors, post_processors_mut, Vec<PostProcessor>);
+
+    /// Returns the warnings collected while rendering since the last call, e.g. about constructs
+    /// skipped in lenient mode.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.take_positioned_warnings().into_iter()
+            .map(|(warning, _offset)| warning)
+            .collect()
+    }
+
+    /// Like `take_warnings`, but also returns the byte offset in the source that each warning
+    /// refers to, if known.
+    pub fn take_positioned_warnings(&self) -> Vec<(String, Option<usize>)> {
+        self.warnings.replace(Vec::new())
+    }
+
+    pub(crate) fn warn(&self, warning: String) {
+        self.warn_at(warning, None);
+    }
+
+    pub(crate) fn warn_at(&self, warning: String, offset: Option<usize>) {
+        self.warnings.borrow_mut().push((warning, offset));
+    }
+
+    /// Remembers the end of the text that was just rendered; a quote directly following it closes.
+    pub(crate) fn note_preceding_text(&self, text: &str) {
+        if let Some(c) = text.chars().last() {
+            self.preceding_char.set(Some(c));
+        }
+    }
+
+    /// Forgets the preceding text at the beginning of a block.
+    pub(crate) fn forget_preceding_text(&self) {
+        self.preceding_char.set(None);
+    }
+
+    /// Sets the number of `sub` prefixes of the section command for second-level headings in the
+    /// Markdown file being rendered, which depends on the level of its ToC entry.
+    pub fn set_heading_depth(&self, depth: u32) {
+        self.heading_depth.set(depth);
+    }
+
+    pub(crate) fn heading_depth(&self) -> u32 {
+        self.heading_depth.get()
+    }
+
+    /// Sets the directory of the Markdown file being rendered, relative to which its images are
+    /// resolved when copying assets.
+    pub fn set_source_dir(&self, dir: &Path) {
+        self.source_dir.replace(dir.to_path_buf());
+    }
+
+    pub(crate) fn count_index_entry(&self) {
+        self.index_entry_counter.set(self.index_entry_counter.get() + 1);
+    }
+
+    /// The number of index entries that have been output so far.
+    pub fn index_entry_count(&self) -> usize {
+        self.index_entry_counter.get()
+    }
+
+    pub(crate) fn count_figure(&self) {
+        self.figure_counter.set(self.figure_counter.get() + 1);
+    }
+
+    /// The number of captioned (floating) figures that have been output so far.
+    pub fn figure_count(&self) -> usize {
+        self.figure_counter.get()
+    }
+
+    /// The ConTeXt environment used for fenced divs with the given name; the name itself unless
+    /// it has been mapped to another environment.
+    pub fn environment<'a>(&'a self, div_name: &'a str) -> &'a str {
+        self.environments.get(div_name).map(|e| e.as_str()).unwrap_or(div_name)
+    }
+
+    pub fn is_char_escaped(&self, c: char) -> bool {
+        self.escaped_chars.iter().any(|(first, last)| *first <= c && c <= *last)
+    }
+}
+impl Default for TexConfig {
+    fn default() -> Self {
+        TexConfig::new()
+    }
+}
+
+
+fn parse_codepoint(text: &str) -> Result<char, String> {
+    let hex = text.trim();
+    let hex = hex.strip_prefix("U+").or_else(|| hex.strip_prefix("u+")).unwrap_or(hex);
+    let value = match u32::from_str_radix(hex, 16) {
+        Ok(v) => v,
+        Err(err) => return Err(format!("invalid codepoint {:?}: {}", text, err)),
+    };
+    match std::char::from_u32(value) {
+        Some(c) => Ok(c),
+        None => Err(format!("codepoint {:?} is not a valid character", text)),
+    }
+}
+
+/// Parses a codepoint or a range of codepoints, e.g. `U+00A0` or `U+2000-U+200A`.
+pub fn parse_codepoint_range(text: &str) -> Result<(char, char), String> {
+    let (first, last) = match text.find('-') {
+        Some(dash_index) => (
+            parse_codepoint(&text[..dash_index])?,
+            parse_codepoint(&text[dash_index+1..])?,
+        ),
+        None => {
+            let c = parse_codepoint(text)?;
+            (c, c)
+        },
+    };
+    if first > last {
+        return Err(format!("codepoint range {:?} is reversed", text));
+    }
+    Ok((first, last))
+}
+
+
+/// The depth of the deepest sectioning command below a chapter that ConTeXt defines
+/// (`subsubsubsubsection`); LaTeX's deepest, `subparagraph`, is as deep.
+pub const MAX_SECTION_DEPTH: u32 = 4;
+
+pub(crate) fn sub_prefixed(depth: u32, base: &str) -> String {
+    let mut command = String::new();
+    for _ in 0..depth {
+        command.push_str("sub");
+    }
+    command.push_str(base);
+    command
+}
+
+/// Returns the name of the numbered sectioning command at the given depth below a chapter
+/// (`section`, `subsection`, `subsubsection`, ...), using the deepest one for deeper levels.
+pub fn section_command(depth: u32) -> String {
+    sub_prefixed(depth.min(MAX_SECTION_DEPTH), "section")
+}
+
+/// Returns the name of the unnumbered sectioning command at the given depth below a chapter
+/// (`subject`, `subsubject`, `subsubsubject`, ...), using the deepest one for deeper levels.
+pub fn subject_command(depth: u32) -> String {
+    sub_prefixed(depth.min(MAX_SECTION_DEPTH), "subject")
+}
+
+/// Returns the depth below a chapter of the sectioning command for a Markdown heading of the
+/// given level (at least 2), warning and clamping it if no command is that deep.
+pub(crate) fn heading_section_depth(level: u32, config: &TexConfig) -> u32 {
+    let depth = level - 2 + config.heading_depth();
+    if depth > MAX_SECTION_DEPTH {
+        config.warn(format!(
+            "heading of level {} is nested too deeply; using the deepest sectioning command instead", level,
+        ));
+        return MAX_SECTION_DEPTH;
+    }
+    depth
+}
+
+/// Outputs a character as `\char"XXXX`, braced so that TeX neither reads a following hex digit
+/// as part of the number nor swallows a following space.
+pub fn char_escape(c: char) -> String {
+    let c_int: u32 = c.into();
+    format!("{{\\char\"{:04X}}}", c_int)
+}
+
+pub fn escape_tex(text: &str, config: &TexConfig) -> String {
     let mut ret = String::with_capacity(text.len());
     for c in text.chars() {
         match c {
             '\\' | '~' | '{' | '}' | '#' | '%' | '$' => ret.push_str(&format!("\\char`\\{}", c)),
-            '\u{FFFD}' => {
-                let c_int: u32 = c.into();
-                ret.push_str(&format!("\\char\"{:04X}", c_int));
-            },
+            other if config.is_char_escaped(other) => ret.push_str(&char_escape(other)),
+            '\u{00A0}' => ret.push('~'),
+            '\u{2009}' | '\u{202F}' => ret.push_str("\\,"),
+            '\u{00AD}' => ret.push_str("\\-"),
             other => ret.push(other),
         }
     }
     ret
 }
 
+/// Whether the text is a plausible language code such as `de` or `en-US`, which can be output
+/// without escaping.
+pub fn is_language_code(text: &str) -> bool {
+    LANGUAGE_CODE_RE.is_match(text)
+}
+
+/// Formats a number with at most four decimal places and without trailing zeros.
+fn format_decimal(number: f64) -> String {
+    let formatted = format!("{:.4}", number);
+    formatted.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+/// Converts the value of a size attribute of an image to a TeX dimension. Percentages are relative
+/// to `full_size` (such as `\textwidth`), pixels (with or without the `px` unit) assume 96 pixels
+/// per inch, and TeX dimensions such as `3cm` are used as they are.
+pub fn image_dimension(value: &str, full_size: &str) -> Option<String> {
+    let value = value.trim();
+    if TEX_DIMENSION_RE.is_match(value) {
+        return Some(value.to_owned());
+    }
+    if let Some(caps) = PERCENTAGE_RE.captures(value) {
+        let percentage: f64 = caps.get(1).unwrap().as_str().parse().ok()?;
+        return Some(format!("{}{}", format_decimal(percentage / 100.0), full_size));
+    }
+    if let Some(caps) = PIXELS_RE.captures(value) {
+        // a big point is 1/72 of an inch
+        let pixels: f64 = caps.get(1).unwrap().as_str().parse().ok()?;
+        return Some(format!("{}bp", format_decimal(pixels * 72.0 / 96.0)));
+    }
+    None
+}
+
+/// Converts the `width` and `height` attributes of an image to the options of the command
+/// including it, warning about invalid values.
+pub(crate) fn image_size_options(attrs: &Attributes, config: &TexConfig) -> Vec<String> {
+    let mut options = Vec::new();
+    for (key, full_size) in &[("width", "\\textwidth"), ("height", "\\textheight")] {
+        if let Some(value) = attrs.get(key) {
+            match image_dimension(value, full_size) {
+                Some(dimension) => options.push(format!("{}={}", key, dimension)),
+                None => config.warn(format!("ignoring invalid image {} {:?}", key, value)),
+            }
+        }
+    }
+    options
+}
+
+/// Whether the text of a link is itself a URL, as with autolinks and references to DOIs.
+pub(crate) fn is_url_like(text: &str) -> bool {
+    URL_RE.is_match(text)
+}
+
 
 pub fn educate_tex_quotes(text: &str) -> String {
     let rep1res = EDUCATED_QUOTE_RE.replace_all(
@@ -42,6 +503,25 @@ pub fn educate_tex_quotes(text: &str) -> String {
     rep1res.into_owned()
 }
 
+/// Educates the quotes in a text element, taking into account the text rendered before it within
+/// the same block, since soft breaks and formatting split paragraphs into multiple text elements.
+pub(crate) fn educate_quotes_in_context(text: &str, config: &TexConfig) -> String {
+    if !*config.smart_quotes() {
+        return text.to_owned();
+    }
+
+    let educated = match config.preceding_char.get() {
+        Some(c) if !c.is_whitespace() && text.starts_with('"') => {
+            // stand in for the preceding character so that a leading quote closes
+            let with_preceding = educate_tex_quotes(&format!("x{}", text));
+            with_preceding[1..].to_owned()
+        },
+        _ => educate_tex_quotes(text),
+    };
+    config.note_preceding_text(text);
+    educated
+}
+
 
 #[derive(PartialEq, Eq)]
 enum TypingState {
@@ -50,30 +530,34 @@ enum TypingState {
     Plusses,
 }
 
-pub fn to_typing(s: &str) -> String {
+pub fn to_typing(s: &str, type_command: &str) -> String {
     let mut state = TypingState::Closed;
     let mut ret = String::new();
     for c in s.chars() {
         match c {
             '{' | '}' => {
                 if state == TypingState::Braces {
-                    ret.push_str("}");
+                    ret.push('}');
                     state = TypingState::Closed;
                 }
 
                 if state == TypingState::Closed {
-                    ret.push_str("\\type+");
+                    ret.push('\\');
+                    ret.push_str(type_command);
+                    ret.push('+');
                     state = TypingState::Plusses;
                 }
             },
             _ => {
                 if state == TypingState::Plusses {
-                    ret.push_str("+");
+                    ret.push('+');
                     state = TypingState::Closed;
                 }
 
                 if state == TypingState::Closed {
-                    ret.push_str("\\type{");
+                    ret.push('\\');
+                    ret.push_str(type_command);
+                    ret.push('{');
                     state = TypingState::Braces;
                 }
             },
@@ -91,166 +575,306 @@ pub fn to_typing(s: &str) -> String {
     ret
 }
 
-pub fn frag_to_collected_text(frag: &MarkdownFragment) -> Result<String, String> {
+pub fn frag_to_collected_text(frag: &MarkdownFragment) -> Result<String, RenderError> {
     let mut ret = String::new();
     for elem in frag.elements() {
         match elem {
-            MarkdownElement::Text(text) => {
+            MarkdownElement::Text(text) | MarkdownElement::Code(text) => {
                 ret.push_str(text);
             },
+            MarkdownElement::Formatting(_, subfrag) => {
+                let subtext = frag_to_collected_text(subfrag)?;
+                ret.push_str(&subtext);
+            },
+            MarkdownElement::Paragraph(subfrag) => {
+                let subtext = frag_to_collected_text(subfrag)?;
+                ret.push_str(&subtext);
+                ret.push_str("\n\n");
+            },
             _ => {
-                return Err(format!("unknown element type {:?} when collecting text", elem));
+                return Err(RenderError::UnsupportedElement(format!("{} when collecting text", elem), None));
             }
         }
     }
     Ok(ret)
 }
 
-pub fn frag_to_tex(frag: &MarkdownFragment) -> Result<String, String> {
-    let mut ret = String::new();
-    for elem in frag.elements() {
-        match elem {
-            MarkdownElement::BlockQuote(subfrag) => {
-                let subtex = frag_to_tex(subfrag)?;
-                ret.push_str("\\startblockquote\n");
-                ret.push_str(&subtex);
-                ret.push_str("\\stopblockquote\n\n");
-            },
-            MarkdownElement::Code(subfrag) => {
-                // special handling for curly braces
-                let subfrag_escaped = to_typing(subfrag);
-                ret.push_str(&subfrag_escaped);
-            },
-            MarkdownElement::CodeBlock(subfrag) => {
-                let subtex = frag_to_collected_text(subfrag)?;
-                // FIXME: write to file and use \typefile instead?
-                ret.push_str("\\starttyping\n");
-                ret.push_str(&subtex);
-                ret.push_str("\\stoptyping\n\n");
-            },
-            MarkdownElement::Formatting(fmt, subfrag) => {
-                let subtex = frag_to_tex(subfrag)?;
-                match fmt {
-                    MarkdownFormat::Strikethrough => {
-                        ret.push_str("\\overstrike{");
-                        ret.push_str(&subtex);
-                        ret.push_str("}");
-                    },
-                    _ => {
-                        ret.push_str("{");
-                        match fmt {
-                            MarkdownFormat::Emphasis => ret.push_str("\\it "),
-                            MarkdownFormat::Strong => ret.push_str("\\bf "),
-                            _ => {
-                                return Err(format!("unexpected formatting type: {:?}", fmt));
-                            },
-                        }
-                        ret.push_str(&subtex);
-                        ret.push_str("}");
-                    },
-                }
-            },
-            MarkdownElement::Heading(level, subfrag) => {
-                if *level == 1 {
-                    // the heading of this level is already output as part of descending the ToC
-                    continue;
-                }
+/// The longest extension derived from the language of a listing.
+const MAX_LISTING_EXTENSION_LENGTH: usize = 8;
 
-                let subtex = frag_to_tex(subfrag)?;
+fn listing_extension(language: Option<&str>) -> String {
+    let lang = match language {
+        Some(l) => l.to_lowercase(),
+        None => return "txt".to_owned(),
+    };
+    let ext = match lang.as_str() {
+        "rust" => "rs",
+        "python" => "py",
+        "javascript" => "js",
+        "typescript" => "ts",
+        "ruby" => "rb",
+        "shell" | "bash" | "sh" => "sh",
+        "csharp" | "c#" => "cs",
+        "c++" | "cpp" => "cpp",
+        "haskell" => "hs",
+        "markdown" => "md",
+        "text" | "plain" | "" => "txt",
+        other => other,
+    };
+    let sanitized: String = ext.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(MAX_LISTING_EXTENSION_LENGTH)
+        .collect();
+    if sanitized.is_empty() {
+        "txt".to_owned()
+    } else {
+        sanitized
+    }
+}
 
-                ret.push_str("\\");
-                let sub_count = level - 1;
-                for _ in 0..sub_count {
-                    ret.push_str("sub");
-                }
-                ret.push_str("section{");
-                ret.push_str(&subtex);
-                ret.push_str("}\n");
-            },
-            MarkdownElement::Link(url, subfrag) => {
-                let subtex = frag_to_tex(subfrag)?;
-
-                ret.push_str("\\goto{");
-                ret.push_str(&subtex);
-                ret.push_str("}[url(");
-                ret.push_str(url);
-                ret.push_str(")]");
-            },
-            MarkdownElement::Image(url, _subfrag) => {
-                //let subtex = frag_to_tex(subfrag)?;
+/// The path, relative to the output file, of the external listing with the given number.
+///
+/// Listings are numbered sequentially across the whole book, so the names are unique even if
+/// multiple chapters contain listings in the same language. The extension is derived from the
+/// language and only consists of lowercase ASCII letters and digits.
+pub fn listing_path(number: usize, language: Option<&str>) -> String {
+    format!("listings/{:03}.{}", number, listing_extension(language))
+}
 
-                ret.push_str("\\externalfigure[");
-                ret.push_str(url);
-                ret.push_str("]");
-            },
-            MarkdownElement::List(items) => {
-                ret.push_str("\n\\startitemize\n");
-                for item in items {
-                    let subtex = frag_to_tex(item)?;
-
-                    ret.push_str("\\item ");
-                    ret.push_str(&subtex);
-                    ret.push_str("\n");
-                }
-                ret.push_str("\\stopitemize\n");
-            },
-            MarkdownElement::Paragraph(subfrag) => {
-                let subtex = frag_to_tex(subfrag)?;
+/// Writes a code listing to `listings/NNN.ext` next to the output file and returns its path
+/// relative to the output file.
+pub(crate) fn write_external_listing(code: &str, language: Option<&str>, config: &TexConfig) -> Result<String, RenderError> {
+    let number = config.listing_counter.get() + 1;
+    config.listing_counter.set(number);
 
-                ret.push_str(&subtex);
-                ret.push_str("\n\n");
-            },
-            MarkdownElement::Table(table) => {
-                for (i, alignment) in table.alignments().iter().enumerate() {
-                    let align_keyword = match alignment {
-                        'l' => "flushleft",
-                        'r' => "flushright",
-                        'c' => "middle",
-                        _ => "",
-                    };
-                    if align_keyword.is_empty() {
-                        continue;
-                    }
-                    ret.push_str(&format!("\\setupTABLE[c][{}][align={}]\n", i+1, align_keyword));
-                }
-                ret.push_str("\\bTABLE\n");
-                let types_rows = vec![
-                    ("TH", table.header_rows()),
-                    ("TD", table.body_rows()),
-                ];
-                for (t, rows) in types_rows {
-                    for row in rows {
-                        ret.push_str("\\bTR\n");
-                        for col in row {
-                            ret.push_str(&format!("\\b{} ", t));
-                            let coltex = frag_to_tex(col)?;
-                            ret.push_str(&coltex);
-                            ret.push_str(&format!(" \\e{}\n", t));
-                        }
-                        ret.push_str("\\eTR\n");
-                    }
-                }
-                ret.push_str("\\eTABLE\n\n");
-            },
-            MarkdownElement::Text(text) => {
-                let text = educate_tex_quotes(&escape_tex(&text));
-                ret.push_str(&text);
-            },
-            MarkdownElement::HtmlFragment(html) => {
-                let mut mod_html = html.replace("\n", "\n% ");
-                mod_html.insert_str(0, "% ");
-                mod_html.push_str("\n");
-                ret.push_str(&mod_html);
-            },
-            MarkdownElement::FootnoteRef(foot_name) => {
-                ret.push_str("\\note[");
-                ret.push_str(&foot_name);
-                ret.push_str("]");
-            },
-            _ => {
-                return Err(format!("unknown element type {:?}", elem));
+    let rel_path = listing_path(number, language);
+    let mut full_path = config.output_dir().clone();
+    full_path.push(&rel_path);
+
+    if let Some(parent) = full_path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(RenderError::Listing(format!("failed to create listing directory {:?}: {}", parent, err)));
+        }
+    }
+    if let Err(err) = fs::write(&full_path, code) {
+        return Err(RenderError::Listing(format!("failed to write listing {:?}: {}", full_path, err)));
+    }
+
+    Ok(rel_path)
+}
+
+/// Copies a local image into `assets/` next to the output file if assets are being copied, and
+/// returns the path under which to reference it. Other images are referenced as they are.
+pub(crate) fn asset_path(url: &str, config: &TexConfig) -> Result<String, RenderError> {
+    if !config.copy_assets || url.contains("://") || url.starts_with("data:") {
+        return Ok(url.to_owned());
+    }
+
+    let source = config.source_dir.borrow().join(percent_decode(url));
+    if !source.is_file() {
+        config.warn(format!("image {:?} not found; referencing it as it is", source));
+        return Ok(url.to_owned());
+    }
+    if let Some(rel_path) = config.copied_assets.borrow().get(&source) {
+        return Ok(rel_path.clone());
+    }
+
+    // images from different directories may share a file name
+    let file_name = source.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut rel_path = format!("assets/{}", file_name);
+    let mut counter = 1;
+    while config.copied_assets.borrow().values().any(|p| *p == rel_path) {
+        counter += 1;
+        rel_path = match file_name.rfind('.') {
+            Some(dot) => format!("assets/{}-{}{}", &file_name[..dot], counter, &file_name[dot..]),
+            None => format!("assets/{}-{}", file_name, counter),
+        };
+    }
+
+    let full_path = config.output_dir().join(&rel_path);
+    if let Some(parent) = full_path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(RenderError::Asset(format!("failed to create asset directory {:?}: {}", parent, err)));
+        }
+    }
+    if let Err(err) = fs::copy(&source, &full_path) {
+        return Err(RenderError::Asset(format!("failed to copy image {:?} to {:?}: {}", source, full_path, err)));
+    }
+
+    config.copied_assets.borrow_mut().insert(source, rel_path.clone());
+    Ok(rel_path)
+}
+
+fn named_entity(name: &str) -> Option<char> {
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{A0}',
+        "shy" => '\u{AD}',
+        "thinsp" => '\u{2009}',
+        "ensp" => '\u{2002}',
+        "emsp" => '\u{2003}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "deg" => '°',
+        "plusmn" => '±',
+        "times" => '×',
+        "divide" => '÷',
+        "micro" => 'µ',
+        "sect" => '§',
+        "para" => '¶',
+        "middot" => '·',
+        "bull" => '•',
+        "hellip" => '…',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "sbquo" => '‚',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "bdquo" => '„',
+        "laquo" => '«',
+        "raquo" => '»',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "auml" => 'ä',
+        "ouml" => 'ö',
+        "uuml" => 'ü',
+        "Auml" => 'Ä',
+        "Ouml" => 'Ö',
+        "Uuml" => 'Ü',
+        "szlig" => 'ß',
+        "eacute" => 'é',
+        "egrave" => 'è',
+        "aacute" => 'á',
+        "agrave" => 'à',
+        "ccedil" => 'ç',
+        "ntilde" => 'ñ',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Decodes HTML entities (the common named ones as well as numeric ones) in text that has not
+/// passed through the Markdown parser, which decodes them itself. Unknown entities are kept as
+/// they are.
+pub fn decode_entities(text: &str) -> String {
+    ENTITY_RE.replace_all(text, |caps: &Captures| {
+        let decoded = if let Some(dec) = caps.get(1) {
+            dec.as_str().parse::<u32>().ok().and_then(std::char::from_u32)
+        } else if let Some(hex) = caps.get(2) {
+            u32::from_str_radix(hex.as_str(), 16).ok().and_then(std::char::from_u32)
+        } else {
+            named_entity(caps.get(3).unwrap().as_str())
+        };
+        match decoded {
+            Some(c) => c.to_string(),
+            None => caps.get(0).unwrap().as_str().to_owned(),
+        }
+    }).into_owned()
+}
+
+pub fn text_to_tex(text: &str, config: &TexConfig) -> String {
+    if !*config.smart_quotes() {
+        return escape_tex(text, config);
+    }
+    // educate first so that the quotes in escapes such as \char"XXXX are left alone
+    escape_tex(&educate_tex_quotes(text), config)
+}
+
+/// If the element following a table is a paragraph starting with `Table:`, returns the text
+/// after `Table:` in its first text element and the paragraph's remaining elements.
+pub(crate) fn table_caption_parts(next_elem: Option<&MarkdownElement>) -> Option<(&str, &[MarkdownElement])> {
+    let paragraph = match next_elem {
+        Some(MarkdownElement::Paragraph(p)) => p,
+        _ => return None,
+    };
+    let first_text = match paragraph.elements().first() {
+        Some(MarkdownElement::Text(t)) => t,
+        _ => return None,
+    };
+    let caption_start = first_text.strip_prefix("Table:")?;
+    Some((caption_start.trim_start(), &paragraph.elements()[1..]))
+}
+
+/// Returns the term of an `<!-- index: term -->` marker.
+pub(crate) fn index_term(html: &str) -> Option<&str> {
+    INDEX_COMMENT_RE.captures(html).map(|caps| caps.get(1).unwrap().as_str())
+}
+
+/// Returns the number of columns of a `<!-- columns: n -->` marker.
+pub(crate) fn columns_start(html: &str) -> Option<u32> {
+    COLUMNS_COMMENT_RE.captures(html)
+        .and_then(|caps| caps.get(1).unwrap().as_str().parse().ok())
+        .filter(|n| *n > 0)
+}
+
+/// Checks whether the HTML is an `<!-- endcolumns -->` marker.
+pub(crate) fn is_columns_end(html: &str) -> bool {
+    END_COLUMNS_COMMENT_RE.is_match(html)
+}
+
+/// Turns raw HTML into TeX comments, prefixing every line so that none of it reaches the output.
+pub fn tex_comment(html: &str) -> String {
+    let mut ret = String::with_capacity(html.len() + 8);
+    for line in html.trim_end_matches(&['\r', '\n'][..]).split('\n') {
+        ret.push_str("% ");
+        ret.push_str(line.trim_end_matches('\r'));
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Passes an assembled TeX document through the post-processors of the configuration, in the
+/// order in which they were added. This is the extension point for library users who need to
+/// adjust the output without changing the renderers; without post-processors, the document is
+/// returned unchanged.
+pub fn post_process(tex: String, config: &TexConfig) -> String {
+    config.post_processors().iter().fold(tex, |tex, post_processor| post_processor(tex))
+}
+
+pub fn frag_to_tex(frag: &MarkdownFragment, config: &TexConfig) -> Result<String, RenderError> {
+    config.forget_preceding_text();
+    ContextRenderer::new(config).render(frag)
+}
+
+/// Checks whether a block quote is a GitHub-style alert, i.e. starts with a line consisting of
+/// `[!NOTE]`, `[!TIP]`, `[!IMPORTANT]`, `[!WARNING]` or `[!CAUTION]`. Returns the title of the
+/// alert and the remaining elements of its first paragraph.
+pub(crate) fn admonition(quote: &MarkdownFragment) -> Option<(&'static str, &[MarkdownElement])> {
+    let first_para = match quote.elements().first() {
+        Some(MarkdownElement::Paragraph(p)) => p.elements(),
+        _ => return None,
+    };
+
+    // the marker is split into multiple text nodes; collect them up to the first line break
+    let mut marker = String::new();
+    let mut rest_index = first_para.len();
+    for (i, elem) in first_para.iter().enumerate() {
+        match elem {
+            MarkdownElement::Text(t) if t == "\n" => {
+                rest_index = i + 1;
+                break;
             },
+            MarkdownElement::Text(t) => marker.push_str(t),
+            _ => return None,
         }
     }
-    Ok(ret)
+
+    let title = match marker.trim() {
+        "[!NOTE]" => "Note",
+        "[!TIP]" => "Tip",
+        "[!IMPORTANT]" => "Important",
+        "[!WARNING]" => "Warning",
+        "[!CAUTION]" => "Caution",
+        _ => return None,
+    };
+    Some((title, &first_para[rest_index..]))
 }
@@ -3,12 +3,57 @@ use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::path::{Path, PathBuf};
 
-use crate::md_ast::{self, MarkdownElement};
-use crate::texutil::frag_to_tex;
+use lazy_static::lazy_static;
+use regex::Regex;
 
+use crate::context::ContextRenderer;
+use crate::md_ast::{self, MarkdownElement, MarkdownFragment};
+use crate::render::{RenderError, Renderer};
+use crate::texutil::{decode_entities, is_language_code, section_command, subject_command, TexConfig};
 
+
+/// Information about the book as a whole, taken from the front matter of the ToC file.
+///
+/// The values are already converted to TeX.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BookMetadata {
+    author: Option<String>,
+    date: Option<String>,
+    language: Option<String>,
+}
+impl BookMetadata {
+    pub fn new() -> BookMetadata {
+        BookMetadata {
+            author: None,
+            date: None,
+            language: None,
+        }
+    }
+
+    accessor_opt!(author, str);
+    accessor_opt!(date, str);
+
+    // the code of the main language of the book, such as `de`
+    accessor_opt!(language, str);
+}
+impl Default for BookMetadata {
+    fn default() -> Self {
+        BookMetadata::new()
+    }
+}
+
+
+lazy_static! {
+    static ref TAG_COMMENT_RE: Regex = Regex::new("^\\s*<!--\\s*([A-Za-z0-9_-]+)\\s*-->\\s*$").unwrap();
+    static ref SHORT_TITLE_COMMENT_RE: Regex = Regex::new("^\\s*<!--\\s*short:\\s*(.*?)\\s*-->\\s*$").unwrap();
+    static ref TAG_BRACES_RE: Regex = Regex::new("^\\s*\\{([A-Za-z0-9_-]+)\\}\\s*$").unwrap();
+}
+
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TableOfContents {
     title: String,
+    metadata: BookMetadata,
     front_matter_sections: Vec<TOCEntry>,
     body_matter_sections: Vec<TOCEntry>,
     appendix_sections: Vec<TOCEntry>,
@@ -18,6 +63,7 @@ impl TableOfContents {
     pub fn new(title: &str) -> TableOfContents {
         TableOfContents {
             title: title.to_owned(),
+            metadata: BookMetadata::new(),
             front_matter_sections: Vec::new(),
             body_matter_sections: Vec::new(),
             appendix_sections: Vec::new(),
@@ -26,20 +72,33 @@ impl TableOfContents {
     }
 
     accessor!(title, str);
+    accessor_and_mut!(metadata, metadata_mut, BookMetadata);
     accessor_and_mut!(front_matter_sections, front_matter_sections_mut, Vec<TOCEntry>);
     accessor_and_mut!(body_matter_sections, body_matter_sections_mut, Vec<TOCEntry>);
     accessor_and_mut!(appendix_sections, appendix_sections_mut, Vec<TOCEntry>);
     accessor_and_mut!(back_matter_sections, back_matter_sections_mut, Vec<TOCEntry>);
+
+    pub fn all_sections_mut(&mut self) -> Vec<&mut Vec<TOCEntry>> {
+        vec![
+            &mut self.front_matter_sections,
+            &mut self.body_matter_sections,
+            &mut self.appendix_sections,
+            &mut self.back_matter_sections,
+        ]
+    }
 }
 
-#[derive(Eq, Ord)]
+#[derive(Clone, Eq, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum TOCLevel {
     Part,
     Chapter,
     Section(u32),
 }
 impl TOCLevel {
-    fn num_value(&self) -> u32 {
+    /// The depth of the level in the table of contents: 0 for a part, 1 for a chapter, 2 for a
+    /// section, 3 for a subsection and so on. Levels are ordered by their depth.
+    pub fn depth(&self) -> u32 {
         match self {
             TOCLevel::Part => 0,
             TOCLevel::Chapter => 1,
@@ -47,36 +106,54 @@ impl TOCLevel {
         }
     }
 
-    pub fn tex_string(&self) -> String {
+    pub fn is_part(&self) -> bool {
+        matches!(self, TOCLevel::Part)
+    }
+
+    pub fn is_chapter(&self) -> bool {
+        matches!(self, TOCLevel::Chapter)
+    }
+
+    /// Whether the level is a section at any depth below a chapter.
+    pub fn is_section(&self) -> bool {
+        matches!(self, TOCLevel::Section(_))
+    }
+
+    /// The number of `sub` prefixes of the section command for second-level Markdown headings
+    /// within a section at this level.
+    pub fn heading_depth(&self) -> u32 {
+        match self {
+            TOCLevel::Part | TOCLevel::Chapter => 0,
+            TOCLevel::Section(i) => i + 1,
+        }
+    }
+
+    pub fn tex_string(&self, numbered: bool) -> String {
         match self {
             TOCLevel::Part => "part".to_owned(),
-            TOCLevel::Chapter => "chapter".to_owned(),
-            TOCLevel::Section(i) => {
-                let mut sect = String::new();
-                for _ in 0..*i {
-                    sect.push_str("sub");
-                }
-                sect.push_str("section");
-                sect
-            },
+            TOCLevel::Chapter => if numbered { "chapter" } else { "title" }.to_owned(),
+            TOCLevel::Section(i) => if numbered { section_command(*i) } else { subject_command(*i) },
         }
     }
 }
 impl PartialEq for TOCLevel {
     fn eq(&self, other: &TOCLevel) -> bool {
-        self.num_value() == other.num_value()
+        self.depth() == other.depth()
     }
 }
 impl PartialOrd for TOCLevel {
     fn partial_cmp(&self, other: &TOCLevel) -> Option<Ordering> {
-        self.num_value().partial_cmp(&other.num_value())
+        self.depth().partial_cmp(&other.depth())
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TOCEntry {
     level: TOCLevel,
     title: String,
+    short_title: Option<String>,
     path: Option<PathBuf>,
+    tags: Vec<String>,
     child_entries: Vec<TOCEntry>,
 }
 impl TOCEntry {
@@ -84,7 +161,9 @@ impl TOCEntry {
         TOCEntry {
             level,
             title: title.as_ref().to_owned(),
+            short_title: None,
             path: Some(path.as_ref().to_path_buf()),
+            tags: vec![],
             child_entries: vec![],
         }
     }
@@ -93,15 +172,84 @@ impl TOCEntry {
         TOCEntry {
             level,
             title: title.as_ref().to_owned(),
+            short_title: None,
             path: None,
+            tags: vec![],
             child_entries: vec![],
         }
     }
 
     accessor!(level, TOCLevel);
     accessor!(title, str);
+
+    // the shorter title used in running headers, already converted to TeX
+    accessor_opt!(short_title, str);
+
     accessor_opt!(path, Path);
+    accessor_and_mut!(tags, tags_mut, Vec<String>);
     accessor_and_mut!(child_entries, child_entries_mut, Vec<TOCEntry>);
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    fn has_tag_in_subtree(&self, tag: &str) -> bool {
+        self.has_tag(tag) || self.child_entries.iter().any(|c| c.has_tag_in_subtree(tag))
+    }
+}
+
+
+/// Keeps only the entries tagged with `tag`, along with their children and the entries leading
+/// up to them.
+pub fn retain_tagged(entries: &mut Vec<TOCEntry>, tag: &str) {
+    entries.retain(|e| e.has_tag_in_subtree(tag));
+    for entry in entries.iter_mut() {
+        if !entry.has_tag(tag) {
+            retain_tagged(&mut entry.child_entries, tag);
+        }
+    }
+}
+
+/// Removes the entries tagged with `tag`, along with their children.
+pub fn remove_tagged(entries: &mut Vec<TOCEntry>, tag: &str) {
+    entries.retain(|e| !e.has_tag(tag));
+    for entry in entries.iter_mut() {
+        remove_tagged(&mut entry.child_entries, tag);
+    }
+}
+
+/// Replaces the entries with the entry whose path is `path`, along with its children, wherever it
+/// is in the hierarchy. Returns whether such an entry was found; if not, no entries remain.
+pub fn retain_subtree(entries: &mut Vec<TOCEntry>, path: &Path) -> bool {
+    let mut found = None;
+    let mut stack: Vec<TOCEntry> = entries.drain(..).rev().collect();
+    while let Some(entry) = stack.pop() {
+        if entry.path() == Some(path) {
+            found = Some(entry);
+            break;
+        }
+        stack.extend(entry.child_entries.into_iter().rev());
+    }
+    entries.extend(found);
+    !entries.is_empty()
+}
+
+/// Returns the tag if the element is a tag marker following a ToC link, i.e. `<!-- tag -->` or
+/// `{tag}`.
+fn entry_tag(elem: &MarkdownElement) -> Option<String> {
+    let caps = match elem {
+        MarkdownElement::HtmlFragment(html) => TAG_COMMENT_RE.captures(html),
+        MarkdownElement::Text(text) => TAG_BRACES_RE.captures(text),
+        _ => None,
+    };
+    caps.map(|c| c.get(1).unwrap().as_str().to_owned())
+}
+
+fn is_blank_text(elem: &MarkdownElement) -> bool {
+    match elem {
+        MarkdownElement::Text(text) => text.trim().is_empty(),
+        _ => false,
+    }
 }
 
 
@@ -126,38 +274,95 @@ impl Error for TOCLoadError {
 }
 
 
-fn links_to_toc<'a, E: IntoIterator<Item = &'a MarkdownElement>>(frag: E, section_level: u32) -> Result<Vec<TOCEntry>, String> {
+/// Decodes `%XX` escapes in a link target. Sequences that do not decode to valid UTF-8 are kept
+/// as they are.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len()
+                && bytes[i+1].is_ascii_hexdigit() && bytes[i+2].is_ascii_hexdigit() {
+            let hex = std::str::from_utf8(&bytes[i+1..i+3]).unwrap();
+            decoded.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+            continue;
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| text.to_owned())
+}
+
+/// Renders a title in the ToC, which does not continue any preceding text.
+fn render_title(frag: &MarkdownFragment, renderer: &dyn Renderer, config: &TexConfig) -> Result<String, RenderError> {
+    config.forget_preceding_text();
+    renderer.render(frag)
+}
+
+/// Converts the links in a ToC list to entries; `depth` is 0 for the top-level entries, which are
+/// chapters, while the entries nested within them are sections.
+fn links_to_toc<'a, E: IntoIterator<Item = &'a MarkdownElement>>(frag: E, depth: u32, renderer: &dyn Renderer, config: &TexConfig) -> Result<Vec<TOCEntry>, String> {
+    let level = if depth == 0 { TOCLevel::Chapter } else { TOCLevel::Section(depth - 1) };
+
     let mut entries = Vec::new();
     for elem in frag {
         match elem {
             MarkdownElement::Link(url, title_frag) => {
-                let title_tex = frag_to_tex(&title_frag)?;
+                let title_tex = render_title(title_frag, renderer, config)
+                    .map_err(|err| err.to_string())?;
                 entries.push(TOCEntry::new(
-                    TOCLevel::Section(section_level),
+                    level.clone(),
                     title_tex,
-                    url,
+                    percent_decode(url),
                 ));
             },
-            MarkdownElement::List(items) => {
+            MarkdownElement::List(_, items) => {
                 // last entry has subentries
                 let last_entry = match entries.last_mut() {
                     Some(e) => e,
                     None => {
-                        return Err("sublist without an entry".to_owned());
+                        // most likely, a level was skipped; point at the entry that was nested too deeply
+                        let nested = items.iter()
+                            .find_map(|item| links_to_toc(item.elements(), depth + 1, renderer, config).ok()?.into_iter().next());
+                        return Err(match nested {
+                            Some(e) => format!("ToC entry {:?} is nested more than one level deeper than its parent", e.title()),
+                            None => "sublist without an entry".to_owned(),
+                        });
                     },
                 };
 
                 for subitem in items {
-                    let mut sub_entries = links_to_toc(subitem.elements(), section_level + 1)?;
+                    let mut sub_entries = links_to_toc(subitem.elements(), depth + 1, renderer, config)?;
                     last_entry.child_entries_mut().append(&mut sub_entries);
                 }
             },
             MarkdownElement::Paragraph(subfrag) => {
-                let mut sub_entries = links_to_toc(subfrag.elements(), section_level)?;
+                let mut sub_entries = links_to_toc(subfrag.elements(), depth, renderer, config)?;
                 entries.append(&mut sub_entries);
             },
+            other if is_blank_text(other) => {},
+            MarkdownElement::HtmlFragment(html) if SHORT_TITLE_COMMENT_RE.is_match(html) => {
+                let last_entry = match entries.last_mut() {
+                    Some(e) => e,
+                    None => {
+                        return Err(format!("short title without an entry: {}", elem));
+                    },
+                };
+                let short_title = SHORT_TITLE_COMMENT_RE.captures(html).unwrap().get(1).unwrap().as_str();
+                last_entry.short_title = Some(renderer.render_text(&decode_entities(short_title)));
+            },
+            other if entry_tag(other).is_some() => {
+                let last_entry = match entries.last_mut() {
+                    Some(e) => e,
+                    None => {
+                        return Err(format!("tag without an entry: {}", other));
+                    },
+                };
+                last_entry.tags_mut().push(entry_tag(other).unwrap());
+            },
             _ => {
-                return Err(format!("unexpected TOC list item: {:?}", elem));
+                return Err(format!("unexpected TOC list item: {}", elem));
             },
         }
     }
@@ -165,12 +370,40 @@ fn links_to_toc<'a, E: IntoIterator<Item = &'a MarkdownElement>>(frag: E, sectio
 }
 
 
-pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
+/// The ToC file names that are tried if the requested one does not exist.
+const TOC_FALLBACK_NAMES: [&str; 3] = ["SUMMARY.md", "TOC.md", "index.md"];
+
+
+pub fn find_toc_file(book_path: &str, toc_file_name: &str) -> Result<PathBuf, TOCLoadError> {
+    let mut names = vec![toc_file_name];
+    names.extend(TOC_FALLBACK_NAMES.iter().filter(|n| **n != toc_file_name));
+
+    for name in &names {
+        let mut toc_path: PathBuf = PathBuf::new();
+        toc_path.push(book_path);
+        toc_path.push(name);
+        if toc_path.is_file() {
+            return Ok(toc_path);
+        }
+    }
+
+    Err(TOCLoadError::new(format!(
+        "no {} found in {:?} (also tried {}); is this a book directory?",
+        toc_file_name, book_path, names[1..].join(", "),
+    )))
+}
+
+
+/// Loads the table of contents, rendering its titles and metadata for ConTeXt.
+pub fn load_toc(book_path: &str, toc_file_name: &str, config: &TexConfig) -> Result<TableOfContents, TOCLoadError> {
+    load_toc_with_renderer(book_path, toc_file_name, &ContextRenderer::new(config), config)
+}
+
+/// Loads the table of contents, rendering its titles and metadata with the given renderer.
+pub fn load_toc_with_renderer(book_path: &str, toc_file_name: &str, renderer: &dyn Renderer, config: &TexConfig) -> Result<TableOfContents, TOCLoadError> {
     // load the table of contents
-    let mut toc_path: PathBuf = PathBuf::new();
-    toc_path.push(book_path);
-    toc_path.push("SUMMARY.md");
-    let toc_frag = match md_ast::load(&toc_path) {
+    let toc_path = find_toc_file(book_path, toc_file_name)?;
+    let (toc_frag, front_matter) = match md_ast::load_with_front_matter(&toc_path, *config.input_encoding()) {
         Ok(ast) => ast,
         Err(err) => return Err(TOCLoadError::new(format!(
             "failed to parse TOC: {}", err,
@@ -179,14 +412,14 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
 
     let mut title = String::new();
     let mut front_matter_done = false;
-    let mut front_matter_sections = Vec::new();
+    let mut front_matter_sections: Vec<TOCEntry> = Vec::new();
     let mut body_sections = Vec::new();
-    let mut back_matter_sections = Vec::new();
+    let mut back_matter_sections: Vec<TOCEntry> = Vec::new();
     let mut current_part: Option<TOCEntry> = None;
     for elem in toc_frag.elements() {
         match elem {
             MarkdownElement::Heading(1, frag) => {
-                title = match frag_to_tex(&frag) {
+                title = match render_title(frag, renderer, config) {
                     Ok(t) => t,
                     Err(err) => {
                         return Err(TOCLoadError::new(format!(
@@ -200,7 +433,7 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
                     body_sections.push(cp);
                 }
 
-                let part_title = match frag_to_tex(&frag) {
+                let part_title = match render_title(frag, renderer, config) {
                     Ok(t) => t,
                     Err(err) => {
                         return Err(TOCLoadError::new(format!(
@@ -220,21 +453,39 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
                         MarkdownElement::Link(_, _) => {
                             links_to_toc(
                                 vec![parelem],
-                                0
+                                0,
+                                renderer,
+                                config,
                             )
                         },
-                        MarkdownElement::List(items) => {
+                        MarkdownElement::List(_, items) => {
                             links_to_toc(
                                 items.iter().flat_map(|frag| frag.elements()),
-                                0
+                                0,
+                                renderer,
+                                config,
                             )
                         },
-                        MarkdownElement::Text(s) if s == "\n" => {
+                        other if is_blank_text(other) => {
+                            Ok(vec![])
+                        },
+                        other if entry_tag(other).is_some() => {
+                            let matter_sections = if front_matter_done {
+                                &mut back_matter_sections
+                            } else {
+                                &mut front_matter_sections
+                            };
+                            match matter_sections.last_mut() {
+                                Some(e) => e.tags_mut().push(entry_tag(other).unwrap()),
+                                None => return Err(TOCLoadError::new(format!(
+                                    "tag without an entry: {}", other,
+                                ))),
+                            };
                             Ok(vec![])
                         },
                         _ => {
                             return Err(TOCLoadError::new(format!(
-                                "unexpected TOC paragraph item: {:?}", parelem,
+                                "unexpected TOC paragraph item: {}", parelem,
                             )));
                         },
                     };
@@ -253,12 +504,12 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
                     }
                 }
             },
-            MarkdownElement::List(entries) => {
+            MarkdownElement::List(_, entries) => {
                 // front matter are paragraphs before the first list
                 front_matter_done = true;
 
                 for entry in entries {
-                    let mut toc_elems = match links_to_toc(entry.elements(), 0) {
+                    let mut toc_elems = match links_to_toc(entry.elements(), 0, renderer, config) {
                         Ok(els) => els,
                         Err(err) => {
                             return Err(TOCLoadError::new(format!(
@@ -275,7 +526,7 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
             },
             _ => {
                 return Err(TOCLoadError::new(format!(
-                    "unexpected TOC item: {:?}", elem,
+                    "unexpected TOC item: {}", elem,
                 )));
             }
         }
@@ -285,7 +536,29 @@ pub fn load_toc(book_path: &str) -> Result<TableOfContents, TOCLoadError> {
         body_sections.push(cp);
     }
 
+    if front_matter_sections.is_empty() && body_sections.is_empty() && back_matter_sections.is_empty() {
+        // an empty book would only yield a document ConTeXt chokes on
+        return Err(TOCLoadError::new(format!(
+            "TOC {:?} does not reference any sections", toc_path,
+        )));
+    }
+
+    // a language passed on the command line overrides the one in the front matter
+    let language = match (config.language(), front_matter.get("language")) {
+        (Some(cl), _) => Some(cl.clone()),
+        (None, Some(fml)) if is_language_code(fml.trim()) => Some(fml.trim().to_owned()),
+        (None, Some(fml)) => {
+            return Err(TOCLoadError::new(format!(
+                "TOC {:?} has an invalid language code {:?}", toc_path, fml,
+            )));
+        },
+        (None, None) => None,
+    };
+
     let mut toc = TableOfContents::new(&title);
+    toc.metadata_mut().author = front_matter.get("author").map(|a| renderer.render_text(&decode_entities(a)));
+    toc.metadata_mut().date = front_matter.get("date").map(|d| renderer.render_text(&decode_entities(d)));
+    toc.metadata_mut().language = language;
     toc.front_matter_sections_mut().append(&mut front_matter_sections);
     toc.body_matter_sections_mut().append(&mut body_sections);
     toc.back_matter_sections_mut().append(&mut back_matter_sections);
@@ -0,0 +1,38 @@
+use std::env;
+use std::fs;
+
+use md_context::md_ast;
+use md_context::texutil::{frag_to_tex, TexConfig};
+
+
+#[test]
+fn copies_local_images() {
+    let mut base_dir = env::temp_dir();
+    base_dir.push(format!("md-context-assets-{}", std::process::id()));
+    let source_dir = base_dir.join("src");
+    let output_dir = base_dir.join("out");
+    fs::create_dir_all(source_dir.join("a")).unwrap();
+    fs::create_dir_all(source_dir.join("b")).unwrap();
+    fs::write(source_dir.join("a").join("pic.png"), b"first").unwrap();
+    fs::write(source_dir.join("b").join("pic.png"), b"second").unwrap();
+
+    let md = "![](a/pic.png) ![](b/pic.png) ![](a/pic.png) ![](https://example.com/remote.png)\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    *config.copy_assets_mut() = true;
+    *config.output_dir_mut() = output_dir.clone();
+    config.set_source_dir(&source_dir);
+    let tex = frag_to_tex(&frag, &config);
+    let first = fs::read(output_dir.join("assets").join("pic.png"));
+    let second = fs::read(output_dir.join("assets").join("pic-2.png"));
+    fs::remove_dir_all(&base_dir).ok();
+
+    assert_eq!(
+        tex.unwrap(),
+        "\\externalfigure[assets/pic.png] \\externalfigure[assets/pic-2.png] \\externalfigure[assets/pic.png] \\externalfigure[https://example.com/remote.png]\n\n",
+    );
+    assert_eq!(first.unwrap(), b"first");
+    assert_eq!(second.unwrap(), b"second");
+}
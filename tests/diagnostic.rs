@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use md_context::diagnostic::Diagnostic;
+use md_context::encoding::InputEncoding;
+use md_context::md_ast;
+
+
+#[test]
+fn json_format() {
+    let diag = Diagnostic::warning("skipped \"odd\" construct\n").with_file(Path::new("dir/a b.md"));
+    assert_eq!(
+        diag.to_json(),
+        "{\"severity\":\"warning\",\"file\":\"dir/a b.md\",\"offset\":null,\"message\":\"skipped \\\"odd\\\" construct\\n\"}",
+    );
+    assert_eq!(diag.to_string(), "warning: dir/a b.md: skipped \"odd\" construct\n");
+}
+
+#[test]
+fn parse_errors_carry_offsets() {
+    let md = "First paragraph.\n\n<!-- /div -->\n";
+    let parser = pulldown_cmark::Parser::new(md);
+    let err = md_ast::parse_with_offsets(parser).unwrap_err();
+    assert!(err.offset().is_some());
+
+    let (_, _) = md_ast::parse_str("---\ntitle: x\n---\nok\n", Path::new("."), InputEncoding::Utf8).unwrap();
+    let err = md_ast::parse_str("---\ntitle: x\n---\n<!-- /div -->\n", Path::new("."), InputEncoding::Utf8)
+        .unwrap_err()
+        .in_file(Path::new("chapter.md"));
+    let diag = Diagnostic::from(err);
+    assert_eq!(diag.file(), Some(Path::new("chapter.md")));
+    // the offending marker follows the 17 bytes of front matter
+    assert_eq!(diag.offset(), Some(17));
+}
+
+#[test]
+fn offsets_refer_to_the_source_file() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures");
+    let offset = |md: &str| md_ast::parse_str(md, &fixtures, InputEncoding::Utf8).unwrap_err().offset();
+
+    // the markers replacing fenced div lines are longer than the lines
+    assert_eq!(offset("::: note\nText\n:::\n\n<!-- /div -->\n"), Some(19));
+    assert_eq!(offset("---\ntitle: x\n---\nIntro\n\n::: warning\nText\n"), Some(24));
+    assert_eq!(offset("Intro\n\n:::\n"), Some(7));
+
+    // so is included text
+    let directive = "{{#include includes/shared.md}}";
+    assert_eq!(offset(&format!("{}\n\n:::\n", directive)), Some(directive.len() + 2));
+    assert_eq!(offset(&format!("{}\n\n<!-- /div -->\n", directive)), Some(directive.len() + 2));
+    assert_eq!(offset("Text\n\n{{#include includes/missing.md}}\n"), Some(6));
+}
+
+#[test]
+fn human_format() {
+    let diag = Diagnostic::error("failed to parse").with_file(Path::new("chapter.md"));
+    assert_eq!(diag.to_string(), "error: chapter.md: failed to parse");
+    assert_eq!(diag.to_human(true), "\x1b[1;31merror\x1b[0m: chapter.md: failed to parse");
+
+    let diag = Diagnostic::error("unsupported construct").with_file(Path::new("chapter.md")).with_offset(Some(42));
+    assert_eq!(diag.to_human(false), "error: chapter.md:42: unsupported construct");
+
+    let diag = Diagnostic::warning("odd construct");
+    assert_eq!(diag.to_human(false), "warning: odd construct");
+    assert_eq!(diag.to_human(true), "\x1b[1;33mwarning\x1b[0m: odd construct");
+}
@@ -0,0 +1,106 @@
+use std::io::Write;
+
+use md_context::encoding::{EncodingWriter, InputEncoding, OutputEncoding};
+
+
+#[test]
+fn decode_utf8() {
+    assert_eq!(InputEncoding::Utf8.decode("Grüße".as_bytes()).unwrap(), "Grüße");
+    assert_eq!(InputEncoding::Utf8.decode(b"\xEF\xBB\xBFtext").unwrap(), "text");
+
+    let err = InputEncoding::Utf8.decode(b"Gr\xFC\xDFe").unwrap_err();
+    assert!(err.contains("not valid UTF-8"), "{}", err);
+    assert!(err.contains("offset 2"), "{}", err);
+    assert!(err.contains("UTF-16 or Latin-1"), "{}", err);
+
+    let err = InputEncoding::Utf8.decode(b"\xFF\xFEh\x00i\x00").unwrap_err();
+    assert!(err.contains("appears to be UTF-16"), "{}", err);
+}
+
+#[test]
+fn decode_utf16() {
+    assert_eq!(InputEncoding::Utf16.decode(b"\xFF\xFEh\x00\xFC\x00").unwrap(), "hü");
+    assert_eq!(InputEncoding::Utf16.decode(b"\xFE\xFF\x00h\x00\xFC").unwrap(), "hü");
+    assert_eq!(InputEncoding::Utf16.decode(b"h\x00i\x00").unwrap(), "hi");
+    assert!(InputEncoding::Utf16.decode(b"h\x00i").is_err());
+    assert!(InputEncoding::Utf16.decode(b"\x00\xD8").is_err());
+}
+
+#[test]
+fn decode_single_byte() {
+    assert_eq!(InputEncoding::Latin1.decode(b"Gr\xFC\xDFe \x93").unwrap(), "Grüße \u{93}");
+    assert_eq!(InputEncoding::Windows1252.decode(b"\x93Gr\xFC\xDFe\x94 \x80 \x81").unwrap(), "\u{201C}Grüße\u{201D} € \u{81}");
+}
+
+#[test]
+fn encoding_names() {
+    assert_eq!(InputEncoding::from_name("UTF-8"), Some(InputEncoding::Utf8));
+    assert_eq!(InputEncoding::from_name("utf-16"), Some(InputEncoding::Utf16));
+    assert_eq!(InputEncoding::from_name("cp1252"), Some(InputEncoding::Windows1252));
+    assert_eq!(InputEncoding::from_name("ebcdic"), None);
+}
+
+fn literal_markers() -> Vec<(String, String)> {
+    vec![("\\starttyping".to_owned(), "\\stoptyping".to_owned())]
+}
+
+fn encode(encoding: OutputEncoding, escape_non_latin1: bool, pieces: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut writer = EncodingWriter::new(&mut out, encoding, escape_non_latin1, literal_markers(), vec!["\\type".to_owned()]);
+        for piece in pieces {
+            writer.write_all(piece).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+    out
+}
+
+#[test]
+fn write_latin1() {
+    assert_eq!(encode(OutputEncoding::Latin1, false, &["Grüße — ok".as_bytes()]), b"Gr\xFC\xDFe {\\char\"2014} ok");
+}
+
+#[test]
+fn write_escaped() {
+    assert_eq!(encode(OutputEncoding::Utf8, false, &["ü—".as_bytes()]), "ü—".as_bytes());
+    assert_eq!(encode(OutputEncoding::Utf8, true, &["ü—".as_bytes()]), "ü{\\char\"2014}".as_bytes());
+}
+
+#[test]
+fn write_split_character() {
+    let dash = "—".as_bytes();
+    assert_eq!(encode(OutputEncoding::Utf8, false, &[b"a", &dash[..1], &dash[1..], b"b"]), "a—b".as_bytes());
+    assert_eq!(encode(OutputEncoding::Latin1, false, &[&dash[..2], &dash[2..]]), b"{\\char\"2014}");
+
+    let mut out = Vec::new();
+    let mut writer = EncodingWriter::new(&mut out, OutputEncoding::Utf8, false, Vec::new(), Vec::new());
+    assert!(writer.write(b"a\xFFb").is_err());
+}
+
+#[test]
+fn write_literal_material() {
+    let tex = "a → b \\type{x → y} → \\type+{→}+ →\n  \\starttyping\n  f → g\n  \\stoptyping\n→";
+    assert_eq!(
+        String::from_utf8(encode(OutputEncoding::Utf8, true, &[tex.as_bytes()])).unwrap(),
+        "a {\\char\"2192} b \\type{x → y} {\\char\"2192} \\type+{→}+ {\\char\"2192}\n  \\starttyping\n  f → g\n  \\stoptyping\n{\\char\"2192}",
+    );
+
+    // a line split by a flush is continued correctly
+    let (first, second) = tex.split_at(tex.find("y}").unwrap());
+    let mut out = Vec::new();
+    {
+        let mut writer = EncodingWriter::new(&mut out, OutputEncoding::Utf8, true, literal_markers(), vec!["\\type".to_owned()]);
+        writer.write_all(first.as_bytes()).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(second.as_bytes()).unwrap();
+        writer.flush().unwrap();
+    }
+    assert_eq!(out, encode(OutputEncoding::Utf8, true, &[tex.as_bytes()]));
+
+    // Latin-1 cannot represent the arrow in code, and escaping it would change the code
+    let mut out = Vec::new();
+    let mut writer = EncodingWriter::new(&mut out, OutputEncoding::Latin1, false, literal_markers(), Vec::new());
+    let err = writer.write_all("\\starttyping\nf → g\n".as_bytes()).unwrap_err();
+    assert!(err.to_string().contains("U+2192"), "{}", err);
+}
@@ -0,0 +1,9 @@
+use std::io;
+
+// ANCHOR: main
+fn main() {
+    // ANCHOR: body
+    println!("hello");
+    // ANCHOR_END: body
+}
+// ANCHOR_END: main
@@ -0,0 +1,62 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use md_context::md_ast;
+use md_context::texutil::{frag_to_tex, TexConfig};
+
+
+/// Set this environment variable to regenerate the golden `.tex` files instead of comparing.
+const BLESS_VAR: &str = "MD_CONTEXT_BLESS";
+
+
+fn fixtures_dir() -> PathBuf {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("tests");
+    dir.push("fixtures");
+    dir
+}
+
+fn check_fixture(md_path: &Path, bless: bool) -> Result<(), String> {
+    let frag = md_ast::load(md_path)
+        .map_err(|e| format!("{:?}: failed to load: {}", md_path, e))?;
+    let tex = frag_to_tex(&frag, &TexConfig::new())
+        .map_err(|e| format!("{:?}: failed to convert: {}", md_path, e))?;
+
+    let tex_path = md_path.with_extension("tex");
+    if bless {
+        fs::write(&tex_path, &tex)
+            .map_err(|e| format!("{:?}: failed to write: {}", tex_path, e))?;
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(&tex_path)
+        .map_err(|e| format!("{:?}: failed to read: {}", tex_path, e))?;
+    if tex != expected {
+        return Err(format!(
+            "{:?}: output differs from golden file\n--- expected ---\n{}\n--- actual ---\n{}",
+            md_path, expected, tex,
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn golden_fixtures() {
+    let bless = env::var_os(BLESS_VAR).is_some();
+
+    let mut md_paths: Vec<PathBuf> = fs::read_dir(fixtures_dir())
+        .expect("failed to list fixtures")
+        .map(|entry| entry.expect("failed to read fixture entry").path())
+        .filter(|path| path.extension().map(|ext| ext == "md").unwrap_or(false))
+        .collect();
+    md_paths.sort();
+    assert!(!md_paths.is_empty(), "no fixtures found");
+
+    let failures: Vec<String> = md_paths.iter()
+        .filter_map(|path| check_fixture(path, bless).err())
+        .collect();
+    if !failures.is_empty() {
+        panic!("{} fixture(s) failed (set {} to regenerate):\n\n{}", failures.len(), BLESS_VAR, failures.join("\n\n"));
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::PathBuf;
+
+use md_context::encoding::OutputEncoding;
+use md_context::latex::{escape_latex, LatexRenderer};
+use md_context::md_ast;
+use md_context::render::Renderer;
+use md_context::texutil::TexConfig;
+use md_context::toc::load_toc_with_renderer;
+
+
+fn render(md: &str) -> String {
+    let mut parser = pulldown_cmark::Parser::new_ext(md, pulldown_cmark::Options::ENABLE_TABLES);
+    let frag = md_ast::parse(&mut parser).unwrap();
+    let config = TexConfig::new();
+    LatexRenderer::new(&config).render(&frag).unwrap()
+}
+
+#[test]
+fn escaping() {
+    let config = TexConfig::new();
+    assert_eq!(escape_latex("50% of R&D_costs ~ $5 #1 {x} ^ \\", &config), "50\\% of R\\&D\\_costs \\textasciitilde{} \\$5 \\#1 \\{x\\} \\textasciicircum{} \\textbackslash{}");
+}
+
+#[test]
+fn inline_formatting() {
+    assert_eq!(
+        render("Some *emphasis*, **strength**, `co_de` and [a link](https://example.com/#top).\n"),
+        "Some \\emph{emphasis}, \\textbf{strength}, \\texttt{co\\_de} and \\href{https://example.com/\\#top}{a link}.\n\n",
+    );
+}
+
+#[test]
+fn block_elements() {
+    assert_eq!(
+        render("## Sub\n\n3. three\n4. four\n\n> quoted\n\n```\nx_y\n```\n\n| a | b |\n|:-:|--:|\n| c | d |\n"),
+        concat!(
+            "\\subsection{Sub}\n",
+            "\n\\begin{enumerate}\n\\setcounter{enumi}{2}\n\\item three\n\\item four\n\\end{enumerate}\n",
+            "\\begin{quote}\nquoted\n\n\\end{quote}\n\n",
+            "\\begin{verbatim}\nx_y\n\\end{verbatim}\n\n",
+            "\\begin{tabular}{cr}\n\\textbf{a} & \\textbf{b} \\\\\nc & d \\\\\n\\end{tabular}\n\n",
+        ),
+    );
+}
+
+#[test]
+fn numbered_floats() {
+    let mut parser = pulldown_cmark::Parser::new("![Overview](o.png)\n\nAs [Figure](#fig:overview) shows.\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+    let mut config = TexConfig::new();
+    *config.number_floats_mut() = true;
+    assert_eq!(
+        LatexRenderer::new(&config).render(&frag).unwrap(),
+        "\\begin{figure}[h]\n\\centering\n\\includegraphics{o.png}\n\\caption{Overview}\n\\label{fig:overview}\n\\end{figure}\n\n\nAs Figure~\\ref{fig:overview} shows.\n\n",
+    );
+}
+
+#[test]
+fn image_attributes() {
+    assert_eq!(
+        render("![A cat](cat.png){width=50% align=center}\n"),
+        "\\begin{center}\n\\includegraphics[width=0.5\\textwidth]{cat.png}\n\\end{center}\n\n",
+    );
+}
+
+#[test]
+fn reference_images() {
+    assert_eq!(
+        render("![A cat][cat] and ![nothing][none]\n\n[cat]: cat.png\n[none]: <>\n"),
+        "\\includegraphics{cat.png} and nothing\n\n",
+    );
+}
+
+#[test]
+fn empty_link_destinations() {
+    assert_eq!(render("A [draft]() and [another](<>).\n"), "A draft and another.\n\n");
+
+    // the warning must not fail for content that has no plain text
+    assert_eq!(render("[![alt](a.png)]()\n"), "\\includegraphics{a.png}\n\n");
+    assert!(render("[<kbd>x</kbd>]()\n").contains('x'));
+    assert!(render("![the <kbd>x</kbd> key]()\n").contains("key"));
+}
+
+#[test]
+fn toc_titles() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("special_titles");
+
+    let config = TexConfig::new();
+    let renderer = LatexRenderer::new(&config);
+    let toc = load_toc_with_renderer(book_dir.to_str().unwrap(), "SUMMARY.md", &renderer, &config)
+        .unwrap_or_else(|err| panic!("failed to load ToC: {}", err));
+    assert_eq!(toc.title(), "The R\\&D\\_x \\texttt{code} Book");
+
+    let chapter = &toc.body_matter_sections()[0];
+    assert_eq!(chapter.title(), "R\\&D\\_x and \\emph{more}");
+    assert_eq!(chapter.short_title(), Some("R\\&D\\_x"));
+
+    let preamble = renderer.begin_document(&toc, OutputEncoding::Utf8);
+    assert!(preamble.contains("\\title{The R\\&D\\_x \\texttt{code} Book}"), "{}", preamble);
+    assert!(preamble.contains("\\author{R\\&D\\_x Team}"), "{}", preamble);
+    assert!(!preamble.contains("\\type"), "{}", preamble);
+}
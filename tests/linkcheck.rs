@@ -0,0 +1,33 @@
+use md_context::linkcheck::{figure_label, float_reference, slugify, table_label};
+use md_context::md_ast;
+
+
+#[test]
+fn heading_slugs() {
+    assert_eq!(slugify("Getting Started"), "getting-started");
+    assert_eq!(slugify("What's new in 1.2?"), "whats-new-in-12");
+    assert_eq!(slugify("  snake_case and-hyphens "), "snake_case-and-hyphens");
+}
+
+#[test]
+fn float_labels() {
+    let mut parser = pulldown_cmark::Parser::new("![The *big* picture](big.png)\n\nTable: Results, 2020\n\nNo caption\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+    let paragraphs: Vec<_> = frag.elements().iter()
+        .map(|e| match e {
+            md_ast::MarkdownElement::Paragraph(p) => p,
+            _ => panic!("unexpected {}", e),
+        })
+        .collect();
+
+    match paragraphs[0].elements().first() {
+        Some(md_ast::MarkdownElement::Image(_, alt, _)) => assert_eq!(figure_label(alt).as_deref(), Some("fig:the-big-picture")),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(table_label(paragraphs[1]).as_deref(), Some("tab:results-2020"));
+    assert_eq!(table_label(paragraphs[2]), None);
+
+    assert_eq!(float_reference("#fig:x"), Some("fig:x"));
+    assert_eq!(float_reference("#intro"), None);
+    assert_eq!(float_reference("other.md#fig:x"), None);
+}
@@ -0,0 +1,208 @@
+use md_context::encoding::InputEncoding;
+use md_context::md_ast::{self, walk, Attributes, CodeInfo, MarkdownElement, MarkdownFragment, MarkdownTable, MarkdownVisitor};
+
+
+#[derive(Default)]
+struct Counter {
+    texts: Vec<String>,
+    links: Vec<String>,
+    headings: usize,
+    code_blocks: usize,
+}
+impl MarkdownVisitor for Counter {
+    fn visit_text(&mut self, text: &str) {
+        self.texts.push(text.to_owned());
+    }
+
+    fn visit_link(&mut self, url: &str, _content: &MarkdownFragment) {
+        self.links.push(url.to_owned());
+    }
+
+    fn visit_heading(&mut self, _level: u32, _content: &MarkdownFragment) {
+        self.headings += 1;
+    }
+
+    fn visit_code_block(&mut self, _language: Option<&str>, _content: &MarkdownFragment) {
+        self.code_blocks += 1;
+    }
+}
+
+#[test]
+fn visitor_walks_nested_elements() {
+    let md = "# Title\n\n> *a [link](x.md)*\n\n- [other](y.md)\n\n```\ncode\n```\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut counter = Counter::default();
+    walk(&frag, &mut counter);
+    assert_eq!(counter.headings, 1);
+    assert_eq!(counter.links, vec!["x.md".to_owned(), "y.md".to_owned()]);
+    assert_eq!(counter.code_blocks, 1);
+    assert_eq!(counter.texts, vec!["Title".to_owned(), "a ".to_owned(), "link".to_owned(), "other".to_owned()]);
+}
+
+#[test]
+fn element_display() {
+    let table = MarkdownElement::Table(MarkdownTable::new(vec![' '], vec![vec![MarkdownFragment::new(vec![])]], Vec::<Vec<MarkdownFragment>>::new()));
+    assert_eq!(table.to_string(), "table");
+    assert_eq!(MarkdownElement::Code("x = 1".to_owned()).to_string(), "inline code \"x = 1\"");
+
+    let long_text = MarkdownElement::Text("a".repeat(50));
+    assert_eq!(long_text.to_string(), format!("text {:?}...", "a".repeat(40)));
+}
+
+#[test]
+fn info_string_attributes() {
+    let plain = CodeInfo::parse("rust");
+    assert_eq!(plain.language(), Some("rust"));
+    assert!(plain.attributes().is_empty());
+
+    let comma = CodeInfo::parse("rust,ignore,should_panic");
+    assert_eq!(comma.language(), Some("rust"));
+    assert_eq!(comma.attributes(), &vec!["ignore".to_owned(), "should_panic".to_owned()]);
+
+    let space = CodeInfo::parse("python {.line-numbers startFrom=10}");
+    assert_eq!(space.language(), Some("python"));
+    assert_eq!(space.attributes(), &vec![".line-numbers".to_owned(), "startFrom=10".to_owned()]);
+
+    let pandoc = CodeInfo::parse("{.haskell .numberLines}");
+    assert_eq!(pandoc.language(), Some("haskell"));
+    assert_eq!(pandoc.attributes(), &vec![".numberLines".to_owned()]);
+
+    assert_eq!(CodeInfo::parse("").language(), None);
+}
+
+#[test]
+fn fenced_code_block_language() {
+    let md = "```rust,ignore\nfn main() {}\n```\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+    match &frag.elements()[0] {
+        MarkdownElement::CodeBlock(info, _) => {
+            assert_eq!(info.language(), Some("rust"));
+            assert_eq!(info.attributes(), &vec!["ignore".to_owned()]);
+        },
+        other => panic!("unexpected element {}", other),
+    }
+}
+
+#[test]
+fn malformed_table_error() {
+    use pulldown_cmark::{Alignment, Event, Tag};
+
+    // a table nested in a body row, which pulldown-cmark never produces by itself
+    let events = vec![
+        Event::Start(Tag::Table(vec![Alignment::None, Alignment::None])),
+        Event::Start(Tag::TableHead),
+        Event::Start(Tag::TableCell),
+        Event::Text("a".into()),
+        Event::End(Tag::TableCell),
+        Event::Start(Tag::TableCell),
+        Event::Text("b".into()),
+        Event::End(Tag::TableCell),
+        Event::End(Tag::TableHead),
+        Event::Start(Tag::TableRow),
+        Event::Start(Tag::TableCell),
+        Event::Text("1".into()),
+        Event::End(Tag::TableCell),
+        Event::Start(Tag::Table(vec![Alignment::None])),
+    ];
+    let err = md_ast::parse(&mut events.into_iter()).err().expect("malformed table parsed successfully");
+    assert_eq!(
+        err.to_string(),
+        "unexpected start of a table in body row 1 (after 1 cell(s)) of a table with 2 column(s); a table row may only contain cells, and tables cannot be nested",
+    );
+
+    let events = vec![
+        Event::Start(Tag::Table(vec![Alignment::None])),
+        Event::Text("stray".into()),
+    ];
+    let err = md_ast::parse(&mut events.into_iter()).err().expect("malformed table parsed successfully");
+    assert_eq!(err.to_string(), "unexpected text \"stray\" between the rows of a table with 1 column(s)");
+}
+
+#[test]
+fn attribute_parsing() {
+    let (attrs, rest) = Attributes::parse_prefix("{#fig .wide width=50% caption=\"A cat\"} and more").unwrap();
+    assert_eq!(rest, " and more");
+    assert_eq!(attrs.id(), Some("fig"));
+    assert_eq!(attrs.classes(), &vec!["wide".to_owned()]);
+    assert_eq!(attrs.get("width"), Some("50%"));
+    assert_eq!(attrs.get("caption"), Some("A cat"));
+    assert_eq!(attrs.get("height"), None);
+
+    assert!(Attributes::parse_prefix("{}").unwrap().0.is_empty());
+    assert!(Attributes::parse_prefix(" {width=1cm}").is_none());
+    assert!(Attributes::parse_prefix("{width=1cm").is_none());
+    assert!(Attributes::parse_prefix("{not attributes}").is_none());
+}
+
+#[test]
+fn image_attributes_attached() {
+    let md = "![cat](cat.png){width=50%} purrs\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+    let para = match &frag.elements()[0] {
+        MarkdownElement::Paragraph(p) => p,
+        other => panic!("unexpected element {}", other),
+    };
+    match para.elements().as_slice() {
+        [MarkdownElement::Image(url, _, attrs), MarkdownElement::Text(rest)] => {
+            assert_eq!(url, "cat.png");
+            assert_eq!(attrs.get("width"), Some("50%"));
+            assert_eq!(rest, " purrs");
+        },
+        other => panic!("unexpected elements {:?}", other),
+    }
+}
+
+#[test]
+fn parallel_loading_keeps_order() {
+    let mut fixtures_dir = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    fixtures_dir.push("tests");
+    fixtures_dir.push("fixtures");
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(&fixtures_dir)
+        .expect("failed to list fixtures")
+        .map(|entry| entry.expect("failed to read fixture entry").path())
+        .filter(|path| path.extension().map(|e| e == "md").unwrap_or(false))
+        .collect();
+    paths.sort();
+    paths.insert(1, fixtures_dir.join("does-not-exist.md"));
+
+    let loaded = md_ast::load_all_with_front_matter(&paths, 3, InputEncoding::Utf8);
+    assert_eq!(loaded.len(), paths.len());
+    for (path, result) in paths.iter().zip(&loaded) {
+        match (result, md_ast::load_with_front_matter(path, InputEncoding::Utf8)) {
+            (Ok(parallel), Ok(sequential)) => assert_eq!(format!("{:?}", parallel), format!("{:?}", sequential)),
+            (Err(_), Err(_)) => {},
+            _ => panic!("parallel and sequential loading of {:?} disagree", path),
+        }
+    }
+    assert!(loaded[1].is_err());
+}
+
+#[test]
+fn conditional_content() {
+    let md = "Always.\n\n<!-- if print -->\n\nPrint only.\n\n<!-- if color -->\n\nColor print.\n\n<!-- endif -->\n\n<!-- endif -->\n\n<!-- if ebook -->\n\nEbook only.\n\n<!-- endif -->\n\n> Quoted <!-- if ebook -->[link](https://example.com/)<!-- endif -->text.\n";
+    let texts = |profiles: &[&str]| -> Vec<String> {
+        let mut parser = pulldown_cmark::Parser::new(md);
+        let frag = md_ast::parse(&mut parser).unwrap();
+        let profiles: Vec<String> = profiles.iter().map(|p| (*p).to_owned()).collect();
+        let frag = md_ast::apply_conditionals(frag, &profiles).unwrap();
+        let mut counter = Counter::default();
+        walk(&frag, &mut counter);
+        counter.texts
+    };
+
+    assert_eq!(texts(&[]), vec!["Always.", "Quoted ", "text."]);
+    assert_eq!(texts(&["print"]), vec!["Always.", "Print only.", "Quoted ", "text."]);
+    assert_eq!(texts(&["print", "color"]), vec!["Always.", "Print only.", "Color print.", "Quoted ", "text."]);
+    assert_eq!(texts(&["color"]), vec!["Always.", "Quoted ", "text."]);
+    assert_eq!(texts(&["ebook"]), vec!["Always.", "Ebook only.", "Quoted ", "link", "text."]);
+
+    for broken in &["<!-- if print -->\n\nNever closed.\n", "Closed <!-- endif --> too often.\n"] {
+        let mut parser = pulldown_cmark::Parser::new(broken);
+        let frag = md_ast::parse(&mut parser).unwrap();
+        assert!(md_ast::apply_conditionals(frag, &[]).is_err(), "{:?}", broken);
+    }
+}
@@ -0,0 +1,126 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use md_context::encoding::InputEncoding;
+use md_context::md_ast::{self, MarkdownElement, MarkdownFragment};
+
+
+fn parse(md: &str) -> MarkdownFragment {
+    let (frag, _front_matter) = md_ast::parse_str(md, Path::new("."), InputEncoding::Utf8)
+        .unwrap_or_else(|e| panic!("failed to parse {:?}: {}", md, e));
+    frag
+}
+
+/// Merges adjacent text elements, which the parser splits at escapes and entities, and forgets
+/// source positions, which change when the text is rewritten.
+fn merge_texts(frag: &mut MarkdownFragment) {
+    let mut merged: Vec<MarkdownElement> = Vec::new();
+    for mut elem in frag.elements_mut().drain(..) {
+        match &mut elem {
+            MarkdownElement::Text(text) => {
+                if let Some(MarkdownElement::Text(prev)) = merged.last_mut() {
+                    prev.push_str(text);
+                    continue;
+                }
+            },
+            MarkdownElement::Heading(_, content) | MarkdownElement::Paragraph(content)
+                | MarkdownElement::Link(_, content) | MarkdownElement::Image(_, content, _)
+                | MarkdownElement::BlockQuote(content) | MarkdownElement::CodeBlock(_, content)
+                | MarkdownElement::Formatting(_, content) | MarkdownElement::Div(_, content) => {
+                merge_texts(content);
+            },
+            MarkdownElement::Unsupported(_, range) => *range = None,
+            MarkdownElement::List(_, items) => items.iter_mut().for_each(merge_texts),
+            MarkdownElement::Table(table) => {
+                table.header_rows_mut().iter_mut().flatten().for_each(merge_texts);
+                table.body_rows_mut().iter_mut().flatten().for_each(merge_texts);
+            },
+            _ => {},
+        }
+        merged.push(elem);
+    }
+    *frag.elements_mut() = merged;
+}
+
+/// Checks that serializing the AST and parsing the result yields the same AST (up to how text
+/// is split), and that serializing is stable.
+fn check_round_trip(md: &str, frag: &mut MarkdownFragment) {
+    let written = md_ast::to_markdown(frag);
+    let mut reparsed = parse(&written);
+    assert_eq!(md_ast::to_markdown(&reparsed), written);
+
+    merge_texts(frag);
+    merge_texts(&mut reparsed);
+    assert_eq!(
+        format!("{:#?}", reparsed), format!("{:#?}", frag),
+        "AST changed in round trip of\n{}\n--- written as ---\n{}", md, written,
+    );
+}
+
+fn round_trip(md: &str) -> String {
+    let mut frag = parse(md);
+    let written = md_ast::to_markdown(&frag);
+    check_round_trip(md, &mut frag);
+    written
+}
+
+
+#[test]
+fn normalizes_markers() {
+    assert_eq!(round_trip("Title\n=====\n\n* one\n* two\n\n___\n"), "# Title\n\n- one\n- two\n\n***\n");
+    assert_eq!(round_trip("1) a\n1) b\n"), "1. a\n2. b\n");
+    assert_eq!(round_trip(""), "");
+}
+
+#[test]
+fn round_trips_elements() {
+    let docs = [
+        "# Heading with *emphasis* and `code`\n\nSome **strong** and ~~struck~~ text\nover two lines.\n",
+        "Multi-line\nsetext heading\n-----------------\n",
+        "A [link](https://example.com/a_(b)) and ![an image](figures/x.png){#fig .wide width=50%}.\n",
+        "Empty [link]() and <https://example.com> autolink.\n",
+        "- tight\n- list\n  1. nested\n  2. ordered\n- end\n",
+        "3. starts\n4. at three\n\n- first list\n\n* second list\n",
+        "- loose\n\n  with two paragraphs\n\n- list\n",
+        "> quoted\n>\n> > nested\n>\n> - list in quote\n",
+        "```rust,ignore\nfn main() {\n    println!(\"```\");\n}\n```\n\n    indented code\n",
+        "```{.python .numberLines startFrom=3}\nprint(1)\n```\n\n```\n```\n",
+        "| left | center | right | none |\n|:-----|:------:|------:|------|\n| a \\| b | `c` | *d* | |\n",
+        "<!-- a comment\nover lines -->\n\n<div>\nraw\n</div>\n\nInline <span>html</span> and a line  \nbreak.\n",
+        "::: note\nInside a *div*.\n:::\n",
+    ];
+    for doc in &docs {
+        round_trip(doc);
+    }
+}
+
+#[test]
+fn escapes_markdown_syntax_in_text() {
+    let docs = [
+        "\\# not a heading\n\n\\- not a list\n\n1\\. not a list either\n",
+        "Literal \\*stars\\*, \\_underscores\\_, \\`backticks\\` and \\[brackets\\].\n",
+        "A \\<tag> and an &amp;entity; and wow\\![link](x) and \\\\ backslash.\n",
+        "Heading text\n\\===\n\n\\> not a quote\n\n\\::: not a div\n",
+        "# C\\#\n\n\\{\\{#include x}} and braces\n",
+    ];
+    for doc in &docs {
+        round_trip(doc);
+    }
+}
+
+#[test]
+fn round_trips_fixtures() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("tests");
+    dir.push("fixtures");
+
+    let md_paths = fs::read_dir(&dir)
+        .expect("failed to list fixtures")
+        .map(|entry| entry.expect("failed to read fixture entry").path())
+        .filter(|path| path.extension().map(|e| e == "md").unwrap_or(false));
+    for md_path in md_paths {
+        let md = fs::read_to_string(&md_path).unwrap();
+        let mut frag = md_ast::load(&md_path).unwrap();
+        check_round_trip(&md, &mut frag);
+    }
+}
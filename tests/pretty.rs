@@ -0,0 +1,80 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use md_context::md_ast;
+use md_context::pretty::PrettyWriter;
+use md_context::texutil::{frag_to_tex, TexConfig};
+
+
+fn prettify(tex: &str, indent_width: usize, wrap_column: Option<usize>) -> String {
+    let mut output = Vec::new();
+    {
+        let mut writer = PrettyWriter::new(&mut output, indent_width, wrap_column, vec![("\\starttyping".to_owned(), "\\stoptyping".to_owned())]);
+        write!(writer, "{}", tex).unwrap();
+        writer.finish().unwrap();
+    }
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn indents_nested_environments() {
+    let tex = "\\startitemize\n\\startitem\nitem\n\\stopitem\n\\stopitemize\n";
+    assert_eq!(
+        prettify(tex, 2, None),
+        "\\startitemize\n  \\startitem\n    item\n  \\stopitem\n\\stopitemize\n",
+    );
+}
+
+#[test]
+fn leaves_verbatim_alone() {
+    let tex = "\\startblockquote\n\\starttyping\n  indented code\n\\stoptyping\n\\stopblockquote\n";
+    assert_eq!(
+        prettify(tex, 2, Some(10)),
+        "\\startblockquote\n  \\starttyping\n  indented code\n  \\stoptyping\n\\stopblockquote\n",
+    );
+}
+
+#[test]
+fn wraps_outside_braces() {
+    assert_eq!(
+        prettify("one two \\type{three four} five\n", 0, Some(8)),
+        "one two\n\\type{three four}\nfive\n",
+    );
+}
+
+#[test]
+fn passes_through_by_default() {
+    let tex = "  \\startitemize  \nunfinished";
+    assert_eq!(prettify(tex, 0, None), tex);
+}
+
+#[test]
+fn collapses_blank_lines() {
+    let mut md_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    md_path.push("tests");
+    md_path.push("fixtures");
+    md_path.push("lists.md");
+    let frag = md_ast::load(&md_path).unwrap();
+    let tex = frag_to_tex(&frag, &TexConfig::new()).unwrap();
+
+    let pretty_tex = prettify(&format!("\n\\section{{Lists}}\n{}\n\n\\stopbodymatter\n", tex), 0, None);
+    assert!(!pretty_tex.contains("\n\n\n"), "blank line run in {:?}", pretty_tex);
+
+    // except in verbatim environments
+    let code = "\\starttyping\na\n\n\nb\n\\stoptyping\n";
+    assert_eq!(prettify(code, 0, None), code);
+}
+
+#[test]
+fn keeps_incomplete_lines_until_finished() {
+    let mut output = Vec::new();
+    let mut writer = PrettyWriter::new(&mut output, 2, None, Vec::new());
+    let umlaut = "\\startitemize\nü".as_bytes();
+    writer.write_all(&umlaut[..umlaut.len()-1]).unwrap();
+    writer.flush().unwrap();
+    writer.write_all(&umlaut[umlaut.len()-1..]).unwrap();
+    writer.write_all(b"\n    \\stopitemize").unwrap();
+    writer.flush().unwrap();
+    writer.finish().unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "\\startitemize\n  ü\n\\stopitemize");
+}
@@ -0,0 +1,109 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+
+fn convert(book_dir: &PathBuf, out_file: &PathBuf) -> Vec<u8> {
+    let status = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("--no-timestamp")
+        .arg(book_dir)
+        .arg(out_file)
+        .status()
+        .expect("failed to run md-context");
+    assert!(status.success(), "md-context failed with {}", status);
+    fs::read(out_file).expect("failed to read output")
+}
+
+#[test]
+fn output_is_deterministic() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("simple");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-reproducible-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).expect("failed to create output directory");
+
+    let first = convert(&book_dir, &out_dir.join("first.tex"));
+    let second = convert(&book_dir, &out_dir.join("second.tex"));
+    fs::remove_dir_all(&out_dir).ok();
+
+    assert!(!first.is_empty());
+    assert!(first == second, "two conversions of the same book differ");
+}
+
+#[test]
+fn creates_missing_output_directory() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("simple");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-missing-dir-{}", std::process::id()));
+    let output = convert(&book_dir, &out_dir.join("build").join("book.tex"));
+    fs::remove_dir_all(&out_dir).ok();
+
+    assert!(!output.is_empty());
+}
+
+#[test]
+fn no_clobber_keeps_existing_output() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("simple");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-no-clobber-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).expect("failed to create output directory");
+    let out_file = out_dir.join("book.tex");
+    fs::write(&out_file, "precious\n").expect("failed to write output");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("--no-clobber")
+        .arg(&book_dir)
+        .arg(&out_file)
+        .status()
+        .expect("failed to run md-context");
+    let contents = fs::read_to_string(&out_file).expect("failed to read output");
+    fs::remove_dir_all(&out_dir).ok();
+
+    assert!(!status.success());
+    assert_eq!(contents, "precious\n");
+}
+
+#[test]
+fn project_has_component_per_chapter() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("simple");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-project-{}", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("--project")
+        .arg(&book_dir)
+        .arg(out_dir.join("book.tex"))
+        .status()
+        .expect("failed to run md-context");
+    let read = |name: &str| fs::read_to_string(out_dir.join(name)).unwrap_or_default();
+    let product = read("book.tex");
+    let project = read("project.tex");
+    let component = read("component_02.tex");
+    let environment = read("environment.tex");
+    fs::remove_dir_all(&out_dir).ok();
+
+    assert!(status.success(), "md-context failed with {}", status);
+    assert!(product.contains("\\startproduct book\n\\project project\n"), "unexpected product: {}", product);
+    assert!(product.contains("\\component component_01\n"));
+    assert!(product.contains("\\component component_03\n"));
+    assert!(project.contains("\\environment environment\n"));
+    assert!(project.contains("\\product book\n"));
+    assert!(component.contains("\\startcomponent component_02\n"));
+    assert!(component.contains("\\chapter{Getting Started}"));
+    assert!(environment.contains("\\startenvironment environment\n"));
+}
@@ -0,0 +1,21 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+
+#[test]
+fn converts_stdin_to_stdout() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("-")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run md-context");
+    child.stdin.take().unwrap()
+        .write_all(b"Some *emphasis* and `code`.\n")
+        .expect("failed to write input");
+    let output = child.wait_with_output().expect("failed to wait for md-context");
+
+    assert!(output.status.success(), "md-context failed with {}", output.status);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Some {\\it emphasis} and \\type{code}.\n\n");
+}
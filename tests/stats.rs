@@ -0,0 +1,17 @@
+use md_context::md_ast;
+use md_context::stats::Statistics;
+
+
+#[test]
+fn counts_words_and_elements() {
+    let md = "# A *heading*\n\nSome **bold**ly written text.\n\n- one\n- two\n\n| a | b |\n|---|---|\n| c | d |\n\n![alt text](image.png)\n\n```\nnot counted\n```\n";
+    let mut parser = pulldown_cmark::Parser::new_ext(md, pulldown_cmark::Options::ENABLE_TABLES);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let stats = Statistics::of_fragment(&frag);
+    // heading (2) + paragraph (4) + list (2) + table (4) + alt text (2)
+    assert_eq!(*stats.words(), 14);
+    assert_eq!(*stats.figures(), 1);
+    assert_eq!(*stats.tables(), 1);
+    assert_eq!(*stats.code_blocks(), 1);
+}
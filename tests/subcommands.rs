@@ -0,0 +1,161 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+
+fn simple_book() -> PathBuf {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("simple");
+    book_dir
+}
+
+#[test]
+fn build_is_the_default() {
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-build-default-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).expect("failed to create output directory");
+
+    let implicit_file = out_dir.join("implicit.tex");
+    let implicit = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("--no-timestamp")
+        .arg(simple_book())
+        .arg(&implicit_file)
+        .status()
+        .expect("failed to run md-context");
+    let explicit_file = out_dir.join("explicit.tex");
+    let explicit = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("build")
+        .arg("--no-timestamp")
+        .arg(simple_book())
+        .arg(&explicit_file)
+        .status()
+        .expect("failed to run md-context");
+    let implicit_output = fs::read(&implicit_file).expect("failed to read implicit output");
+    let explicit_output = fs::read(&explicit_file).expect("failed to read explicit output");
+    fs::remove_dir_all(&out_dir).ok();
+
+    assert!(implicit.success(), "md-context failed with {}", implicit);
+    assert!(explicit.success(), "md-context build failed with {}", explicit);
+    assert!(implicit_output == explicit_output, "build output differs from default output");
+}
+
+#[test]
+fn check_writes_no_output() {
+    let output = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("check")
+        .arg(simple_book())
+        .output()
+        .expect("failed to run md-context");
+
+    assert!(output.status.success(), "md-context check failed with {}", output.status);
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn check_fails_on_missing_book() {
+    let output = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("check")
+        .arg(simple_book().join("does-not-exist"))
+        .output()
+        .expect("failed to run md-context");
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("failed to load TOC"));
+}
+
+#[test]
+fn list_section_files() {
+    let output = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("dump-toc")
+        .arg("--list-files")
+        .arg(simple_book())
+        .output()
+        .expect("failed to run md-context");
+
+    assert!(output.status.success(), "md-context dump-toc failed with {}", output.status);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 3);
+    assert!(stdout.lines().all(|line| line.ends_with(".md")));
+}
+
+#[test]
+fn check_reports_missing_images() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("images");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("check")
+        .arg(book_dir)
+        .output()
+        .expect("failed to run md-context");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("chapter.md: image \"figures/missing.png\" not found"), "{}", stderr);
+    assert!(!stderr.contains("present.svg"), "{}", stderr);
+    assert!(!stderr.contains("remote.png"), "{}", stderr);
+}
+
+#[test]
+fn index_entries_in_back_matter_complete_the_index() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("back_matter_index");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-back-matter-index-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).expect("failed to create output directory");
+
+    for (backend, expected) in &[("context", "\\completeindex"), ("latex", "\\printindex")] {
+        let out_file = out_dir.join(format!("{}.tex", backend));
+        let status = Command::new(env!("CARGO_BIN_EXE_md-context"))
+            .arg("build")
+            .arg("--backend")
+            .arg(backend)
+            .arg(&book_dir)
+            .arg(&out_file)
+            .status()
+            .expect("failed to run md-context");
+        let output = fs::read_to_string(&out_file).unwrap_or_default();
+
+        assert!(status.success(), "md-context failed with {}", status);
+        assert!(output.contains(expected), "no {} in {}", expected, output);
+    }
+    fs::remove_dir_all(&out_dir).ok();
+}
+
+#[test]
+fn json_failures_are_one_object_per_line() {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push("broken");
+
+    let mut out_dir = env::temp_dir();
+    out_dir.push(format!("md-context-json-failures-{}", std::process::id()));
+    fs::create_dir_all(&out_dir).expect("failed to create output directory");
+    let output = Command::new(env!("CARGO_BIN_EXE_md-context"))
+        .arg("build")
+        .arg("--keep-going")
+        .arg("--error-format")
+        .arg("json")
+        .arg(&book_dir)
+        .arg(out_dir.join("book.tex"))
+        .output()
+        .expect("failed to run md-context");
+    fs::remove_dir_all(&out_dir).ok();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("1 section(s) failed"), "{}", stderr);
+    assert!(stderr.contains("unclosed.md"), "{}", stderr);
+    for line in stderr.lines() {
+        assert!(line.starts_with('{') && line.ends_with('}'), "not a JSON object: {:?}", line);
+    }
+}
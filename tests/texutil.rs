@@ -0,0 +1,332 @@
+use std::path::Path;
+
+use md_context::context::ContextRenderer;
+use md_context::encoding::{InputEncoding, OutputEncoding};
+use md_context::md_ast;
+use md_context::render::{RenderError, Renderer};
+use md_context::texutil::{
+    decode_entities, escape_tex, frag_to_tex, image_dimension, listing_path, parse_codepoint_range, post_process, section_command, subject_command, to_typing, CommandNames, TexConfig,
+};
+use md_context::toc::TableOfContents;
+
+
+#[test]
+fn section_command_depths() {
+    assert_eq!(section_command(0), "section");
+    assert_eq!(section_command(1), "subsection");
+    assert_eq!(section_command(2), "subsubsection");
+    assert_eq!(section_command(3), "subsubsubsection");
+}
+
+#[test]
+fn subject_command_depths() {
+    assert_eq!(subject_command(0), "subject");
+    assert_eq!(subject_command(1), "subsubject");
+    assert_eq!(subject_command(2), "subsubsubject");
+}
+
+#[test]
+fn command_map_overrides() {
+    let mut names = CommandNames::new();
+    names.load_overrides("[commands]\n# house style\nblockquote = \"quotation\"\ntype = \"\\\\mono\"\n")
+        .expect("failed to load overrides");
+    assert_eq!(names.blockquote(), "quotation");
+    assert_eq!(names.type_inline(), "mono");
+    assert_eq!(names.itemize(), "itemize");
+
+    assert!(names.load_overrides("unknown = \"x\"").is_err());
+    assert!(names.load_overrides("goto = unquoted").is_err());
+}
+
+#[test]
+fn configured_escapes() {
+    let mut config = TexConfig::new();
+    assert_eq!(escape_tex("a\u{FFFD}b", &config), "a{\\char\"FFFD}b");
+    assert_eq!(escape_tex("\u{2003}", &config), "\u{2003}");
+
+    config.escaped_chars_mut().push(('\u{00A0}', '\u{00A0}'));
+    config.escaped_chars_mut().push(('\u{2000}', '\u{200A}'));
+    assert_eq!(escape_tex("a\u{00A0}b", &config), "a{\\char\"00A0}b");
+    assert_eq!(escape_tex("\u{2003}", &config), "{\\char\"2003}");
+
+    // neither a following hex digit nor a following space may be taken up by the number
+    assert_eq!(escape_tex("Page\u{00A0}1", &config), "Page{\\char\"00A0}1");
+    assert_eq!(escape_tex("\u{00A0}B", &config), "{\\char\"00A0}B");
+    assert_eq!(escape_tex("\u{FFFD} x", &config), "{\\char\"FFFD} x");
+}
+
+#[test]
+fn codepoint_ranges() {
+    assert_eq!(parse_codepoint_range("U+00A0"), Ok(('\u{00A0}', '\u{00A0}')));
+    assert_eq!(parse_codepoint_range("U+2000-U+200A"), Ok(('\u{2000}', '\u{200A}')));
+    assert!(parse_codepoint_range("U+200A-U+2000").is_err());
+    assert!(parse_codepoint_range("nonsense").is_err());
+    assert!(parse_codepoint_range("U+00A0-").is_err());
+}
+
+#[test]
+fn unicode_spaces() {
+    let mut config = TexConfig::new();
+    assert_eq!(escape_tex("a\u{00A0}b\u{2009}c\u{202F}d", &config), "a~b\\,c\\,d");
+
+    // a configured escape takes precedence over the mapping
+    config.escaped_chars_mut().push(('\u{00A0}', '\u{00A0}'));
+    assert_eq!(escape_tex("a\u{00A0}b\u{2009}c", &config), "a{\\char\"00A0}b\\,c");
+}
+
+#[test]
+fn lenient_mode_skips_unsupported() {
+    let mut parser = pulldown_cmark::Parser::new("before\\\nafter\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    assert!(matches!(frag_to_tex(&frag, &config), Err(RenderError::UnsupportedElement(_, _))));
+
+    *config.lenient_mut() = true;
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert_eq!(tex, "before% skipped unsupported Markdown construct HardBreak\nafter\n\n");
+    assert_eq!(config.take_warnings(), vec!["skipped unsupported Markdown construct HardBreak".to_owned()]);
+    assert!(config.take_warnings().is_empty());
+}
+
+#[test]
+fn unsupported_constructs_are_positioned() {
+    let md = "---\ntitle: x\n---\nbefore  \nafter\n";
+    let (frag, _front_matter) = md_ast::parse_str(md, Path::new("."), InputEncoding::Utf8).unwrap();
+    let break_offset = md.find("  \n").unwrap();
+
+    let mut config = TexConfig::new();
+    let err = frag_to_tex(&frag, &config).unwrap_err();
+    assert_eq!(err.offset(), Some(break_offset));
+
+    *config.lenient_mut() = true;
+    frag_to_tex(&frag, &config).unwrap();
+    assert_eq!(
+        config.take_positioned_warnings(),
+        vec![("skipped unsupported Markdown construct HardBreak".to_owned(), Some(break_offset))],
+    );
+}
+
+#[test]
+fn render_error_kinds() {
+    let mut parser = pulldown_cmark::Parser::new("# One\n\n### Three\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    config.heading_commands_mut().push("chapter".to_owned());
+    let err = frag_to_tex(&frag, &config).unwrap_err();
+    assert!(matches!(err, RenderError::MissingHeadingCommand(3)));
+    assert_eq!(err.to_string(), "no command configured for heading level 3");
+}
+
+#[test]
+fn code_span_boundaries() {
+    assert_eq!(to_typing("`", "type"), "\\type{`}");
+    assert_eq!(to_typing("}", "type"), "\\type+}+");
+    assert_eq!(to_typing("{", "type"), "\\type+{+");
+    assert_eq!(to_typing(" a ", "type"), "\\type{ a }");
+    assert_eq!(to_typing(" ", "type"), "\\type{ }");
+    assert_eq!(to_typing(" } ", "type"), "\\type{ }\\type+}+\\type{ }");
+    assert_eq!(to_typing("", "type"), "");
+
+    // pulldown_cmark strips one space from either side; the rest must survive
+    let mut parser = pulldown_cmark::Parser::new("`` ` `` and `  a  ` and ` } `\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+    let tex = frag_to_tex(&frag, &TexConfig::new()).unwrap();
+    assert_eq!(tex, "\\type{`} and \\type{ a } and \\type+}+\n\n");
+}
+
+#[test]
+fn smart_quotes_can_be_disabled() {
+    let mut parser = pulldown_cmark::Parser::new("Run \"make all\" now.\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    assert_eq!(frag_to_tex(&frag, &config).unwrap(), "Run \u{201C}make all\u{201D} now.\n\n");
+
+    *config.smart_quotes_mut() = false;
+    assert_eq!(frag_to_tex(&frag, &config).unwrap(), "Run \"make all\" now.\n\n");
+}
+
+#[test]
+fn numbered_floats() {
+    let md = "![System overview](overview.png)\n\n| a |\n|---|\n| 1 |\n\nTable: Measurements\n\nSee [Figure](#fig:system-overview) and [Table](#tab:measurements).\n";
+    let mut parser = pulldown_cmark::Parser::new_ext(md, pulldown_cmark::Options::ENABLE_TABLES);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    *config.table_captions_mut() = true;
+    *config.number_floats_mut() = true;
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert!(tex.starts_with("\\placefigure[here][fig:system-overview]{System overview}{\\externalfigure[overview.png]}\n\n"));
+    assert!(tex.contains("\\startplacetable[reference=tab:measurements,title={Measurements}]\n"));
+    assert!(tex.ends_with("See \\in{Figure}[fig:system-overview] and \\in{Table}[tab:measurements].\n\n"));
+}
+
+#[test]
+fn numbered_code_lines() {
+    let md = "```python {.line-numbers}\nprint(1)\n```\n\n```rust\nfn main() {}\n```\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    assert_eq!(
+        frag_to_tex(&frag, &config).unwrap(),
+        "\\starttyping[numbering=line]\nprint(1)\n\\stoptyping\n\n\\starttyping\nfn main() {}\n\\stoptyping\n\n",
+    );
+
+    *config.number_code_lines_mut() = true;
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert_eq!(tex.matches("\\starttyping[numbering=line]\n").count(), 2);
+}
+
+#[test]
+fn listing_names() {
+    assert_eq!(listing_path(1, Some("rust")), "listings/001.rs");
+    assert_eq!(listing_path(12, Some("Python")), "listings/012.py");
+    assert_eq!(listing_path(1000, None), "listings/1000.txt");
+    assert_eq!(listing_path(3, Some("../../etc/passwd")), "listings/003.etcpassw");
+    assert_eq!(listing_path(4, Some("$}{#")), "listings/004.txt");
+    assert_eq!(listing_path(5, Some("supercalifragilistic")), "listings/005.supercal");
+}
+
+#[test]
+fn external_listings_are_numbered_across_fragments() {
+    let mut out_dir = std::env::temp_dir();
+    out_dir.push(format!("md-context-listings-{}", std::process::id()));
+    let mut config = TexConfig::new();
+    *config.output_dir_mut() = out_dir.clone();
+    *config.external_listing_min_lines_mut() = Some(1);
+
+    // two chapters, each with a Rust listing
+    let mut tex = String::new();
+    for code in &["fn one() {}", "fn two() {}"] {
+        let md = format!("```rust\n{}\n```\n", code);
+        let mut parser = pulldown_cmark::Parser::new(&md);
+        let frag = md_ast::parse(&mut parser).unwrap();
+        tex.push_str(&frag_to_tex(&frag, &config).unwrap());
+    }
+    let first = std::fs::read_to_string(out_dir.join("listings").join("001.rs"));
+    let second = std::fs::read_to_string(out_dir.join("listings").join("002.rs"));
+    std::fs::remove_dir_all(&out_dir).ok();
+
+    assert_eq!(tex, "\\typefile{listings/001.rs}\n\n\\typefile{listings/002.rs}\n\n");
+    assert_eq!(first.unwrap(), "fn one() {}\n");
+    assert_eq!(second.unwrap(), "fn two() {}\n");
+}
+
+#[test]
+fn entity_decoding() {
+    assert_eq!(decode_entities("Smith &amp; Wesson&trade;"), "Smith & Wesson™");
+    assert_eq!(decode_entities("&#8212;&#x2014;"), "——");
+    assert_eq!(decode_entities("&bogus; & &#xFFFFFF;"), "&bogus; & &#xFFFFFF;");
+}
+
+#[test]
+fn environment_files_precede_starttext() {
+    let mut config = TexConfig::new();
+    config.environment_files_mut().push("env_book".to_owned());
+    config.environment_files_mut().push("env_fonts".to_owned());
+    let preamble = ContextRenderer::new(&config)
+        .begin_document(&TableOfContents::new("Book"), OutputEncoding::Utf8);
+
+    let book = preamble.find("\\environment env_book\n").expect("first environment missing");
+    let fonts = preamble.find("\\environment env_fonts\n").expect("second environment missing");
+    let start = preamble.find("\\starttext").unwrap();
+    assert!(book < fonts && fonts < start, "unexpected preamble: {}", preamble);
+}
+
+#[test]
+fn image_dimensions() {
+    assert_eq!(image_dimension("3cm", "\\textwidth").as_deref(), Some("3cm"));
+    assert_eq!(image_dimension("50%", "\\textwidth").as_deref(), Some("0.5\\textwidth"));
+    assert_eq!(image_dimension("100%", "\\textheight").as_deref(), Some("1\\textheight"));
+    assert_eq!(image_dimension("12.5%", "\\textwidth").as_deref(), Some("0.125\\textwidth"));
+    assert_eq!(image_dimension("96px", "\\textwidth").as_deref(), Some("72bp"));
+    assert_eq!(image_dimension("200", "\\textwidth").as_deref(), Some("150bp"));
+    assert_eq!(image_dimension("wide", "\\textwidth"), None);
+    assert_eq!(image_dimension("3 furlongs", "\\textwidth"), None);
+}
+
+#[test]
+fn post_processors_run_in_order() {
+    let mut config = TexConfig::new();
+    assert_eq!(post_process("\\starttext\n".to_owned(), &config), "\\starttext\n");
+
+    config.post_processors_mut().push(Box::new(|tex| tex.replace("text", "TEXT")));
+    config.post_processors_mut().push(Box::new(|tex| format!("\\usemodule[tikz]\n{}", tex)));
+    assert_eq!(post_process("\\starttext\n".to_owned(), &config), "\\usemodule[tikz]\n\\startTEXT\n");
+}
+
+#[test]
+fn explicit_paragraphs() {
+    let md = "First paragraph.\n\n> Quoted paragraph.\n>\n> Another one.\n\n- tight item\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    assert!(!frag_to_tex(&frag, &config).unwrap().contains("\\startparagraph"));
+
+    *config.explicit_paragraphs_mut() = true;
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert!(tex.starts_with("\\startparagraph\nFirst paragraph.\n\\stopparagraph\n\n"), "{}", tex);
+    assert!(tex.contains("\\startparagraph\nQuoted paragraph.\n\\stopparagraph\n\n\\startparagraph\nAnother one.\n\\stopparagraph"), "{}", tex);
+    assert_eq!(tex.matches("\\startparagraph").count(), 3);
+}
+
+#[test]
+fn narrower_quotes() {
+    let md = "> Outer.\n>\n> > Inner.\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    assert!(frag_to_tex(&frag, &config).unwrap().starts_with("\\startblockquote\n"));
+
+    *config.narrower_quotes_mut() = true;
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert_eq!(
+        tex,
+        "\\startnarrower\nOuter.\n\n\\startnarrower\nInner.\n\n\\stopnarrower\n\n\\stopnarrower\n\n",
+    );
+}
+
+#[test]
+fn generated_back_matter() {
+    let md = "![A figure](figure.png)\n\nA term<!-- index: term --> in the index.\n";
+    let mut parser = pulldown_cmark::Parser::new(md);
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    let mut config = TexConfig::new();
+    *config.float_figures_mut() = true;
+    {
+        let renderer = ContextRenderer::new(&config);
+        assert_eq!(renderer.generated_back_matter(), "");
+        renderer.render(&frag).unwrap();
+        assert_eq!(renderer.generated_back_matter(), "\n\\completeindex\n");
+    }
+
+    *config.list_of_figures_mut() = true;
+    let renderer = ContextRenderer::new(&config);
+    renderer.render(&frag).unwrap();
+    assert_eq!(renderer.generated_back_matter(), "\n\\completelistoffigures\n\n\\completeindex\n");
+}
+
+#[test]
+fn headings_deeper_than_section_commands() {
+    assert_eq!(section_command(4), "subsubsubsubsection");
+    assert_eq!(section_command(9), "subsubsubsubsection");
+    assert_eq!(subject_command(5), "subsubsubsubsubject");
+
+    let mut parser = pulldown_cmark::Parser::new("## Two\n\n###### Six\n");
+    let frag = md_ast::parse(&mut parser).unwrap();
+
+    // as if the file were nested deeply in the ToC, making the level-6 heading a level-9 one
+    let config = TexConfig::new();
+    config.set_heading_depth(3);
+    let tex = frag_to_tex(&frag, &config).unwrap();
+    assert!(tex.contains("\\subsubsubsection{Two}"), "{}", tex);
+    assert!(tex.contains("\\subsubsubsubsection{Six}"), "{}", tex);
+    assert!(!tex.contains("subsubsubsubsubsection"));
+    assert_eq!(config.take_warnings().len(), 1);
+}
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use md_context::texutil::TexConfig;
+use md_context::toc::{load_toc, remove_tagged, retain_subtree, retain_tagged, TableOfContents, TOCEntry, TOCLevel};
+
+
+fn book_dir(name: &str) -> PathBuf {
+    let mut book_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    book_dir.push("tests");
+    book_dir.push("books");
+    book_dir.push(name);
+    book_dir
+}
+
+fn load_book(name: &str) -> TableOfContents {
+    load_toc(book_dir(name).to_str().unwrap(), "SUMMARY.md", &TexConfig::new())
+        .expect("failed to load ToC")
+}
+
+fn assert_entry(entry: &TOCEntry, level: TOCLevel, title: &str, child_count: usize) {
+    assert!(*entry.level() == level, "unexpected level for {:?}", entry.title());
+    assert_eq!(entry.title(), title);
+    assert_eq!(entry.child_entries().len(), child_count, "unexpected child count for {:?}", title);
+}
+
+#[test]
+fn three_level_nesting() {
+    let toc = load_book("nested");
+    assert_eq!(toc.title(), "Reference Manual");
+
+    let chapters = toc.body_matter_sections();
+    assert_eq!(chapters.len(), 3);
+    assert_entry(&chapters[0], TOCLevel::Chapter, "Chapter One", 2);
+    assert_entry(&chapters[1], TOCLevel::Chapter, "Chapter Two", 0);
+
+    let sections = chapters[0].child_entries();
+    assert_entry(&sections[0], TOCLevel::Section(0), "Section One A", 2);
+    assert_entry(&sections[1], TOCLevel::Section(0), "Section One B", 0);
+
+    let subsections = sections[0].child_entries();
+    assert_entry(&subsections[0], TOCLevel::Section(1), "Subsection One A i", 0);
+    assert_entry(&subsections[1], TOCLevel::Section(1), "Subsection One A ii", 0);
+}
+
+#[test]
+fn level_depths() {
+    let levels = [TOCLevel::Part, TOCLevel::Chapter, TOCLevel::Section(0), TOCLevel::Section(2)];
+    let depths: Vec<u32> = levels.iter().map(|l| l.depth()).collect();
+    assert_eq!(depths, vec![0, 1, 2, 4]);
+
+    assert!(levels[0].is_part() && !levels[0].is_chapter() && !levels[0].is_section());
+    assert!(levels[1].is_chapter() && !levels[1].is_section());
+    assert!(levels[3].is_section() && !levels[3].is_part());
+
+    let toc = load_book("nested");
+    let subsection = &toc.body_matter_sections()[0].child_entries()[0].child_entries()[0];
+    assert_eq!(subsection.level().depth(), 3);
+}
+
+#[test]
+fn nesting_validation() {
+    let err = match load_toc(book_dir("skipped_level").to_str().unwrap(), "SUMMARY.md", &TexConfig::new()) {
+        Ok(_) => panic!("skipped nesting level not detected"),
+        Err(err) => err,
+    };
+    assert!(err.message().contains("\"Too Deep\" is nested more than one level deeper"), "{}", err.message());
+}
+
+#[test]
+fn tagged_entries() {
+    let mut toc = load_book("nested");
+    let chapters = toc.body_matter_sections();
+    assert!(chapters[2].has_tag("sample"));
+    assert!(chapters[2].child_entries()[0].has_tag("draft"));
+    assert!(!chapters[0].has_tag("sample"));
+
+    let mut only_sample = toc.body_matter_sections_mut().drain(..).collect();
+    retain_tagged(&mut only_sample, "sample");
+    assert_eq!(only_sample.len(), 1);
+    assert_entry(&only_sample[0], TOCLevel::Chapter, "Chapter Three", 1);
+
+    remove_tagged(&mut only_sample, "draft");
+    assert_entry(&only_sample[0], TOCLevel::Chapter, "Chapter Three", 0);
+}
+
+#[test]
+fn subtree() {
+    let mut toc = load_book("nested");
+    let mut section = toc.body_matter_sections_mut().drain(..).collect();
+    assert!(retain_subtree(&mut section, Path::new("one-a.md")));
+    assert_eq!(section.len(), 1);
+    assert_entry(&section[0], TOCLevel::Section(0), "Section One A", 2);
+
+    let mut toc = load_book("nested");
+    let mut missing = toc.body_matter_sections_mut().drain(..).collect();
+    assert!(!retain_subtree(&mut missing, Path::new("four.md")));
+    assert!(missing.is_empty());
+}
+
+#[test]
+fn metadata_from_front_matter() {
+    let toc = load_book("metadata");
+    assert_eq!(toc.title(), "Ein Buch");
+    assert_eq!(toc.metadata().author(), Some("Erika Mustermann"));
+    assert_eq!(toc.metadata().language(), Some("de"));
+
+    let chapter = &toc.body_matter_sections()[0];
+    assert_eq!(chapter.title(), "Ein Kapitel mit einem sehr langen Titel");
+    assert_eq!(chapter.short_title(), Some("Kapitel"));
+
+    let mut config = TexConfig::new();
+    *config.language_mut() = Some("de-AT".to_owned());
+    let toc = load_toc(book_dir("metadata").to_str().unwrap(), "SUMMARY.md", &config)
+        .expect("failed to load ToC");
+    assert_eq!(toc.metadata().language(), Some("de-AT"));
+}
+
+#[test]
+fn empty_summary() {
+    let book_dir = book_dir("empty");
+    let err = load_toc(book_dir.to_str().unwrap(), "SUMMARY.md", &TexConfig::new())
+        .err().expect("empty ToC loaded successfully");
+    assert!(err.to_string().contains("does not reference any sections"), "unexpected error: {}", err);
+}
+
+#[test]
+fn percent_encoded_paths() {
+    let toc = load_book("spaces");
+    let chapters = toc.body_matter_sections();
+    assert_eq!(chapters[0].path(), Some(Path::new("My Chapter.md")));
+    assert_eq!(chapters[1].path(), Some(Path::new("Über alles.md")));
+
+    for chapter in chapters {
+        let mut chapter_path = book_dir("spaces");
+        chapter_path.push(chapter.path().unwrap());
+        assert!(chapter_path.is_file(), "{:?} does not exist", chapter_path);
+    }
+}
@@ -0,0 +1,88 @@
+use md_context::texutil::to_typing;
+
+
+/// Splits the output of `to_typing` back into its verbatim pieces, failing if a piece is not
+/// delimited properly or contains its own closing delimiter.
+fn parse_typing(tex: &str) -> Result<Vec<String>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = tex;
+    while !rest.is_empty() {
+        if !rest.starts_with("\\type") {
+            return Err(format!("expected \\type at {:?}", rest));
+        }
+        rest = &rest["\\type".len()..];
+
+        let (close, forbidden): (char, &[char]) = match rest.chars().next() {
+            Some('{') => ('}', &['{', '}']),
+            Some('+') => ('+', &['+']),
+            other => return Err(format!("unexpected delimiter {:?}", other)),
+        };
+        rest = &rest[1..];
+
+        let end = match rest.find(close) {
+            Some(e) => e,
+            None => return Err(format!("unterminated \\type in {:?}", tex)),
+        };
+        let piece = &rest[..end];
+        if piece.is_empty() {
+            return Err(format!("empty \\type in {:?}", tex));
+        }
+        if piece.contains(forbidden) {
+            return Err(format!("delimiter inside \\type content {:?}", piece));
+        }
+        pieces.push(piece.to_owned());
+        rest = &rest[end+1..];
+    }
+    Ok(pieces)
+}
+
+fn check(s: &str) {
+    let tex = to_typing(s, "type");
+    let pieces = parse_typing(&tex)
+        .unwrap_or_else(|err| panic!("{:?} -> {:?}: {}", s, tex, err));
+    assert_eq!(pieces.concat(), s, "{:?} -> {:?}", s, tex);
+}
+
+
+#[test]
+fn delimiters_never_clash_exhaustive() {
+    // every string of up to six characters from an alphabet heavy on delimiters
+    let alphabet = ['{', '}', '+', 'a', ' ', '\\'];
+    let mut current: Vec<String> = vec![String::new()];
+    for _ in 0..6 {
+        let mut next = Vec::with_capacity(current.len() * alphabet.len());
+        for prefix in &current {
+            for c in &alphabet {
+                let mut s = prefix.clone();
+                s.push(*c);
+                check(&s);
+                next.push(s);
+            }
+        }
+        current = next;
+    }
+}
+
+#[test]
+fn delimiters_never_clash_random() {
+    // longer strings from a fixed-seed xorshift generator, so failures are reproducible; this
+    // keeps the crate free of dev-dependencies, and as `check` reports the failing input and the
+    // exhaustive test covers the short strings that shrinking would lead to, little is lost
+    // compared to a property testing crate
+    let alphabet: Vec<char> = "{}+ab \\%#~^_$&\"`ü€".chars().collect();
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut next_random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..2000 {
+        let length = (next_random() % 64) as usize;
+        let s: String = (0..length)
+            .map(|_| alphabet[(next_random() % alphabet.len() as u64) as usize])
+            .collect();
+        check(&s);
+    }
+}